@@ -1,26 +1,37 @@
 //!
+#![cfg_attr(not(feature = "std"), no_std)]
 
-use crate::qcow::Qcow3;
+extern crate alloc;
+
+use aes_gcm::Aes256Gcm;
 use aes_gcm::KeyInit;
-use aes_gcm::{aead::Aead, Aes256Gcm, Key, Nonce};
-use anyhow::bail;
-use anyhow::Result;
-use binrw::{BinRead, BinReaderExt, BinWrite};
-use rand::Rng;
-use regex::Regex;
+use alloc::{string::String, vec::Vec};
+use binrw::{BinRead, BinWrite};
+use core::ffi::CStr;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::ffi::CStr;
-use std::{
-    fs::File,
-    io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
-    path::Path,
-    time::{SystemTime, UNIX_EPOCH},
-};
 use strum::{Display, EnumIter};
-use tracing::{debug, info, trace};
 
+#[cfg(feature = "std")]
+use {
+    crate::qcow::Qcow3,
+    aes_gcm::{aead::Aead, Key, Nonce},
+    anyhow::{bail, Result},
+    binrw::BinReaderExt,
+    rand::Rng,
+    regex::Regex,
+    std::{
+        fs::File,
+        io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
+        path::Path,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tracing::{debug, info, trace},
+};
+
+#[cfg(feature = "std")]
 pub mod qcow;
+pub mod reader;
 
 /// Supported system architectures for goldboot images.
 #[derive(
@@ -37,6 +48,7 @@ pub enum ImageArch {
     S390x,
 }
 
+#[cfg(feature = "std")]
 impl Default for ImageArch {
     fn default() -> Self {
         match std::env::consts::ARCH {
@@ -51,6 +63,7 @@ impl Default for ImageArch {
     }
 }
 
+#[cfg(feature = "std")]
 impl TryFrom<String> for ImageArch {
     type Error = anyhow::Error;
     fn try_from(s: String) -> Result<Self> {
@@ -83,6 +96,7 @@ impl TryFrom<String> for ImageArch {
 /// file. Clusters are variable in size and ideally smaller than their
 /// associated blocks (due to compression). If a block does not have an
 /// associated cluster, that block is zero.
+#[cfg(feature = "std")]
 pub struct ImageHandle {
     /// The primary file header
     pub primary_header: PrimaryHeader,
@@ -99,7 +113,7 @@ pub struct ImageHandle {
     /// The section directory
     pub directory: Option<Directory>,
 
-    /// The filesystem path to the image file
+    /// The filesystem path to the image file (or its URL if the image is remote)
     pub path: std::path::PathBuf,
 
     /// The size in bytes of the image file on disk
@@ -185,7 +199,7 @@ pub struct PrimaryHeader {
 
 impl PrimaryHeader {
     pub fn name(&self) -> String {
-        unsafe { CStr::from_ptr(self.name.as_ptr() as *const std::ffi::c_char) }
+        unsafe { CStr::from_ptr(self.name.as_ptr() as *const core::ffi::c_char) }
             .to_string_lossy()
             .into_owned()
     }
@@ -289,12 +303,13 @@ pub struct Cluster {
 }
 
 /// Build an encryption key from the given password.
-fn new_key(password: String) -> Aes256Gcm {
+fn new_key(password: &str) -> Aes256Gcm {
     // Hash so it's the correct length
     Aes256Gcm::new(&Sha256::new().chain_update(password.as_bytes()).finalize())
 }
 
 /// Hash the entire image file to produce the image ID.
+#[cfg(feature = "std")]
 pub fn compute_id(path: impl AsRef<Path>) -> Result<String> {
     let mut file = File::open(&path)?;
     let mut hasher = Sha256::new();
@@ -303,13 +318,22 @@ pub fn compute_id(path: impl AsRef<Path>) -> Result<String> {
     Ok(hex::encode(hasher.finalize()))
 }
 
+#[cfg(feature = "std")]
 impl ImageHandle {
     /// Load all sections into memory except the cluster table. If the image is
     /// encrypted, the sections will be decrypted.
     pub fn load(&mut self, password: Option<String>) -> Result<()> {
-        let mut file = File::open(&self.path)?;
+        self.load_from(&mut File::open(&self.path)?, password)
+    }
 
-        let cipher = new_key(password.unwrap_or("".to_string()));
+    /// Load all sections except the cluster table from the given reader which
+    /// may be something other than a local file (a remote image for example).
+    pub fn load_from(
+        &mut self,
+        file: &mut (impl Read + Seek),
+        password: Option<String>,
+    ) -> Result<()> {
+        let cipher = new_key(&password.unwrap_or_default());
 
         // Load the directory first because other sections rely on it
         file.seek(SeekFrom::Start(self.primary_header.directory_offset))?;
@@ -385,14 +409,9 @@ impl ImageHandle {
     /// Open a new handle on the given file.
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref();
-        let mut file = File::open(path)?;
 
         debug!("Opening image from: {}", path.display());
 
-        // Read primary header (always plaintext)
-        let primary_header: PrimaryHeader = file.read_be()?;
-        trace!("Read: {:?}", &primary_header);
-
         // Get image ID
         let id = if let Some(stem) = path.file_stem() {
             if Regex::new("[A-Fa-f0-9]{64}")?.is_match(stem.to_str().unwrap()) {
@@ -404,6 +423,24 @@ impl ImageHandle {
             compute_id(&path).unwrap()
         };
 
+        Self::open_from(File::open(path)?, path, id, std::fs::metadata(path)?.len())
+    }
+
+    /// Open a new handle on an image that can be read from the given reader.
+    /// Only the headers are read, so this is suitable for remote images where
+    /// the cluster table is fetched on demand.
+    pub fn open_from(
+        mut file: impl Read + Seek,
+        path: impl AsRef<Path>,
+        id: String,
+        file_size: u64,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+
+        // Read primary header (always plaintext)
+        let primary_header: PrimaryHeader = file.read_be()?;
+        trace!("Read: {:?}", &primary_header);
+
         if primary_header.encryption_type == HeaderEncryptionType::None {
             // Read protected header
             let protected_header: ProtectedHeader = file.read_be()?;
@@ -425,7 +462,7 @@ impl ImageHandle {
                 digest_table: None,
                 directory: Some(directory),
                 path: path.to_path_buf(),
-                file_size: std::fs::metadata(&path)?.len(),
+                file_size,
             })
         } else {
             Ok(Self {
@@ -436,7 +473,7 @@ impl ImageHandle {
                 digest_table: None,
                 directory: None,
                 path: path.to_path_buf(),
-                file_size: std::fs::metadata(&path)?.len(),
+                file_size,
             })
         }
     }
@@ -445,7 +482,7 @@ impl ImageHandle {
     /// re-encrypt the clusters because they are encrypted with the cluster key.
     pub fn change_password(&self, _old_password: String, new_password: String) -> Result<()> {
         // Create the cipher and a RNG for the nonces
-        let _cipher = new_key(new_password);
+        let _cipher = new_key(&new_password);
         let _rng = rand::thread_rng();
 
         todo!()
@@ -467,7 +504,7 @@ impl ImageHandle {
         let mut source_file = File::open(&source.path)?;
 
         // Prepare cipher and RNG if the image header should be encrypted
-        let header_cipher = new_key(password.clone().unwrap_or("".to_string()));
+        let header_cipher = new_key(&password.clone().unwrap_or_default());
         let mut rng = rand::thread_rng();
 
         // Prepare directory
@@ -707,6 +744,18 @@ impl ImageHandle {
 
     /// Write the image contents out to disk.
     pub fn write<F: Fn(u64, u64) -> ()>(&self, dest: impl AsRef<Path>, progress: F) -> Result<()> {
+        self.write_from(BufReader::new(File::open(&self.path)?), dest, progress)
+    }
+
+    /// Write the image contents out to disk, reading clusters from the given
+    /// reader. Clusters are read in order, so the reader only needs to support
+    /// efficient forward seeks.
+    pub fn write_from<F: Fn(u64, u64)>(
+        &self,
+        mut cluster_table: impl Read + Seek,
+        dest: impl AsRef<Path>,
+        progress: F,
+    ) -> Result<()> {
         if self.protected_header.is_none() || self.digest_table.is_none() {
             bail!("Image not loaded");
         }
@@ -725,9 +774,6 @@ impl ImageHandle {
             .read(true)
             .open(dest)?;
 
-        // Open the cluster table for reading
-        let mut cluster_table = BufReader::new(File::open(&self.path)?);
-
         // Extend the file if necessary
         // TODO stream_len?
         if dest.metadata()?.len() < self.primary_header.size {
@@ -0,0 +1,314 @@
+//! A minimal image reader that only requires `core` and `alloc`, so images can
+//! be written from environments without an operating system (like UEFI).
+
+use crate::{
+    new_key, Cluster, ClusterCompressionType, ClusterEncryptionType, DigestTable, DigestTableEntry,
+    Directory, HeaderEncryptionType, PrimaryHeader, ProtectedHeader,
+};
+use aes_gcm::{aead::Aead, Aes256Gcm, Key, KeyInit, Nonce};
+use alloc::{vec, vec::Vec};
+use binrw::{
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    BinReaderExt,
+};
+use core::fmt::Display;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub enum ReadError {
+    /// The image could not be read or parsed
+    Format(binrw::Error),
+
+    /// The password is wrong or missing
+    Decrypt,
+
+    /// A cluster could not be decompressed
+    Decompress,
+
+    /// A decoded cluster doesn't match its digest
+    Digest { block_offset: u64 },
+
+    /// The image hasn't been loaded yet
+    NotLoaded,
+}
+
+impl From<binrw::Error> for ReadError {
+    fn from(error: binrw::Error) -> Self {
+        ReadError::Format(error)
+    }
+}
+
+impl From<binrw::io::Error> for ReadError {
+    fn from(error: binrw::io::Error) -> Self {
+        ReadError::Format(binrw::Error::Io(error))
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReadError::Format(error) => write!(f, "Invalid image: {error}"),
+            ReadError::Decrypt => write!(f, "Failed to decrypt image"),
+            ReadError::Decompress => write!(f, "Failed to decompress cluster"),
+            ReadError::Digest { block_offset } => {
+                write!(f, "Digest mismatch for block at offset {block_offset}")
+            }
+            ReadError::NotLoaded => write!(f, "Image not loaded"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ReadError {}
+
+fn decrypt(cipher: &Aes256Gcm, nonce: &[u8; 12], data: &[u8]) -> Result<Vec<u8>, ReadError> {
+    cipher
+        .decrypt(Nonce::from_slice(nonce), data)
+        .map_err(|_| ReadError::Decrypt)
+}
+
+/// Reads a goldboot image from any seekable source.
+pub struct ImageReader<R> {
+    reader: R,
+
+    /// The primary file header
+    pub primary_header: PrimaryHeader,
+
+    /// The secondary header (available after loading)
+    pub protected_header: Option<ProtectedHeader>,
+
+    /// The digest table (available after loading)
+    pub digest_table: Option<DigestTable>,
+}
+
+impl<R: Read + Seek> ImageReader<R> {
+    /// Read the primary header from the given source.
+    pub fn open(mut reader: R) -> Result<Self, ReadError> {
+        reader.seek(SeekFrom::Start(0))?;
+        let primary_header: PrimaryHeader = reader.read_be()?;
+
+        Ok(Self {
+            reader,
+            primary_header,
+            protected_header: None,
+            digest_table: None,
+        })
+    }
+
+    /// Whether a password is required to load the image.
+    pub fn is_encrypted(&self) -> bool {
+        self.primary_header.encryption_type != HeaderEncryptionType::None
+    }
+
+    /// Read a section which may be encrypted with the header cipher.
+    fn read_section(
+        &mut self,
+        cipher: &Aes256Gcm,
+        offset: u64,
+        size: u32,
+        nonce: &[u8; 12],
+    ) -> Result<Vec<u8>, ReadError> {
+        let mut bytes = vec![0u8; size as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut bytes)?;
+
+        match self.primary_header.encryption_type {
+            HeaderEncryptionType::None => Ok(bytes),
+            HeaderEncryptionType::Aes256 => decrypt(cipher, nonce, &bytes),
+        }
+    }
+
+    /// Load the protected header and digest table. The config is skipped since
+    /// it's not needed to write the image.
+    pub fn load(&mut self, password: Option<&str>) -> Result<(), ReadError> {
+        let cipher = new_key(password.unwrap_or_default());
+
+        let directory: Directory = Cursor::new(self.read_section(
+            &cipher,
+            self.primary_header.directory_offset,
+            self.primary_header.directory_size,
+            &self.primary_header.directory_nonce.clone(),
+        )?)
+        .read_be()?;
+
+        // The protected header immediately follows the primary header
+        self.reader.seek(SeekFrom::Start(0))?;
+        let _primary: PrimaryHeader = self.reader.read_be()?;
+        let protected_offset = self.reader.stream_position()?;
+
+        let protected_header: ProtectedHeader = Cursor::new(self.read_section(
+            &cipher,
+            protected_offset,
+            directory.protected_size,
+            &directory.protected_nonce,
+        )?)
+        .read_be()?;
+
+        let digest_table: DigestTable = Cursor::new(self.read_section(
+            &cipher,
+            directory.digest_table_offset,
+            directory.digest_table_size,
+            &directory.digest_table_nonce,
+        )?)
+        .read_be()?;
+
+        self.protected_header = Some(protected_header);
+        self.digest_table = Some(digest_table);
+        Ok(())
+    }
+
+    /// The number of clusters in the image.
+    pub fn cluster_count(&self) -> usize {
+        self.digest_table
+            .as_ref()
+            .map_or(0, |table| table.digest_table.len())
+    }
+
+    /// Get the digest table entry for the given cluster.
+    pub fn entry(&self, index: usize) -> Result<&DigestTableEntry, ReadError> {
+        self.digest_table
+            .as_ref()
+            .and_then(|table| table.digest_table.get(index))
+            .ok_or(ReadError::NotLoaded)
+    }
+
+    /// Read the given cluster and decode it back into its original block. The
+    /// block is verified against the digest table.
+    pub fn decode_cluster(&mut self, index: usize) -> Result<Vec<u8>, ReadError> {
+        let entry = self.entry(index)?.clone();
+
+        self.reader.seek(SeekFrom::Start(entry.cluster_offset))?;
+        let cluster: Cluster = self.reader.read_be()?;
+
+        let block = decode_cluster(
+            self.protected_header.as_ref().ok_or(ReadError::NotLoaded)?,
+            index,
+            cluster.data,
+        )?;
+
+        let digest: [u8; 32] = Sha256::new().chain_update(&block).finalize().into();
+        if digest != entry.digest {
+            return Err(ReadError::Digest {
+                block_offset: entry.block_offset,
+            });
+        }
+
+        Ok(block)
+    }
+
+    /// Write the image contents to the given destination. Blocks that already
+    /// match their digest are skipped.
+    pub fn write<W: Read + Write + Seek>(
+        &mut self,
+        dest: &mut W,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<(), ReadError> {
+        let block_size = self
+            .protected_header
+            .as_ref()
+            .ok_or(ReadError::NotLoaded)?
+            .block_size as u64;
+        let total = self.cluster_count() as u64 * block_size;
+
+        let mut block = vec![0u8; block_size as usize];
+
+        for index in 0..self.cluster_count() {
+            let entry = self.entry(index)?.clone();
+
+            dest.seek(SeekFrom::Start(entry.block_offset))?;
+            dest.read_exact(&mut block)?;
+
+            let digest: [u8; 32] = Sha256::new().chain_update(&block).finalize().into();
+            if digest != entry.digest {
+                let block = self.decode_cluster(index)?;
+
+                dest.seek(SeekFrom::Start(entry.block_offset))?;
+                dest.write_all(&block)?;
+            }
+
+            progress(block_size, total);
+        }
+
+        dest.flush()?;
+        Ok(())
+    }
+}
+
+/// Reverse the encryption and compression of a cluster's data. The index is
+/// needed to find the cluster's nonce.
+pub fn decode_cluster(
+    protected_header: &ProtectedHeader,
+    index: usize,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, ReadError> {
+    let data = match protected_header.cluster_encryption {
+        ClusterEncryptionType::None => data,
+        ClusterEncryptionType::Aes256 => decrypt(
+            &Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&protected_header.cluster_key)),
+            protected_header
+                .nonce_table
+                .get(index)
+                .ok_or(ReadError::Decrypt)?,
+            &data,
+        )?,
+    };
+
+    match protected_header.cluster_compression {
+        ClusterCompressionType::None => Ok(data),
+        ClusterCompressionType::Zstd => decompress(&data, protected_header.block_size as usize),
+    }
+}
+
+#[cfg(not(any(feature = "std", feature = "no_std")))]
+compile_error!("Either the std or no_std feature is required");
+
+#[cfg(feature = "std")]
+fn decompress(data: &[u8], block_size: usize) -> Result<Vec<u8>, ReadError> {
+    zstd::bulk::decompress(data, block_size).map_err(|_| ReadError::Decompress)
+}
+
+#[cfg(all(feature = "no_std", not(feature = "std")))]
+fn decompress(data: &[u8], block_size: usize) -> Result<Vec<u8>, ReadError> {
+    let mut block = vec![0u8; block_size];
+    let size = ruzstd::FrameDecoder::new()
+        .decode_all(data, &mut block)
+        .map_err(|_| ReadError::Decompress)?;
+    block.truncate(size);
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{qcow::Qcow3, ImageHandle};
+    use sha1::Sha1;
+
+    #[test]
+    fn write_encrypted_image_with_reader() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+
+        ImageHandle::convert(
+            &Qcow3::open("test/small.qcow2")?,
+            String::from("Test"),
+            vec![],
+            Some(String::from("1234")),
+            true,
+            tmp.path().join("small.gb"),
+            |_, _| {},
+        )?;
+
+        let mut reader = ImageReader::open(std::fs::File::open(tmp.path().join("small.gb"))?)?;
+        assert!(reader.is_encrypted());
+        assert!(reader.load(Some("wrong")).is_err());
+        reader.load(Some("1234"))?;
+
+        let mut raw = Cursor::new(vec![0u8; reader.primary_header.size as usize]);
+        reader.write(&mut raw, |_, _| {})?;
+
+        assert_eq!(
+            hex::encode(Sha1::new().chain_update(raw.into_inner()).finalize()),
+            "34e1c79c80941e5519ec76433790191318a5c77b"
+        );
+        Ok(())
+    }
+}
@@ -1,10 +1,14 @@
 use crate::extract::ImageHandle;
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::Body,
+    extract::{Path, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
 use goldboot::registry::api::image::ImageInfoResponse;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
 
 /// Get image info
 pub async fn info(image: ImageHandle) -> Json<ImageInfoResponse> {
@@ -14,6 +18,27 @@ pub async fn info(image: ImageHandle) -> Json<ImageInfoResponse> {
 /// Get image list
 pub async fn list() {}
 
+/// Download the image file. Range requests are supported so clients can fetch
+/// clusters on demand instead of downloading the entire image.
+pub async fn download(headers: HeaderMap, image: ImageHandle) -> Response {
+    let mut request = Request::new(Body::empty());
+    *request.headers_mut() = headers;
+
+    // Stream the file rather than loading it into memory
+    match ServeFile::new(&image.0.path).oneshot(request).await {
+        Ok(response) => {
+            let mut response = response.map(Body::new);
+
+            // The image ID is a hash of the file, so clients can rely on it
+            if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", image.0.id)) {
+                response.headers_mut().insert(header::ETAG, etag);
+            }
+            response
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
 // Push an image
 /*
 pub async fn push(id: web::Path<String>, rq: actix_web::HttpRequest) -> Result<HttpResponse> {
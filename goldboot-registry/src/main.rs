@@ -29,6 +29,7 @@ async fn main() {
     let app = Router::new()
         .route("/image/list", get(api::image::list))
         .route("/image/info/:image_id", get(api::image::info))
+        .route("/image/download/:image_id", get(api::image::download))
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
@@ -0,0 +1,68 @@
+//! Simple interactive prompts on the UEFI console.
+
+use alloc::string::String;
+use uefi::{
+    prelude::*,
+    print, println,
+    proto::console::text::{Key, ScanCode},
+};
+
+/// Block until a key is pressed.
+pub fn read_key(system_table: &mut SystemTable<Boot>) -> Key {
+    loop {
+        if let Some(event) = system_table.stdin().wait_for_key_event() {
+            let _ = system_table
+                .boot_services()
+                .wait_for_event(&mut [unsafe { event.unsafe_clone() }]);
+        }
+
+        if let Ok(Some(key)) = system_table.stdin().read_key() {
+            return key;
+        }
+    }
+}
+
+/// Read a line of input. If `mask` is set, the input is not echoed.
+pub fn read_line(system_table: &mut SystemTable<Boot>, prompt: &str, mask: bool) -> String {
+    print!("{}: ", prompt);
+    let mut line = String::new();
+
+    loop {
+        match read_key(system_table) {
+            Key::Printable(c) if u16::from(c) == 0x0d => break,
+            Key::Printable(c) if u16::from(c) == 0x08 => {
+                if line.pop().is_some() {
+                    print!("\u{8} \u{8}");
+                }
+            }
+            Key::Printable(c) => {
+                let c = char::from(c);
+                line.push(c);
+                print!("{}", if mask { '*' } else { c });
+            }
+            Key::Special(ScanCode::ESCAPE) => {
+                line.clear();
+                break;
+            }
+            Key::Special(_) => {}
+        }
+    }
+
+    println!();
+    line
+}
+
+/// Prompt for a selection between 1 and `count`.
+pub fn select(system_table: &mut SystemTable<Boot>, prompt: &str, count: usize) -> Option<usize> {
+    loop {
+        let line = read_line(system_table, prompt, false);
+        if line.is_empty() {
+            return None;
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(number) if number >= 1 && number <= count => return Some(number - 1),
+            _ => println!("Enter a number between 1 and {}", count),
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Access to the target disks through the UEFI block I/O protocol.
+
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout},
+    vec::Vec,
+};
+use binrw::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use core::{
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+use uefi::{
+    prelude::*,
+    proto::{
+        device_path::{DevicePath, DeviceSubType, DeviceType},
+        loaded_image::LoadedImage,
+        media::block::BlockIO,
+    },
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol},
+};
+
+/// A disk that can be selected as the write target.
+pub struct Disk {
+    pub handle: Handle,
+
+    /// The size of the disk in bytes
+    pub size: u64,
+
+    pub removable: bool,
+}
+
+/// The nodes of a device path for comparison.
+type PathNodes = Vec<(DeviceType, DeviceSubType, Vec<u8>)>;
+
+fn device_path(
+    handle: Handle,
+    image_handle: Handle,
+    boot_services: &BootServices,
+) -> Option<PathNodes> {
+    let path = unsafe {
+        boot_services.open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()?;
+
+    Some(
+        path.node_iter()
+            .map(|node| {
+                let (device_type, sub_type) = node.full_type();
+                (device_type, sub_type, node.data().to_vec())
+            })
+            .collect(),
+    )
+}
+
+/// Get the device path of the partition that this application was loaded
+/// from.
+fn boot_device_path(image_handle: Handle, boot_services: &BootServices) -> Option<PathNodes> {
+    let loaded_image = unsafe {
+        boot_services.open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: image_handle,
+                agent: image_handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .ok()?;
+
+    device_path(loaded_image.device()?, image_handle, boot_services)
+}
+
+/// Find all writable whole disks (partitions are excluded). The disk that this
+/// application was loaded from holds the images, so it's excluded too.
+pub fn find(image_handle: Handle, boot_services: &BootServices) -> uefi::Result<Vec<Disk>> {
+    let mut disks = Vec::new();
+
+    let boot_device = boot_device_path(image_handle, boot_services);
+    if boot_device.is_none() {
+        log::warn!("Failed to find the boot disk");
+    }
+
+    for handle in boot_services.find_handles::<BlockIO>()? {
+        // Don't take over the device from its drivers just to look at it
+        let block_io = unsafe {
+            boot_services.open_protocol::<BlockIO>(
+                OpenProtocolParams {
+                    handle,
+                    agent: image_handle,
+                    controller: None,
+                },
+                OpenProtocolAttributes::GetProtocol,
+            )
+        }?;
+
+        let media = block_io.media();
+        if media.is_logical_partition() || !media.is_media_present() || media.is_read_only() {
+            continue;
+        }
+
+        // The boot partition's path starts with the path of its disk
+        if let (Some(boot_device), Some(disk)) = (
+            &boot_device,
+            device_path(handle, image_handle, boot_services),
+        ) {
+            if boot_device.starts_with(&disk) {
+                continue;
+            }
+        }
+
+        disks.push(Disk {
+            handle,
+            size: (media.last_block() + 1) * media.block_size() as u64,
+            removable: media.is_removable_media(),
+        });
+    }
+
+    Ok(disks)
+}
+
+/// A zeroed heap buffer with the alignment that a device requires for
+/// transfers.
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize, align: usize) -> binrw::io::Result<Self> {
+        // Devices without requirements report 0 or 1
+        let layout = Layout::from_size_align(size.max(1), align.max(1))
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid buffer alignment"))?;
+
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+        Ok(Self { ptr, layout })
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// Adapts a block device for the image reader. Reads and writes don't need to
+/// be aligned to the device's block size.
+pub struct DiskWriter<'a> {
+    block_io: ScopedProtocol<'a, BlockIO>,
+    media_id: u32,
+    block_size: u64,
+
+    /// The alignment that transfer buffers need
+    io_align: usize,
+    size: u64,
+    position: u64,
+}
+
+impl<'a> DiskWriter<'a> {
+    /// Open the disk exclusively so no other driver writes to it concurrently.
+    pub fn open(disk: &Disk, boot_services: &'a BootServices) -> uefi::Result<Self> {
+        let block_io = boot_services.open_protocol_exclusive::<BlockIO>(disk.handle)?;
+        let media = block_io.media();
+
+        Ok(Self {
+            media_id: media.media_id(),
+            block_size: media.block_size() as u64,
+            io_align: media.io_align() as usize,
+            size: disk.size,
+            block_io,
+            position: 0,
+        })
+    }
+
+    /// Read the device blocks covering the given range.
+    fn read_range(&mut self, length: usize) -> binrw::io::Result<(u64, AlignedBuffer)> {
+        let lba = self.position / self.block_size;
+        let end = (self.position + length as u64).div_ceil(self.block_size);
+        let mut buffer =
+            AlignedBuffer::new(((end - lba) * self.block_size) as usize, self.io_align)?;
+
+        self.block_io
+            .read_blocks(self.media_id, lba, &mut buffer)
+            .map_err(|_| Error::new(ErrorKind::Other, "Failed to read blocks"))?;
+        Ok((lba, buffer))
+    }
+}
+
+impl Read for DiskWriter<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> binrw::io::Result<usize> {
+        let length = core::cmp::min(buf.len() as u64, self.size.saturating_sub(self.position));
+        if length == 0 {
+            return Ok(0);
+        }
+
+        let (_, buffer) = self.read_range(length as usize)?;
+        let start = (self.position % self.block_size) as usize;
+        buf[..length as usize].copy_from_slice(&buffer[start..start + length as usize]);
+
+        self.position += length;
+        Ok(length as usize)
+    }
+}
+
+impl Write for DiskWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> binrw::io::Result<usize> {
+        if self.position + buf.len() as u64 > self.size {
+            return Err(Error::new(ErrorKind::WriteZero, "Write past end of disk"));
+        }
+
+        // Partial blocks at either end need to be merged with existing data
+        let start = (self.position % self.block_size) as usize;
+        let (lba, mut buffer) = if start == 0 && buf.len() as u64 % self.block_size == 0 {
+            let mut buffer = AlignedBuffer::new(buf.len(), self.io_align)?;
+            buffer.copy_from_slice(buf);
+            (self.position / self.block_size, buffer)
+        } else {
+            let (lba, mut buffer) = self.read_range(buf.len())?;
+            buffer[start..start + buf.len()].copy_from_slice(buf);
+            (lba, buffer)
+        };
+
+        self.block_io
+            .write_blocks(self.media_id, lba, &mut buffer)
+            .map_err(|_| Error::new(ErrorKind::Other, "Failed to write blocks"))?;
+
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> binrw::io::Result<()> {
+        self.block_io
+            .flush_blocks()
+            .map_err(|_| Error::new(ErrorKind::Other, "Failed to flush disk"))
+    }
+}
+
+impl Seek for DiskWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> binrw::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid seek"))?;
+
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,115 @@
+//! Find goldboot images on the filesystem the application was loaded from.
+
+use alloc::{string::String, vec::Vec};
+use goldboot_image::reader::ImageReader;
+use uefi::{
+    prelude::*,
+    proto::media::file::{Directory, File, FileAttribute, FileInfo, FileMode, RegularFile},
+    CString16,
+};
+
+/// Directories that are searched for images in addition to the root.
+const SEARCH_DIRS: [&str; 2] = ["\\", "\\goldboot"];
+
+/// Adapts a UEFI file for the image reader.
+pub struct FileReader {
+    file: RegularFile,
+    size: u64,
+}
+
+impl binrw::io::Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> binrw::io::Result<usize> {
+        self.file
+            .read(buf)
+            .map_err(|_| binrw::io::Error::new(binrw::io::ErrorKind::Other, "Failed to read file"))
+    }
+}
+
+impl binrw::io::Seek for FileReader {
+    fn seek(&mut self, pos: binrw::io::SeekFrom) -> binrw::io::Result<u64> {
+        let position = match pos {
+            binrw::io::SeekFrom::Start(offset) => Some(offset),
+            binrw::io::SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            binrw::io::SeekFrom::Current(offset) => self
+                .file
+                .get_position()
+                .ok()
+                .and_then(|position| position.checked_add_signed(offset)),
+        }
+        .ok_or_else(|| binrw::io::Error::new(binrw::io::ErrorKind::InvalidInput, "Invalid seek"))?;
+
+        self.file.set_position(position).map_err(|_| {
+            binrw::io::Error::new(binrw::io::ErrorKind::Other, "Failed to seek file")
+        })?;
+        Ok(position)
+    }
+}
+
+/// An image file that was found on the boot filesystem.
+pub struct FoundImage {
+    /// The full path of the image file
+    pub path: String,
+
+    /// The image reader
+    pub reader: ImageReader<FileReader>,
+}
+
+/// Find all readable images in the search directories of the filesystem that
+/// this application was loaded from.
+pub fn find(image_handle: Handle, boot_services: &BootServices) -> uefi::Result<Vec<FoundImage>> {
+    let mut fs = boot_services.get_image_file_system(image_handle)?;
+    let mut root = fs.open_volume()?;
+    let mut images = Vec::new();
+
+    for dir in SEARCH_DIRS {
+        let Some(mut directory) = open_dir(&mut root, dir) else {
+            continue;
+        };
+
+        while let Some(info) = directory.read_entry_boxed()? {
+            let name = String::from(info.file_name());
+            if info.attribute().contains(FileAttribute::DIRECTORY) || !name.ends_with(".gb") {
+                continue;
+            }
+
+            let path = alloc::format!("{}\\{}", dir.trim_end_matches('\\'), name);
+            match open_image(&mut root, &path, &info) {
+                Some(reader) => images.push(FoundImage { path, reader }),
+                None => log::warn!("Skipping invalid image: {}", path),
+            }
+        }
+    }
+
+    Ok(images)
+}
+
+fn open_dir(root: &mut Directory, path: &str) -> Option<Directory> {
+    root.open(
+        &CString16::try_from(path).ok()?,
+        FileMode::Read,
+        FileAttribute::empty(),
+    )
+    .ok()?
+    .into_directory()
+}
+
+fn open_image(
+    root: &mut Directory,
+    path: &str,
+    info: &FileInfo,
+) -> Option<ImageReader<FileReader>> {
+    let file = root
+        .open(
+            &CString16::try_from(path).ok()?,
+            FileMode::Read,
+            FileAttribute::empty(),
+        )
+        .ok()?
+        .into_regular_file()?;
+
+    ImageReader::open(FileReader {
+        file,
+        size: info.file_size(),
+    })
+    .ok()
+}
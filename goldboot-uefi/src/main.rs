@@ -1,3 +1,164 @@
-fn main() {
-    println!("Hello, world!");
+//! A standalone UEFI application that writes goldboot images without booting
+//! an operating system. Copy it to `\EFI\BOOT\BOOTX64.EFI` on a USB stick's
+//! ESP along with some images (in the root or a `\goldboot` directory).
+//!
+//! To test it in QEMU, place the same files in a directory and run:
+//!
+//! ```sh
+//! cargo build --release
+//! mkdir -p esp/EFI/BOOT && cp target/x86_64-unknown-uefi/release/goldboot-uefi.efi esp/EFI/BOOT/BOOTX64.EFI
+//! qemu-system-x86_64 -bios OVMF.fd -drive format=raw,file=fat:rw:esp -drive format=raw,file=disk.raw
+//! ```
+
+#![no_main]
+#![no_std]
+
+extern crate alloc;
+
+use goldboot_image::reader::ReadError;
+use uefi::{prelude::*, println, table::runtime::ResetType};
+
+pub mod console;
+pub mod disk;
+pub mod images;
+
+#[entry]
+fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
+    uefi::helpers::init(&mut system_table).unwrap();
+    let _ = system_table.stdout().clear();
+
+    println!("goldboot");
+    println!();
+
+    let status = run(image_handle, &mut system_table);
+
+    println!("Press any key to reboot");
+    console::read_key(&mut system_table);
+    system_table
+        .runtime_services()
+        .reset(ResetType::COLD, status, None)
+}
+
+fn run(image_handle: Handle, system_table: &mut SystemTable<Boot>) -> Status {
+    // Select an image
+    let mut images = match images::find(image_handle, system_table.boot_services()) {
+        Ok(images) if !images.is_empty() => images,
+        Ok(_) => {
+            println!("No images found");
+            return Status::NOT_FOUND;
+        }
+        Err(error) => {
+            println!("Failed to search for images: {:?}", error);
+            return error.status();
+        }
+    };
+
+    for (i, image) in images.iter().enumerate() {
+        println!(
+            "  {}) {} ({}, {} bytes)",
+            i + 1,
+            image.reader.primary_header.name(),
+            image.path,
+            image.reader.primary_header.size
+        );
+    }
+    let Some(index) = console::select(system_table, "Select an image", images.len()) else {
+        return Status::ABORTED;
+    };
+    let image = &mut images[index];
+
+    // Select the target disk
+    let disks = match disk::find(image_handle, system_table.boot_services()) {
+        Ok(disks) if !disks.is_empty() => disks,
+        Ok(_) => {
+            println!("No disks found");
+            return Status::NOT_FOUND;
+        }
+        Err(error) => {
+            println!("Failed to search for disks: {:?}", error);
+            return error.status();
+        }
+    };
+
+    println!();
+    for (i, disk) in disks.iter().enumerate() {
+        println!(
+            "  {}) {} bytes{}",
+            i + 1,
+            disk.size,
+            if disk.removable { " (removable)" } else { "" }
+        );
+    }
+    let Some(index) = console::select(system_table, "Select the target disk", disks.len()) else {
+        return Status::ABORTED;
+    };
+    let disk = &disks[index];
+
+    if image.reader.primary_header.size > disk.size {
+        println!("The image is larger than the selected disk");
+        return Status::BUFFER_TOO_SMALL;
+    }
+
+    if console::read_line(
+        system_table,
+        "All data on the disk will be lost. Type 'yes' to continue",
+        false,
+    ) != "yes"
+    {
+        return Status::ABORTED;
+    }
+
+    // Decrypt the image if needed
+    let mut attempts = 0;
+    loop {
+        let password = if image.reader.is_encrypted() {
+            Some(console::read_line(system_table, "Password", true))
+        } else {
+            None
+        };
+
+        match image.reader.load(password.as_deref()) {
+            Ok(_) => break,
+            Err(ReadError::Decrypt) if attempts < 2 => {
+                println!("Incorrect password");
+                attempts += 1;
+            }
+            Err(error) => {
+                println!("Failed to load image: {}", error);
+                return Status::LOAD_ERROR;
+            }
+        }
+    }
+
+    // Write it
+    let mut writer = match disk::DiskWriter::open(disk, system_table.boot_services()) {
+        Ok(writer) => writer,
+        Err(error) => {
+            println!("Failed to open disk: {:?}", error);
+            return error.status();
+        }
+    };
+
+    let mut written = 0;
+    let mut last_percent = u64::MAX;
+    let result = image.reader.write(&mut writer, |size, total| {
+        written += size;
+        let percent = written * 100 / total.max(1);
+        if percent != last_percent {
+            uefi::print!("\rWriting: {}%", percent);
+            last_percent = percent;
+        }
+    });
+    println!();
+
+    match result {
+        Ok(_) => {
+            println!("Write complete");
+            Status::SUCCESS
+        }
+        Err(error) => {
+            println!("Failed to write image: {}", error);
+            Status::DEVICE_ERROR
+        }
+    }
 }
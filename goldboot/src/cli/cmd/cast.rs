@@ -1,5 +1,5 @@
 use crate::foundry::{Foundry, FoundryConfigPath};
-use std::process::ExitCode;
+use std::{path::Path, process::ExitCode};
 use tracing::debug;
 use tracing::error;
 use validator::Validate;
@@ -9,12 +9,17 @@ pub fn run(cmd: super::Commands) -> ExitCode {
         super::Commands::Cast {
             record,
             debug,
+            dashboard,
             read_password,
             no_accel,
+            no_cache,
+            resume,
+            keep_failed,
+            on_failure,
             output,
             path,
         } => {
-            let config_path = match FoundryConfigPath::from_dir(path) {
+            let config_path = match FoundryConfigPath::from_dir(&path) {
                 Some(p) => {
                     debug!("Loading config from {}", p);
                     p
@@ -28,7 +33,15 @@ pub fn run(cmd: super::Commands) -> ExitCode {
             // Load config from current directory
             let mut foundry: Foundry = config_path.load().unwrap();
             foundry.debug = debug;
+            foundry.dashboard = dashboard;
             foundry.record = record;
+            foundry.no_cache = no_cache;
+            foundry.resume = resume;
+            foundry.keep_failed = keep_failed;
+            foundry.on_failure = on_failure;
+            foundry.checkpoints = Some(Path::new(&path).join(".goldboot").join("checkpoints"));
+            foundry.report = Some(Path::new(&path).join(".goldboot").join("report.json"));
+            foundry.screenshots = Some(Path::new(&path).join(".goldboot").join("screenshots"));
             debug!("Loaded: {:#?}", &foundry);
 
             // Include the encryption password if provided
@@ -0,0 +1,175 @@
+use console::Style;
+use dialoguer::theme::ColorfulTheme;
+use goldboot_image::{HeaderEncryptionType, ImageHandle};
+use std::{path::Path, process::ExitCode};
+use tracing::{error, info};
+
+use super::MulticastCommands;
+use crate::{
+    cli::{progress::ProgressBar, prompt::image_password},
+    deploy::{
+        client,
+        multicast::{self, MulticastReceiver, MulticastSender},
+        server, DeployConfig,
+    },
+    library::ImageLibrary,
+};
+
+pub fn run(cmd: super::Commands) -> ExitCode {
+    match cmd {
+        super::Commands::ServeDeploy { config, port } => {
+            let mut config = match DeployConfig::load(&config) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!(error = %err, "Failed to load deployment config");
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            if let Some(port) = port {
+                config.port = port;
+            }
+
+            match server::run(config) {
+                Ok(_) => ExitCode::SUCCESS,
+                Err(err) => {
+                    error!(error = %err, "Deployment server failed");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        super::Commands::DeployClient {
+            server,
+            mac,
+            reboot,
+        } => {
+            #[cfg(not(target_os = "linux"))]
+            if reboot {
+                error!("Rebooting is only supported on Linux");
+                return ExitCode::FAILURE;
+            }
+
+            if let Err(err) = client::run(server, mac) {
+                error!(error = %err, "Deployment failed");
+                return ExitCode::FAILURE;
+            }
+
+            #[cfg(target_os = "linux")]
+            if reboot {
+                if let Err(err) = crate::pivot_root::reboot() {
+                    error!(error = %err, "Failed to reboot");
+                    return ExitCode::FAILURE;
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        super::Commands::Multicast { command } => match command {
+            MulticastCommands::Send {
+                image,
+                group,
+                repair_port,
+                passes,
+                rate,
+                read_password,
+            } => {
+                let image_handle = if Path::new(&image).exists() {
+                    ImageHandle::open(&image)
+                } else {
+                    ImageLibrary::find_by_id(&image)
+                };
+
+                let result = image_handle
+                    .and_then(|mut image_handle| {
+                        let password = match image_handle.primary_header.encryption_type {
+                            HeaderEncryptionType::None => None,
+                            _ => Some(image_password(read_password)?),
+                        };
+                        image_handle.load(password)?;
+                        MulticastSender::new(
+                            image_handle,
+                            multicast::parse_group(&group)?,
+                            repair_port,
+                        )
+                    })
+                    .and_then(|mut sender| {
+                        if let Some(rate) = rate {
+                            sender.rate = rate * 1_000_000 / 8;
+                        }
+                        sender.send(passes)
+                    });
+
+                if let Err(err) = result {
+                    error!(error = %err, "Multicast failed");
+                    return ExitCode::FAILURE;
+                }
+
+                // Late receivers may still need repairs
+                info!("Serving repairs until interrupted");
+                loop {
+                    std::thread::park();
+                }
+            }
+            MulticastCommands::Receive {
+                server,
+                output,
+                group,
+                confirm,
+                read_password,
+            } => {
+                let theme = ColorfulTheme {
+                    values_style: Style::new().yellow().dim(),
+                    ..ColorfulTheme::default()
+                };
+
+                // The image isn't known until the sender is reached, so only an
+                // explicitly given password is used
+                let password = if read_password || std::env::var("GOLDBOOT_PASSWORD").is_ok() {
+                    match image_password(read_password) {
+                        Ok(password) => Some(password),
+                        Err(err) => {
+                            error!(error = %err, "Failed to read password");
+                            return ExitCode::FAILURE;
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let mut receiver = match multicast::parse_server(&server).and_then(|server| {
+                    MulticastReceiver::connect(server, multicast::parse_group(&group)?, password)
+                }) {
+                    Ok(receiver) => receiver,
+                    Err(err) => {
+                        error!(error = %err, "Failed to connect to multicast sender");
+                        return ExitCode::FAILURE;
+                    }
+                };
+
+                if crate::device::is_block_device(&output) {
+                    match super::write::check_device(
+                        &output,
+                        receiver.image.primary_header.size,
+                        confirm,
+                        &theme,
+                    ) {
+                        Ok(true) => {}
+                        Ok(false) => return ExitCode::SUCCESS,
+                        Err(err) => {
+                            error!(error = %err, "Refusing to write device");
+                            return ExitCode::FAILURE;
+                        }
+                    }
+                }
+
+                match receiver.receive(&output, ProgressBar::Write.new_empty()) {
+                    Ok(_) => ExitCode::SUCCESS,
+                    Err(err) => {
+                        error!(error = %err, "Failed to receive image");
+                        ExitCode::FAILURE
+                    }
+                }
+            }
+        },
+        _ => panic!(),
+    }
+}
@@ -57,6 +57,7 @@ pub fn run(cmd: super::Commands) -> ExitCode {
                 for m in mold {
                     if let Ok(source) = m.default_source(foundry.arch) {
                         foundry.alloy.push(ImageElement {
+                            boot: None,
                             source,
                             mold: m,
                             fabricators: None,
@@ -164,6 +165,7 @@ pub fn run(cmd: super::Commands) -> ExitCode {
 
                     if let Ok(source) = mold.default_source(foundry.arch) {
                         foundry.alloy.push(ImageElement {
+                            boot: None,
                             source,
                             mold: mold.to_owned(),
                             fabricators: None,
@@ -1,17 +1,19 @@
-use crate::foundry::{molds::ImageMold, FoundryConfigPath};
+use crate::foundry::{molds::ImageMold, qemu::OnFailure, FoundryConfigPath};
 
 pub mod cast;
+pub mod deploy;
 pub mod image;
 pub mod init;
 pub mod registry;
+pub mod run;
 pub mod write;
 
 #[derive(clap::Subcommand, Debug)]
 pub enum Commands {
     /// Cast (build) a new image
     Cast {
-        /// Save a screenshot to ./screenshots after each boot command for
-        /// debugging
+        /// Record the screen during the cast to .goldboot/screenshots along
+        /// with an HTML timeline for review
         #[clap(long, num_args = 0)]
         record: bool,
 
@@ -19,6 +21,10 @@ pub enum Commands {
         #[clap(long, num_args = 0)]
         debug: bool,
 
+        /// Serve a local web dashboard for watching and debugging the cast
+        #[clap(long, num_args = 0..=1, default_missing_value = "8080")]
+        dashboard: Option<u16>,
+
         /// Read the encryption password from STDIN
         #[clap(long, num_args = 0)]
         read_password: bool,
@@ -27,6 +33,23 @@ pub enum Commands {
         #[clap(long, num_args = 0)]
         no_accel: bool,
 
+        /// Rebuild every step instead of reusing cached build layers
+        #[clap(long, num_args = 0)]
+        no_cache: bool,
+
+        /// Keep checkpoints with --no-cache and continue from those of a previous
+        /// failed cast (cached casts always continue from the last unchanged step)
+        #[clap(long, num_args = 0, requires = "no_cache")]
+        resume: bool,
+
+        /// Keep the temporary files of a failed cast for inspection
+        #[clap(long, num_args = 0)]
+        keep_failed: bool,
+
+        /// What to do with the VM when the cast fails
+        #[clap(long, value_enum)]
+        on_failure: Option<OnFailure>,
+
         /// The optional output destination (defaults to image library)
         #[clap(long)]
         output: Option<String>,
@@ -47,17 +70,38 @@ pub enum Commands {
 
     /// Write images to storage
     Write {
-        /// The ID or path of the image to write
-        #[clap(index = 1)]
-        image: String,
+        /// The ID, path, URL, or registry reference (<registry>/<image id>) of
+        /// the image to write. With --gbl, this is the goldboot Linux image and
+        /// defaults to the newest one in the library.
+        #[clap(index = 1, required_unless_present = "gbl")]
+        image: Option<String>,
 
         /// The output destination
         #[clap(long)]
         output: String,
 
-        /// Do not prompt for confirmation (be extremely careful with this)
+        /// Do not prompt for confirmation (be extremely careful with this).
+        /// Devices with mounted filesystems are always refused.
         #[clap(long, num_args = 0)]
         confirm: bool,
+
+        /// A manifest describing how to personalize the written disk (hostname,
+        /// injected files, regenerated keys)
+        #[clap(long)]
+        personalize: Option<String>,
+
+        /// Reimage the disk the current system is running from. The image is
+        /// staged in memory and the machine reboots once the write completes.
+        #[clap(long, num_args = 0)]
+        live: bool,
+
+        /// Create bootable goldboot Linux media carrying the given images
+        #[clap(long, num_args = 1..)]
+        gbl: Vec<String>,
+
+        /// Continue a live write from the temporary root (internal use only)
+        #[clap(long, num_args = 0, hide = true)]
+        live_stage2: bool,
     },
 
     /// Initialize the current directory
@@ -85,6 +129,63 @@ pub enum Commands {
         mimic_hardware: bool,
     },
 
+    /// Serve images to machines that boot from the network
+    ServeDeploy {
+        /// The deployment configuration file
+        #[clap(index = 1)]
+        config: String,
+
+        /// Override the HTTP port from the configuration
+        #[clap(long)]
+        port: Option<u16>,
+    },
+
+    /// Write the image assigned to this machine by a deployment server
+    DeployClient {
+        /// The deployment server URL (defaults to goldboot.server on the
+        /// kernel command line)
+        #[clap(long)]
+        server: Option<String>,
+
+        /// This machine's MAC address (defaults to goldboot.mac on the kernel
+        /// command line or the first network interface)
+        #[clap(long)]
+        mac: Option<String>,
+
+        /// Reboot once the image is written
+        #[clap(long, num_args = 0)]
+        reboot: bool,
+    },
+
+    /// Distribute an image to many machines at once over multicast
+    Multicast {
+        #[clap(subcommand)]
+        command: MulticastCommands,
+    },
+
+    /// Boot an image in a temporary VM. Changes are discarded on exit.
+    Run {
+        /// The ID, name, path, or registry reference of the image to boot
+        #[clap(index = 1)]
+        image: String,
+
+        /// The QEMU display type (VNC is always available)
+        #[clap(long, default_value = "sdl")]
+        display: String,
+
+        /// The host port that's forwarded to the image's SSH server
+        #[clap(long, default_value_t = 2222)]
+        ssh_port: u16,
+
+        /// The amount of memory to allocate to the VM
+        #[clap(long, default_value = "4G")]
+        memory: String,
+
+        /// Read the image's encryption password from STDIN
+        #[clap(long, num_args = 0)]
+        read_password: bool,
+    },
+
     /// Manage image registries
     Registry {
         #[clap(subcommand)]
@@ -92,6 +193,60 @@ pub enum Commands {
     },
 }
 
+#[derive(clap::Subcommand, Debug)]
+pub enum MulticastCommands {
+    /// Send an image to a multicast group
+    Send {
+        /// The image ID or path
+        #[clap(index = 1)]
+        image: String,
+
+        /// The multicast group address
+        #[clap(long, default_value = "239.255.42.99:4242")]
+        group: String,
+
+        /// The port of the unicast repair channel
+        #[clap(long, default_value_t = 4243)]
+        repair_port: u16,
+
+        /// The number of times to send the image
+        #[clap(long, default_value_t = 3)]
+        passes: usize,
+
+        /// The maximum send rate in megabits per second
+        #[clap(long)]
+        rate: Option<u64>,
+
+        /// Read the image's encryption password from STDIN
+        #[clap(long, num_args = 0)]
+        read_password: bool,
+    },
+
+    /// Receive an image from a multicast sender
+    Receive {
+        /// The sender's repair channel address (host:port)
+        #[clap(index = 1)]
+        server: String,
+
+        /// The output file or device
+        #[clap(long)]
+        output: String,
+
+        /// The multicast group address
+        #[clap(long, default_value = "239.255.42.99:4242")]
+        group: String,
+
+        /// Do not prompt for confirmation (be extremely careful with this).
+        /// Devices with mounted filesystems are always refused.
+        #[clap(long, num_args = 0)]
+        confirm: bool,
+
+        /// Read the image's encryption password from STDIN
+        #[clap(long, num_args = 0)]
+        read_password: bool,
+    },
+}
+
 #[derive(clap::Subcommand, Debug)]
 pub enum RegistryCommands {
     /// Enter a token for a registry
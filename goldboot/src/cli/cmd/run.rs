@@ -0,0 +1,64 @@
+use crate::{
+    cli::{progress::ProgressBar, prompt::image_password},
+    foundry::{ovmf, qemu::QemuBuilder, sources::mold::find_base},
+};
+use anyhow::Result;
+use goldboot_image::HeaderEncryptionType;
+use std::process::ExitCode;
+use tracing::{error, info};
+
+pub fn run(cmd: super::Commands) -> ExitCode {
+    match cmd {
+        super::Commands::Run {
+            image,
+            display,
+            ssh_port,
+            memory,
+            read_password,
+        } => match boot(&image, &display, ssh_port, &memory, read_password) {
+            Err(err) => {
+                error!(error = %err, "Failed to run image");
+                ExitCode::FAILURE
+            }
+            _ => ExitCode::SUCCESS,
+        },
+        _ => panic!(),
+    }
+}
+
+/// Write the image to a temporary disk and boot it until the VM exits.
+fn boot(
+    image: &str,
+    display: &str,
+    ssh_port: u16,
+    memory: &str,
+    read_password: bool,
+) -> Result<()> {
+    let mut image_handle = find_base(image)?;
+    let password = match image_handle.primary_header.encryption_type {
+        HeaderEncryptionType::None => None,
+        _ => Some(image_password(read_password)?),
+    };
+    image_handle.load(password)?;
+
+    // Everything is discarded when this is dropped
+    let tmp = tempfile::tempdir()?;
+    let disk = tmp.path().join("disk.raw");
+    image_handle.write(&disk, ProgressBar::Write.new_empty())?;
+
+    let arch = image_handle.primary_header.arch;
+    let mut qemu =
+        QemuBuilder::standalone(arch, &ovmf::prepare(arch, tmp.path())?, tmp.path(), memory)?
+            .disk(&disk, "raw")
+            .display(display)
+            .forward_ssh(ssh_port)
+            .start()?;
+
+    info!(
+        image = %image_handle.primary_header.name(),
+        vnc_port = qemu.vnc_port,
+        ssh_port,
+        "Booted image; changes will be discarded when the VM exits"
+    );
+    qemu.wait()
+}
@@ -1,10 +1,27 @@
+use anyhow::bail;
+use anyhow::Result;
 use console::Style;
+#[cfg(unix)]
+use dialoguer::Input;
 use dialoguer::{theme::ColorfulTheme, Confirm};
 use goldboot_image::ImageHandle;
+#[cfg(target_os = "linux")]
+use std::{fs::File, io::Seek, os::unix::process::CommandExt, process::Command};
 use std::{path::Path, process::ExitCode};
-use tracing::error;
+#[cfg(target_os = "linux")]
+use tracing::warn;
+use tracing::{error, info};
+#[cfg(unix)]
+use ubyte::ToByteUnit;
 
+use crate::device::is_block_device;
+#[cfg(unix)]
+use crate::device::BlockDevice;
+#[cfg(unix)]
+use crate::personalize::Personalization;
 use crate::{cli::progress::ProgressBar, library::ImageLibrary};
+#[cfg(target_os = "linux")]
+use crate::{pivot_root, registry::remote::HttpRangeReader};
 
 pub fn run(cmd: super::Commands) -> ExitCode {
     match cmd {
@@ -12,47 +29,327 @@ pub fn run(cmd: super::Commands) -> ExitCode {
             image,
             output,
             confirm,
+            personalize,
+            live,
+            live_stage2,
+            gbl,
         } => {
+            #[cfg(not(target_os = "linux"))]
+            if live || live_stage2 {
+                error!("Live writes are only supported on Linux");
+                return ExitCode::FAILURE;
+            }
+
+            #[cfg(target_os = "linux")]
+            if live_stage2 {
+                // Services are stopped and the old root is gone, so exiting
+                // would strand the machine
+                if let Err(err) = write_live_stage2(&output) {
+                    pivot_root::emergency(&err);
+                }
+                return ExitCode::SUCCESS;
+            }
+
+            if live && personalize.is_some() {
+                error!("Personalization is not supported for live writes");
+                return ExitCode::FAILURE;
+            }
+
+            if !gbl.is_empty() && (live || personalize.is_some()) {
+                error!("GBL media cannot be combined with live writes or personalization");
+                return ExitCode::FAILURE;
+            }
+
+            // GBL media is based on the newest goldboot Linux image by default
+            let image = match image {
+                Some(image) => image,
+                None => match crate::gbl::find_base() {
+                    Ok(image_handle) => image_handle.path.to_string_lossy().to_string(),
+                    Err(err) => {
+                        error!(error = %err, "Failed to find goldboot Linux image");
+                        return ExitCode::FAILURE;
+                    }
+                },
+            };
+
+            // The images to include on GBL media
+            let gbl_images = match gbl
+                .iter()
+                .map(|image| {
+                    if Path::new(image).exists() {
+                        ImageHandle::open(image)
+                    } else {
+                        ImageLibrary::find_by_id(image)
+                    }
+                })
+                .collect::<Result<Vec<ImageHandle>>>()
+            {
+                Ok(gbl_images) => gbl_images,
+                Err(err) => {
+                    error!(error = %err, "Failed to open image for GBL media");
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            #[cfg(not(unix))]
+            if personalize.is_some() {
+                error!("Personalization is only supported on Unix");
+                return ExitCode::FAILURE;
+            }
+
+            // Load the manifest early so we don't fail after writing
+            #[cfg(unix)]
+            let personalization = match personalize.map(Personalization::load).transpose() {
+                Ok(personalization) => personalization,
+                Err(err) => {
+                    error!(error = %err, "Failed to load personalization manifest");
+                    return ExitCode::FAILURE;
+                }
+            };
+
             let theme = ColorfulTheme {
                 values_style: Style::new().yellow().dim(),
                 ..ColorfulTheme::default()
             };
 
+            // Remote images are streamed directly to the output
+            let mut remote = None;
+
             let mut image_handle = if Path::new(&image).exists() {
                 match ImageHandle::open(&image) {
                     Ok(image_handle) => image_handle,
                     Err(_) => return ExitCode::FAILURE,
                 }
+            } else if let Some(url) = crate::registry::remote::resolve_url(&image) {
+                match crate::registry::remote::open(&url) {
+                    Ok((image_handle, reader)) => {
+                        remote = Some(reader);
+                        image_handle
+                    }
+                    Err(err) => {
+                        error!(error = %err, "Failed to open remote image");
+                        return ExitCode::FAILURE;
+                    }
+                }
             } else {
                 match ImageLibrary::find_by_id(&image) {
                     Ok(image_handle) => image_handle,
                     Err(_) => return ExitCode::FAILURE,
                 }
             };
-            if image_handle.load(None).is_err() {
+
+            if remote.is_some() && !gbl_images.is_empty() {
+                error!("GBL media requires a local goldboot Linux image");
                 return ExitCode::FAILURE;
             }
 
-            if Path::new(&output).exists() && !confirm {
-                if !Confirm::with_theme(&theme)
+            let loaded = match remote.as_mut() {
+                Some(reader) => image_handle.load_from(reader, None),
+                None => image_handle.load(None),
+            };
+            if loaded.is_err() {
+                return ExitCode::FAILURE;
+            }
+
+            #[cfg(target_os = "linux")]
+            if live {
+                // This only returns if the write didn't start
+                if let Err(err) = write_live(&image_handle, remote, &output, confirm, &theme) {
+                    error!(error = %err, "Failed to start live write");
+                    return ExitCode::FAILURE;
+                }
+                return ExitCode::SUCCESS;
+            }
+
+            if is_block_device(&output) {
+                let required_size = if gbl_images.is_empty() {
+                    image_handle.primary_header.size
+                } else {
+                    crate::gbl::required_size(&image_handle, &gbl_images)
+                };
+
+                match check_device(&output, required_size, confirm, &theme) {
+                    Ok(true) => {}
+                    Ok(false) => std::process::exit(0),
+                    Err(err) => {
+                        error!(error = %err, "Refusing to write device");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            } else if Path::new(&output).exists()
+                && !confirm
+                && !Confirm::with_theme(&theme)
                     .with_prompt("Do you want to continue?")
                     .interact()
                     .unwrap()
-                {
-                    std::process::exit(0);
-                }
+            {
+                std::process::exit(0);
             }
 
-            // TODO special case for GBL; select images to include
+            let result = match remote {
+                _ if !gbl_images.is_empty() => {
+                    crate::gbl::write(&image_handle, &gbl_images, &output)
+                }
+                Some(reader) => {
+                    image_handle.write_from(reader, &output, ProgressBar::Write.new_empty())
+                }
+                None => image_handle.write(&output, ProgressBar::Write.new_empty()),
+            };
 
-            match image_handle.write(output, ProgressBar::Write.new_empty()) {
-                Err(err) => {
-                    error!(error = %err, "Failed to write image");
-                    ExitCode::FAILURE
+            if let Err(err) = result {
+                error!(error = %err, "Failed to write image");
+                return ExitCode::FAILURE;
+            }
+
+            #[cfg(unix)]
+            if let Some(personalization) = personalization {
+                if let Err(err) = personalization.apply(&output) {
+                    error!(error = %err, "Failed to personalize image");
+                    return ExitCode::FAILURE;
                 }
-                _ => ExitCode::SUCCESS,
             }
+
+            ExitCode::SUCCESS
         }
         _ => panic!(),
     }
 }
+
+/// Check that the given block device is safe to overwrite and confirm with the
+/// user. Returns whether the write should proceed.
+#[cfg(unix)]
+pub(crate) fn check_device(
+    path: &str,
+    image_size: u64,
+    confirm: bool,
+    theme: &ColorfulTheme,
+) -> Result<bool> {
+    let device = BlockDevice::open(path)?;
+    device.check_target(image_size)?;
+    let size = device.size()?;
+
+    println!("Target device:   {}", device.path.display());
+    println!(
+        "Model:           {}",
+        device.model().unwrap_or(String::from("unknown"))
+    );
+    println!(
+        "Serial:          {}",
+        device.serial().unwrap_or(String::from("unknown"))
+    );
+    println!("Size:            {}", size.bytes());
+    println!("Removable:       {}", device.removable());
+    match device.partition_table() {
+        Ok(partition_table) => println!("Partition table: {}", partition_table),
+        Err(_) => println!("Partition table: unknown"),
+    }
+    for partition in device.partitions()? {
+        println!(
+            "    {:12} {}",
+            partition.name,
+            partition.size().unwrap_or(0).bytes()
+        );
+    }
+
+    if confirm {
+        return Ok(true);
+    }
+
+    confirm_device(&device, theme)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn check_device(
+    path: &str,
+    _image_size: u64,
+    _confirm: bool,
+    _theme: &ColorfulTheme,
+) -> Result<bool> {
+    bail!("Not a block device: {}", path)
+}
+
+#[cfg(unix)]
+fn confirm_device(device: &BlockDevice, theme: &ColorfulTheme) -> Result<bool> {
+    if device.removable() {
+        Ok(Confirm::with_theme(theme)
+            .with_prompt(format!(
+                "All data on {} will be lost. Do you want to continue?",
+                device.path.display()
+            ))
+            .interact()?)
+    } else {
+        // Make it harder to overwrite internal disks by accident
+        let name: String = Input::with_theme(theme)
+            .with_prompt(format!(
+                "{} is not removable. Type its name ({}) to continue",
+                device.path.display(),
+                device.name
+            ))
+            .allow_empty(true)
+            .interact()?;
+
+        Ok(name == device.name || Path::new(&name) == device.path)
+    }
+}
+
+/// Stage the image in memory, pivot to a temporary root, and hand over to the
+/// staged binary to overwrite the disk the system is running from.
+#[cfg(target_os = "linux")]
+fn write_live(
+    image_handle: &ImageHandle,
+    remote: Option<HttpRangeReader>,
+    output: &str,
+    confirm: bool,
+    theme: &ColorfulTheme,
+) -> Result<()> {
+    if !is_block_device(output) {
+        bail!("Live writes require a block device: {}", output);
+    }
+
+    pivot_root::check_prerequisites(image_handle.file_size)?;
+
+    let device = BlockDevice::open(output)?;
+    warn!(
+        device = ?device.path,
+        "All services will be stopped and the system will reboot after the write"
+    );
+    if !confirm && !confirm_device(&device, theme)? {
+        return Ok(());
+    }
+
+    let root = match remote {
+        Some(mut reader) => {
+            reader.rewind()?;
+            pivot_root::stage(&mut reader, image_handle.file_size)?
+        }
+        None => pivot_root::stage(&mut File::open(&image_handle.path)?, image_handle.file_size)?,
+    };
+
+    pivot_root::stop_services()?;
+    pivot_root::pivot(&root)?;
+
+    info!("Continuing on the system console");
+    let err = match pivot_root::redirect_to_console() {
+        Ok(()) => Command::new(pivot_root::STAGED_BINARY)
+            .args(["write", pivot_root::STAGED_IMAGE, "--output", output])
+            .arg("--live-stage2")
+            .exec()
+            .into(),
+        Err(err) => err,
+    };
+
+    // The old root is gone after the pivot, so there's nothing to return to
+    pivot_root::emergency(&err)
+}
+
+/// Finish a live write from the temporary root.
+#[cfg(target_os = "linux")]
+fn write_live_stage2(output: &str) -> Result<()> {
+    pivot_root::release_old_root()?;
+
+    let mut image_handle = ImageHandle::open(pivot_root::STAGED_IMAGE)?;
+    image_handle.load(None)?;
+    image_handle.write(output, ProgressBar::Write.new_empty())?;
+
+    pivot_root::reboot()
+}
@@ -98,17 +98,14 @@ impl ProgressBar {
         let mut copied: u64 = 0;
 
         loop {
-            if let Ok(size) = reader.read(&mut buffer) {
-                if size == 0 {
-                    break;
-                }
-                writer.write(&buffer[0..size])?;
-                let new = min(copied + (size as u64), len);
-                copied = new;
-                progress.set_position(new);
-            } else {
+            let size = reader.read(&mut buffer)?;
+            if size == 0 {
                 break;
             }
+            writer.write_all(&buffer[0..size])?;
+            let new = min(copied + (size as u64), len);
+            copied = new;
+            progress.set_position(new);
         }
 
         progress.finish_and_clear();
@@ -16,3 +16,25 @@ pub trait PromptNew {
     where
         Self: Sized;
 }
+
+/// Get the password of an encrypted image from STDIN (with `--read-password`),
+/// the `GOLDBOOT_PASSWORD` variable, or an interactive prompt.
+pub fn image_password(read_password: bool) -> Result<String> {
+    if read_password {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    if let Ok(password) = std::env::var("GOLDBOOT_PASSWORD") {
+        // Wipe out the value since we no longer need it
+        std::env::set_var("GOLDBOOT_PASSWORD", "");
+        return Ok(password);
+    }
+
+    Ok(
+        dialoguer::Password::with_theme(&dialoguer::theme::ColorfulTheme::default())
+            .with_prompt("Image password?")
+            .interact()?,
+    )
+}
@@ -0,0 +1,155 @@
+use super::{normalize_mac, AssignmentResponse, DeployState, ProgressUpdate};
+use crate::device::{is_block_device, BlockDevice};
+use anyhow::{anyhow, bail, Result};
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// How often progress is reported to the server.
+const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Find the value of the given parameter in a kernel command line.
+pub fn cmdline_arg(cmdline: &str, key: &str) -> Option<String> {
+    cmdline.split_whitespace().find_map(|arg| {
+        arg.strip_prefix(key)
+            .and_then(|value| value.strip_prefix('='))
+            .map(|value| value.to_string())
+    })
+}
+
+/// Get the MAC address of the first network interface that has one.
+fn detect_mac() -> Result<String> {
+    let mut interfaces: Vec<_> = std::fs::read_dir("/sys/class/net")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect();
+    interfaces.sort();
+
+    interfaces
+        .iter()
+        .filter_map(|interface| std::fs::read_to_string(interface.join("address")).ok())
+        .map(|address| normalize_mac(&address))
+        .find(|address| address != "00:00:00:00:00:00")
+        .ok_or_else(|| anyhow!("Failed to find a network interface"))
+}
+
+/// Reports deployment progress to the server. Failures are only logged since
+/// they shouldn't interrupt the write.
+struct Reporter {
+    client: reqwest::blocking::Client,
+    url: String,
+}
+
+impl Reporter {
+    fn report(&self, state: DeployState, written: u64, total: u64, message: Option<String>) {
+        if let Err(err) = self
+            .client
+            .post(&self.url)
+            .json(&ProgressUpdate {
+                state,
+                written,
+                total,
+                message,
+            })
+            .send()
+        {
+            warn!(error = %err, "Failed to report progress");
+        }
+    }
+}
+
+/// Request this machine's assignment from the deployment server and write it.
+/// The server and MAC address are taken from the kernel command line if not
+/// given.
+pub fn run(server: Option<String>, mac: Option<String>) -> Result<()> {
+    let cmdline = std::fs::read_to_string("/proc/cmdline").unwrap_or_default();
+
+    let server = server
+        .or_else(|| cmdline_arg(&cmdline, "goldboot.server"))
+        .ok_or_else(|| anyhow!("No deployment server given"))?;
+    let server = server.trim_end_matches('/');
+
+    let mac = match mac.or_else(|| cmdline_arg(&cmdline, "goldboot.mac")) {
+        Some(mac) => normalize_mac(&mac),
+        None => detect_mac()?,
+    };
+
+    info!(server = %server, mac = %mac, "Requesting assignment");
+    let assignment: AssignmentResponse =
+        reqwest::blocking::get(format!("{server}/assignment/{mac}"))?
+            .error_for_status()?
+            .json()?;
+
+    let reporter = Reporter {
+        client: reqwest::blocking::Client::new(),
+        url: format!("{server}/progress/{mac}"),
+    };
+
+    let result = (|| -> Result<()> {
+        let (mut image_handle, mut reader) =
+            crate::registry::remote::open(&format!("{server}{}", assignment.url))?;
+        image_handle.load_from(&mut reader, None)?;
+
+        // The same refusals as a manual write apply, but nobody is around to
+        // confirm. Anything other than a disk would just fill up /dev.
+        if !is_block_device(&assignment.device) {
+            bail!("Not a block device: {}", assignment.device);
+        }
+        BlockDevice::open(&assignment.device)?.check_target(image_handle.primary_header.size)?;
+
+        info!(
+            image_id = %assignment.image_id,
+            device = %assignment.device,
+            "Writing assigned image"
+        );
+
+        let written = Cell::new(0u64);
+        let last_report = Cell::new(Instant::now());
+
+        // The same total that the write reports
+        let total = image_handle
+            .protected_header
+            .as_ref()
+            .map(|header| header.cluster_count as u64 * header.block_size as u64)
+            .unwrap_or_default();
+
+        image_handle.write_from(reader, &assignment.device, |block, _| {
+            written.set(written.get() + block);
+
+            if last_report.get().elapsed() >= REPORT_INTERVAL {
+                last_report.set(Instant::now());
+                reporter.report(DeployState::Writing, written.get(), total, None);
+            }
+        })?;
+
+        reporter.report(DeployState::Done, total, total, None);
+        Ok(())
+    })();
+
+    if let Err(err) = &result {
+        reporter.report(DeployState::Failed, 0, 0, Some(err.to_string()));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cmdline_arg() {
+        let cmdline = "initrd=initrd.img goldboot.server=http://10.0.0.1:8080 goldboot.mac=52:54:00:12:34:56 quiet";
+
+        assert_eq!(
+            cmdline_arg(cmdline, "goldboot.server"),
+            Some(String::from("http://10.0.0.1:8080"))
+        );
+        assert_eq!(
+            cmdline_arg(cmdline, "goldboot.mac"),
+            Some(String::from("52:54:00:12:34:56"))
+        );
+        assert_eq!(cmdline_arg(cmdline, "goldboot"), None);
+    }
+}
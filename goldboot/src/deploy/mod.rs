@@ -0,0 +1,232 @@
+//! Network deployment writes images to many machines at once without any
+//! removable media. Machines boot from the network (PXE with iPXE or UEFI HTTP
+//! boot) into a small client which asks the deployment server for its assigned
+//! image, streams it to the local disk, and reports progress back.
+//!
+//! The server doesn't answer DHCP itself. Instead, an existing DHCP server (or
+//! dnsmasq in proxy mode) should point clients at the TFTP root for PXE, or
+//! directly at `http://<server>/boot.ipxe` for iPXE and HTTP boot. For example:
+//!
+//! ```text
+//! dnsmasq --port=0 --dhcp-range=192.168.100.0,proxy --pxe-service=x86-64_EFI,goldboot,ipxe.efi \
+//!     --dhcp-userclass=set:ipxe,iPXE --dhcp-boot=tag:ipxe,http://192.168.100.1:8080/boot.ipxe
+//! ```
+//!
+//! To test end to end, attach the server and some QEMU guests to the same
+//! bridge (`-netdev bridge,id=n0,br=br0 -device virtio-net,netdev=n0`) and boot
+//! the guests with `-boot n`. The ignored `deploy` integration test does the
+//! same for a single guest over user networking.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+pub mod client;
+pub mod multicast;
+pub mod server;
+pub mod tftp;
+
+/// Configuration for a deployment server.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct DeployConfig {
+    /// The address that clients use to reach the server
+    pub address: String,
+
+    /// The HTTP port
+    #[serde(default = "default_port")]
+    pub port: u16,
+
+    /// The image for machines that don't have an assignment. Every machine
+    /// that network boots gets it, so it's only used with `default_device`.
+    pub default_image: Option<String>,
+
+    /// The disk to write the default image to
+    pub default_device: Option<String>,
+
+    /// Image assignments for individual machines
+    #[serde(default)]
+    pub machines: Vec<Assignment>,
+
+    /// The kernel of the network boot client
+    pub client_kernel: Option<String>,
+
+    /// The initramfs of the network boot client which must run
+    /// `goldboot deploy-client` on boot
+    pub client_initrd: Option<String>,
+
+    /// A directory of files to serve over TFTP (like iPXE binaries)
+    pub tftp_root: Option<String>,
+}
+
+fn default_port() -> u16 {
+    8080
+}
+
+fn default_device() -> String {
+    String::from("/dev/sda")
+}
+
+/// Assigns an image to a machine.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Assignment {
+    /// The machine's MAC address
+    pub mac: String,
+
+    /// The ID or path of the image to write
+    pub image: String,
+
+    /// The disk to write the image to
+    #[serde(default = "default_device")]
+    pub device: String,
+}
+
+impl DeployConfig {
+    /// Load a configuration from a JSON, RON, TOML, or YAML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)?;
+
+        Ok(match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_slice(&data)?,
+            Some("ron") => ron::de::from_bytes(&data)?,
+            Some("toml") => toml::from_str(&String::from_utf8(data)?)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_slice(&data)?,
+            _ => bail!("Unknown config format: {}", path.display()),
+        })
+    }
+
+    /// Check the configuration for mistakes that would write the wrong disks.
+    pub fn validate(&self) -> Result<()> {
+        if self.default_image.is_some() && self.default_device.is_none() {
+            bail!("The default image needs an explicit default device");
+        }
+        Ok(())
+    }
+
+    /// The URL clients use to reach the server.
+    pub fn url(&self) -> String {
+        format!("http://{}:{}", self.address, self.port)
+    }
+
+    /// Find the assignment for the given machine.
+    pub fn assignment(&self, mac: &str) -> Option<Assignment> {
+        let mac = normalize_mac(mac);
+
+        self.machines
+            .iter()
+            .find(|assignment| normalize_mac(&assignment.mac) == mac)
+            .cloned()
+            .or_else(|| {
+                self.default_image
+                    .clone()
+                    .zip(self.default_device.clone())
+                    .map(|(image, device)| Assignment { mac, image, device })
+            })
+    }
+}
+
+/// The response to a client asking for its assignment.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AssignmentResponse {
+    pub image_id: String,
+
+    /// The image URL relative to the server
+    pub url: String,
+
+    /// The disk to write the image to
+    pub device: String,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum DeployState {
+    /// The client received its assignment
+    Assigned,
+
+    /// The image is being written
+    Writing,
+
+    /// The image was written successfully
+    Done,
+
+    /// The write failed
+    Failed,
+}
+
+/// A progress report sent by a client.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ProgressUpdate {
+    pub state: DeployState,
+
+    /// The number of bytes written so far
+    pub written: u64,
+
+    /// The total number of bytes to write
+    pub total: u64,
+
+    /// An error message if the write failed
+    pub message: Option<String>,
+}
+
+/// The deployment status of a single machine.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MachineStatus {
+    pub mac: String,
+    pub image_id: String,
+    pub state: DeployState,
+    pub written: u64,
+    pub total: u64,
+    pub message: Option<String>,
+
+    /// When the last update was received (seconds since the epoch)
+    pub updated: u64,
+}
+
+/// Convert a MAC address into the lowercase colon separated form.
+pub fn normalize_mac(mac: &str) -> String {
+    mac.trim().to_lowercase().replace('-', ":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assignment() {
+        let config = DeployConfig {
+            address: String::from("192.168.100.1"),
+            port: 8080,
+            default_image: Some(String::from("default")),
+            default_device: Some(String::from("/dev/vda")),
+            machines: vec![Assignment {
+                mac: String::from("52-54-00-12-34-56"),
+                image: String::from("special"),
+                device: String::from("/dev/nvme0n1"),
+            }],
+            ..Default::default()
+        };
+
+        let assignment = config.assignment("52:54:00:12:34:56").unwrap();
+        assert_eq!(assignment.image, "special");
+        assert_eq!(assignment.device, "/dev/nvme0n1");
+
+        let assignment = config.assignment("52:54:00:AB:CD:EF").unwrap();
+        assert_eq!(assignment.image, "default");
+        assert_eq!(assignment.device, "/dev/vda");
+    }
+
+    #[test]
+    fn test_default_needs_device() {
+        let mut config = DeployConfig {
+            address: String::from("192.168.100.1"),
+            port: 8080,
+            default_image: Some(String::from("default")),
+            ..Default::default()
+        };
+
+        assert!(config.validate().is_err());
+        assert!(config.assignment("52:54:00:ab:cd:ef").is_none());
+
+        config.default_device = Some(String::from("/dev/sda"));
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,624 @@
+//! Multicast distribution sends an image's clusters to any number of machines
+//! at once, so a large fleet can be deployed without saturating the uplink.
+//!
+//! The sender streams every cluster to a multicast group in fragments that fit
+//! in a single datagram. Since multicast is unreliable, each receiver also
+//! connects to the sender over a unicast TCP "repair" channel which serves
+//! arbitrary ranges of the image file. Receivers read the image headers over
+//! the repair channel, verify every cluster they reassemble against the digest
+//! table, and fetch whatever is missing or corrupt over the repair channel at
+//! the end.
+
+use anyhow::{anyhow, bail, Result};
+use binrw::{BinRead, BinReaderExt, BinWrite};
+use goldboot_image::{
+    reader::decode_cluster, Cluster, DigestTableEntry, HeaderEncryptionType, ImageHandle,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    path::Path,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+/// The maximum amount of cluster data in one datagram (leaves room for headers
+/// within a typical 1500 byte MTU).
+const FRAGMENT_SIZE: usize = 1400;
+
+/// The amount of data to request at once over the repair channel. Larger
+/// requests are truncated by the sender so clients can't force it to allocate
+/// more than this.
+const READ_AHEAD: u64 = 1024 * 1024;
+
+/// How many times the end of a pass is announced.
+const END_REPEAT: usize = 3;
+
+#[derive(BinRead, BinWrite, Debug, PartialEq, Eq)]
+#[brw(big, magic = b"GBMC")]
+enum Packet {
+    /// A piece of a cluster
+    #[brw(magic = 0u8)]
+    Fragment {
+        session: u32,
+        index: u32,
+        offset: u32,
+        cluster_size: u32,
+        size: u16,
+        #[br(count = size as usize)]
+        data: Vec<u8>,
+    },
+
+    /// Every cluster has been sent once
+    #[brw(magic = 1u8)]
+    End { session: u32 },
+}
+
+/// Sends an image to a multicast group and answers repair requests.
+pub struct MulticastSender {
+    image: ImageHandle,
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    session: u32,
+
+    /// The port of the repair channel
+    pub repair_port: u16,
+
+    /// The maximum send rate in bytes per second (zero is unlimited)
+    pub rate: u64,
+}
+
+impl MulticastSender {
+    /// Prepare to send the given image (which must be loaded) and start the
+    /// repair channel.
+    pub fn new(image: ImageHandle, group: SocketAddrV4, repair_port: u16) -> Result<Self> {
+        if image.digest_table.is_none() {
+            bail!("Image not loaded");
+        }
+
+        let session = rand::random();
+        let listener = TcpListener::bind(("0.0.0.0", repair_port))?;
+        let repair_port = listener.local_addr()?.port();
+
+        let path = image.path.clone();
+        let id = image.id.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let path = path.clone();
+                let id = id.clone();
+                std::thread::spawn(move || {
+                    if let Err(err) = serve_repairs(stream, &path, session, &id) {
+                        debug!(error = %err, "Repair connection closed");
+                    }
+                });
+            }
+        });
+
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_multicast_ttl_v4(1)?;
+        socket.set_multicast_loop_v4(true)?;
+
+        info!(group = %group, repair_port, "Starting multicast sender");
+        Ok(Self {
+            image,
+            socket,
+            group,
+            session,
+            repair_port,
+            rate: 0,
+        })
+    }
+
+    /// Stream every cluster to the group the given number of times. Sending
+    /// more than once gives late receivers a chance to catch up without using
+    /// the repair channel.
+    pub fn send(&self, passes: usize) -> Result<()> {
+        let entries = &self
+            .image
+            .digest_table
+            .as_ref()
+            .ok_or_else(|| anyhow!("Image not loaded"))?
+            .digest_table;
+
+        let mut file = BufReader::new(File::open(&self.image.path)?);
+        let mut packet = Cursor::new(Vec::with_capacity(FRAGMENT_SIZE + 32));
+        let start = Instant::now();
+        let mut sent: u64 = 0;
+
+        for pass in 0..passes {
+            debug!(pass, "Starting multicast pass");
+
+            for (index, entry) in entries.iter().enumerate() {
+                file.seek(SeekFrom::Start(entry.cluster_offset))?;
+                let cluster: Cluster = file.read_be()?;
+
+                for (i, data) in cluster.data.chunks(FRAGMENT_SIZE).enumerate() {
+                    packet.get_mut().clear();
+                    packet.set_position(0);
+                    Packet::Fragment {
+                        session: self.session,
+                        index: index as u32,
+                        offset: (i * FRAGMENT_SIZE) as u32,
+                        cluster_size: cluster.size,
+                        size: data.len() as u16,
+                        data: data.to_vec(),
+                    }
+                    .write(&mut packet)?;
+
+                    self.socket.send_to(packet.get_ref(), self.group)?;
+                    sent += packet.get_ref().len() as u64;
+
+                    // Wait until the average rate drops below the limit
+                    if self.rate > 0 {
+                        let expected = Duration::from_secs_f64(sent as f64 / self.rate as f64);
+                        if let Some(wait) = expected.checked_sub(start.elapsed()) {
+                            std::thread::sleep(wait);
+                        }
+                    }
+                }
+            }
+
+            packet.get_mut().clear();
+            packet.set_position(0);
+            Packet::End {
+                session: self.session,
+            }
+            .write(&mut packet)?;
+            for _ in 0..END_REPEAT {
+                self.socket.send_to(packet.get_ref(), self.group)?;
+            }
+        }
+
+        info!(passes, bytes = sent, "Multicast complete");
+        Ok(())
+    }
+}
+
+/// Answer range requests on a repair connection. The sender first announces the
+/// image length, session, and ID.
+fn serve_repairs(mut stream: TcpStream, path: &Path, session: u32, id: &str) -> Result<()> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(&session.to_be_bytes())?;
+    stream.write_all(&(id.len() as u16).to_be_bytes())?;
+    stream.write_all(id.as_bytes())?;
+
+    let mut request = [0u8; 12];
+    let mut buffer = Vec::new();
+    loop {
+        stream.read_exact(&mut request)?;
+        let offset = u64::from_be_bytes(request[..8].try_into()?);
+        let size = u32::from_be_bytes(request[8..].try_into()?) as u64;
+
+        // Requests past the end or larger than the read ahead are truncated
+        let size = size.min(READ_AHEAD).min(length.saturating_sub(offset)) as usize;
+        buffer.resize(size, 0);
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+
+        stream.write_all(&(size as u32).to_be_bytes())?;
+        stream.write_all(&buffer)?;
+    }
+}
+
+/// A seekable reader over the sender's image file via the repair channel.
+struct RepairReader {
+    stream: TcpStream,
+    length: u64,
+    position: u64,
+    buffer: Vec<u8>,
+    buffer_offset: u64,
+}
+
+impl RepairReader {
+    /// Fetch the range starting at the current position into the buffer.
+    fn fill(&mut self) -> std::io::Result<()> {
+        self.stream.write_all(&self.position.to_be_bytes())?;
+        self.stream.write_all(&(READ_AHEAD as u32).to_be_bytes())?;
+
+        let mut size = [0u8; 4];
+        self.stream.read_exact(&mut size)?;
+        self.buffer.resize(u32::from_be_bytes(size) as usize, 0);
+        self.stream.read_exact(&mut self.buffer)?;
+        self.buffer_offset = self.position;
+        Ok(())
+    }
+}
+
+impl Read for RepairReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let buffer_end = self.buffer_offset + self.buffer.len() as u64;
+        if self.position < self.buffer_offset || self.position >= buffer_end {
+            self.fill()?;
+        }
+
+        let start = (self.position - self.buffer_offset) as usize;
+        let size = std::cmp::min(buf.len(), self.buffer.len() - start);
+        buf[..size].copy_from_slice(&self.buffer[start..start + size]);
+
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for RepairReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+/// A cluster that is being reassembled from fragments.
+struct PartialCluster {
+    data: Vec<u8>,
+    offsets: HashSet<u32>,
+    received: usize,
+}
+
+impl PartialCluster {
+    fn new(size: u32) -> Self {
+        Self {
+            data: vec![0u8; size as usize],
+            offsets: HashSet::new(),
+            received: 0,
+        }
+    }
+
+    /// Add a fragment of a cluster with the given size. Fragments that disagree
+    /// with the size of the first one or that were already received are
+    /// dropped.
+    fn add(&mut self, cluster_size: u32, offset: u32, data: &[u8]) -> bool {
+        if cluster_size as usize != self.data.len()
+            || offset as usize + data.len() > self.data.len()
+            || !self.offsets.insert(offset)
+        {
+            return false;
+        }
+
+        self.data[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        self.received += data.len();
+        true
+    }
+
+    fn complete(&self) -> bool {
+        self.received == self.data.len()
+    }
+}
+
+/// Receives an image from a multicast sender.
+pub struct MulticastReceiver {
+    reader: RepairReader,
+    group: SocketAddrV4,
+    session: u32,
+
+    /// The image being received (loaded)
+    pub image: ImageHandle,
+
+    /// How long to wait for multicast data before switching to the repair
+    /// channel
+    pub idle_timeout: Duration,
+}
+
+impl MulticastReceiver {
+    /// Connect to the sender's repair channel and load the image headers.
+    pub fn connect(
+        server: impl ToSocketAddrs,
+        group: SocketAddrV4,
+        password: Option<String>,
+    ) -> Result<Self> {
+        let mut stream = TcpStream::connect(server)?;
+        let server = stream.peer_addr()?;
+
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header)?;
+        let length = u64::from_be_bytes(header[..8].try_into()?);
+        let session = u32::from_be_bytes(header[8..12].try_into()?);
+        let mut id = vec![0u8; u16::from_be_bytes(header[12..].try_into()?) as usize];
+        stream.read_exact(&mut id)?;
+
+        let mut reader = RepairReader {
+            stream,
+            length,
+            position: 0,
+            buffer: Vec::new(),
+            buffer_offset: 0,
+        };
+
+        let mut image = ImageHandle::open_from(
+            &mut reader,
+            server.to_string(),
+            String::from_utf8(id)?,
+            length,
+        )?;
+        if image.primary_header.encryption_type != HeaderEncryptionType::None && password.is_none()
+        {
+            bail!("The image is encrypted and no password was given");
+        }
+        image.load_from(&mut reader, password)?;
+
+        Ok(Self {
+            reader,
+            group,
+            session,
+            image,
+            idle_timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// Receive the image and write it to the given destination. Every block is
+    /// verified before the write is considered complete.
+    pub fn receive<F: Fn(u64, u64)>(&mut self, dest: impl AsRef<Path>, progress: F) -> Result<()> {
+        let protected_header = self
+            .image
+            .protected_header
+            .clone()
+            .ok_or_else(|| anyhow!("Image not loaded"))?;
+        let entries = self
+            .image
+            .digest_table
+            .clone()
+            .ok_or_else(|| anyhow!("Image not loaded"))?
+            .digest_table;
+
+        let block_size = protected_header.block_size as u64;
+        let total = entries.len() as u64 * block_size;
+        let limits = cluster_limits(&entries, self.reader.length);
+
+        let mut dest = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .read(true)
+            .open(dest)?;
+        if dest.metadata()?.len() < self.image.primary_header.size {
+            dest.set_len(self.image.primary_header.size)?;
+        }
+
+        // Blocks that already match don't need to be received at all
+        let mut block = vec![0u8; block_size as usize];
+        let mut done = vec![false; entries.len()];
+        for (index, entry) in entries.iter().enumerate() {
+            dest.seek(SeekFrom::Start(entry.block_offset))?;
+            dest.read_exact(&mut block)?;
+            if Sha256::digest(&block).as_slice() == entry.digest {
+                done[index] = true;
+                progress(block_size, total);
+            }
+        }
+
+        let store = |dest: &mut File, index: usize, entry: &DigestTableEntry, data: Vec<u8>| {
+            let block = decode_cluster(&protected_header, index, data)?;
+            if Sha256::digest(&block).as_slice() != entry.digest {
+                bail!("Cluster {} failed verification", index);
+            }
+
+            dest.seek(SeekFrom::Start(entry.block_offset))?;
+            dest.write_all(&block)?;
+            Ok(())
+        };
+
+        let mut remaining = done.iter().filter(|done| !**done).count();
+        if remaining > 0 {
+            let socket = UdpSocket::bind(("0.0.0.0", self.group.port()))?;
+            socket.join_multicast_v4(self.group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_read_timeout(Some(self.idle_timeout))?;
+
+            let mut partials: HashMap<u32, PartialCluster> = HashMap::new();
+            let mut buffer = vec![0u8; 65536];
+
+            // Whether the start of a pass was seen, meaning the next end of
+            // pass leaves nothing more to gain from listening
+            let mut saw_start = false;
+
+            info!(group = %self.group, remaining, "Receiving clusters");
+            while remaining > 0 {
+                let size = match socket.recv(&mut buffer) {
+                    Ok(size) => size,
+                    Err(_) => {
+                        debug!("Timed out waiting for multicast data");
+                        break;
+                    }
+                };
+
+                match Cursor::new(&buffer[..size]).read_be::<Packet>() {
+                    Ok(Packet::Fragment {
+                        session,
+                        index,
+                        offset,
+                        cluster_size,
+                        data,
+                        ..
+                    }) if session == self.session => {
+                        if index == 0 && offset == 0 {
+                            saw_start = true;
+                        }
+
+                        let Some(entry) = entries.get(index as usize) else {
+                            continue;
+                        };
+                        // Anyone can send to the group, so don't trust the size
+                        if done[index as usize] || cluster_size as u64 > limits[index as usize] {
+                            continue;
+                        }
+
+                        let partial = partials
+                            .entry(index)
+                            .or_insert_with(|| PartialCluster::new(cluster_size));
+                        if !partial.add(cluster_size, offset, &data) {
+                            continue;
+                        }
+
+                        if partial.complete() {
+                            let partial = partials.remove(&index).expect("partial exists");
+                            match store(&mut dest, index as usize, entry, partial.data) {
+                                Ok(_) => {
+                                    done[index as usize] = true;
+                                    remaining -= 1;
+                                    progress(block_size, total);
+                                }
+                                Err(err) => debug!(error = %err, "Discarding cluster"),
+                            }
+                        }
+                    }
+                    Ok(Packet::End { session }) if session == self.session && saw_start => break,
+                    _ => {}
+                }
+            }
+        }
+
+        // Fetch anything that was missed over the repair channel
+        if remaining > 0 {
+            info!(remaining, "Repairing missing clusters");
+        }
+        for (index, entry) in entries.iter().enumerate() {
+            if done[index] {
+                continue;
+            }
+
+            self.reader.seek(SeekFrom::Start(entry.cluster_offset))?;
+            let cluster: Cluster = self.reader.read_be()?;
+            store(&mut dest, index, entry, cluster.data)?;
+            progress(block_size, total);
+        }
+
+        dest.flush()?;
+        Ok(())
+    }
+}
+
+/// Get the largest possible size of each cluster in an image of the given
+/// length. Clusters can't overlap, so each one ends before the next begins.
+fn cluster_limits(entries: &[DigestTableEntry], length: u64) -> Vec<u64> {
+    let mut offsets: Vec<u64> = entries.iter().map(|entry| entry.cluster_offset).collect();
+    offsets.sort_unstable();
+
+    entries
+        .iter()
+        .map(|entry| {
+            let end = match offsets.binary_search(&entry.cluster_offset) {
+                Ok(position) => offsets.get(position + 1).copied().unwrap_or(length),
+                Err(_) => length,
+            };
+
+            // Each cluster starts with its size
+            end.saturating_sub(entry.cluster_offset).saturating_sub(4)
+        })
+        .collect()
+}
+
+/// Parse a multicast group address, warning if it's not a multicast address.
+pub fn parse_group(group: &str) -> Result<SocketAddrV4> {
+    let group: SocketAddrV4 = group.parse()?;
+    if !group.ip().is_multicast() {
+        warn!(group = %group, "Not a multicast address");
+    }
+    Ok(group)
+}
+
+/// The address of a sender's repair channel.
+pub fn parse_server(server: &str) -> Result<SocketAddr> {
+    server
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Failed to resolve: {}", server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goldboot_image::qcow::Qcow3;
+    use sha1::Sha1;
+
+    #[test]
+    fn test_multicast_loopback() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+
+        let mut image = ImageHandle::convert(
+            &Qcow3::open("../goldboot-image/test/small.qcow2")?,
+            String::from("Test"),
+            vec![],
+            None,
+            true,
+            tmp.path().join("small.gb"),
+            |_, _| {},
+        )?;
+        image.load(None)?;
+
+        let group = parse_group("239.255.42.99:42424")?;
+        let sender = MulticastSender::new(image, group, 0)?;
+
+        let mut receiver =
+            MulticastReceiver::connect(("127.0.0.1", sender.repair_port), group, None)?;
+        receiver.idle_timeout = Duration::from_millis(500);
+
+        // Receivers still finish if multicast isn't routable since every
+        // cluster can be repaired
+        std::thread::spawn(move || sender.send(2));
+        receiver.receive(tmp.path().join("small.raw"), |_, _| {})?;
+
+        assert_eq!(
+            hex::encode(
+                Sha1::new()
+                    .chain_update(std::fs::read(tmp.path().join("small.raw"))?)
+                    .finalize()
+            ),
+            "34e1c79c80941e5519ec76433790191318a5c77b"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_cluster() {
+        let mut partial = PartialCluster::new(20);
+
+        assert!(partial.add(20, 0, &[1; 10]));
+        assert!(!partial.add(20, 0, &[1; 10]));
+
+        // A fragment that claims a larger cluster is dropped
+        assert!(!partial.add(100, 50, &[2; 10]));
+        assert!(!partial.add(20, 15, &[2; 10]));
+        assert!(!partial.complete());
+
+        assert!(partial.add(20, 10, &[3; 10]));
+        assert!(partial.complete());
+        assert_eq!(partial.data[..10], [1; 10]);
+        assert_eq!(partial.data[10..], [3; 10]);
+    }
+
+    #[test]
+    fn test_cluster_limits() {
+        let entry = |cluster_offset| DigestTableEntry {
+            cluster_offset,
+            block_offset: 0,
+            digest: [0; 32],
+        };
+
+        assert_eq!(
+            cluster_limits(&[entry(100), entry(300), entry(200)], 1000),
+            vec![96, 696, 96]
+        );
+    }
+}
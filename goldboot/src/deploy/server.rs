@@ -0,0 +1,214 @@
+use super::{
+    normalize_mac, AssignmentResponse, DeployConfig, DeployState, MachineStatus, ProgressUpdate,
+};
+use crate::library::ImageLibrary;
+use anyhow::Result;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use goldboot_image::ImageHandle;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::runtime::Runtime;
+use tower_http::{services::ServeFile, trace::TraceLayer};
+use tracing::{info, warn};
+
+/// Shared state of the deployment server.
+struct DeployServer {
+    config: DeployConfig,
+
+    /// Image IDs by the reference used in the config
+    images: HashMap<String, String>,
+
+    /// The status of every machine that has contacted the server
+    machines: Mutex<HashMap<String, MachineStatus>>,
+}
+
+/// Find the image that a config reference (an ID or path) points to.
+fn resolve_image(reference: &str) -> Result<(String, PathBuf)> {
+    let image = if std::path::Path::new(reference).exists() {
+        ImageHandle::open(reference)?
+    } else {
+        ImageLibrary::find_by_id(reference)?
+    };
+    Ok((image.id, image.path))
+}
+
+/// Generate an iPXE script that boots the deployment client.
+pub fn ipxe_script(server: &str) -> String {
+    format!(
+        "#!ipxe\n\
+        kernel {server}/client/vmlinuz initrd=initrd.img goldboot.server={server} goldboot.mac=${{net0/mac}}\n\
+        initrd --name initrd.img {server}/client/initrd.img\n\
+        boot\n"
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+async fn boot_script(State(server): State<Arc<DeployServer>>) -> String {
+    ipxe_script(&server.config.url())
+}
+
+async fn assignment(
+    State(server): State<Arc<DeployServer>>,
+    Path(mac): Path<String>,
+) -> Result<Json<AssignmentResponse>, StatusCode> {
+    let mac = normalize_mac(&mac);
+
+    let Some(assignment) = server.config.assignment(&mac) else {
+        warn!(mac = %mac, "No image assigned to machine");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let image_id = server
+        .images
+        .get(&assignment.image)
+        .cloned()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    info!(mac = %mac, image_id = %image_id, "Machine requested its assignment");
+    server.machines.lock().unwrap().insert(
+        mac.clone(),
+        MachineStatus {
+            mac,
+            image_id: image_id.clone(),
+            state: DeployState::Assigned,
+            written: 0,
+            total: 0,
+            message: None,
+            updated: now(),
+        },
+    );
+
+    Ok(Json(AssignmentResponse {
+        url: format!("/image/download/{image_id}.gb"),
+        image_id,
+        device: assignment.device,
+    }))
+}
+
+async fn progress(
+    State(server): State<Arc<DeployServer>>,
+    Path(mac): Path<String>,
+    Json(update): Json<ProgressUpdate>,
+) -> StatusCode {
+    let mac = normalize_mac(&mac);
+    let mut machines = server.machines.lock().unwrap();
+
+    let Some(status) = machines.get_mut(&mac) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if status.state != update.state {
+        match update.state {
+            DeployState::Failed => {
+                warn!(mac = %mac, message = ?update.message, "Deployment failed")
+            }
+            state => info!(mac = %mac, state = ?state, "Deployment state changed"),
+        }
+    }
+
+    status.state = update.state;
+    status.written = update.written;
+    status.total = update.total;
+    status.message = update.message;
+    status.updated = now();
+    StatusCode::OK
+}
+
+async fn status(State(server): State<Arc<DeployServer>>) -> Json<Vec<MachineStatus>> {
+    let mut machines: Vec<MachineStatus> =
+        server.machines.lock().unwrap().values().cloned().collect();
+    machines.sort_by(|a, b| a.mac.cmp(&b.mac));
+    Json(machines)
+}
+
+/// Run the deployment server until it's killed.
+pub fn run(config: DeployConfig) -> Result<()> {
+    config.validate()?;
+
+    let mut router = Router::new();
+    let mut images = HashMap::new();
+
+    // Serve each image that's referenced by the config
+    for reference in config
+        .machines
+        .iter()
+        .map(|assignment| &assignment.image)
+        .chain(config.default_image.iter())
+    {
+        if images.contains_key(reference) {
+            continue;
+        }
+
+        let (id, path) = resolve_image(reference)?;
+        info!(image_id = %id, path = ?path, "Serving image");
+
+        // ServeFile supports range requests which the client needs
+        router = router.route_service(&format!("/image/download/{id}.gb"), ServeFile::new(path));
+        images.insert(reference.clone(), id);
+    }
+
+    if let Some(kernel) = &config.client_kernel {
+        router = router.route_service("/client/vmlinuz", ServeFile::new(kernel));
+    }
+    if let Some(initrd) = &config.client_initrd {
+        router = router.route_service("/client/initrd.img", ServeFile::new(initrd));
+    }
+
+    if let Some(root) = config.tftp_root.clone() {
+        std::thread::spawn(move || {
+            if let Err(err) = super::tftp::serve(root, 69) {
+                warn!(error = %err, "TFTP server failed");
+            }
+        });
+    }
+
+    let address = format!("0.0.0.0:{}", config.port);
+    let server = Arc::new(DeployServer {
+        config,
+        images,
+        machines: Mutex::new(HashMap::new()),
+    });
+
+    let router = router
+        .route("/boot.ipxe", get(boot_script))
+        .route("/assignment/:mac", get(assignment))
+        .route("/progress/:mac", post(progress))
+        .route("/status", get(status))
+        .with_state(server)
+        .layer(TraceLayer::new_for_http());
+
+    info!(address = %address, "Starting deployment server");
+    Runtime::new()?.block_on(async move {
+        let listener = tokio::net::TcpListener::bind(address).await?;
+        axum::serve(listener, router).await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipxe_script() {
+        let script = ipxe_script("http://192.168.100.1:8080");
+
+        assert!(script.starts_with("#!ipxe\n"));
+        assert!(script.contains("goldboot.mac=${net0/mac}"));
+        assert!(script.contains("initrd --name initrd.img http://192.168.100.1:8080/client/"));
+    }
+}
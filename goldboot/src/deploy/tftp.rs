@@ -0,0 +1,225 @@
+//! A minimal read-only TFTP server (RFC 1350) for handing iPXE to PXE firmware.
+//! Only octet transfers and the blksize/tsize options (RFC 2348, RFC 2349) are
+//! supported.
+
+use anyhow::{bail, Result};
+use std::{
+    fs::File,
+    io::Read,
+    net::{SocketAddr, UdpSocket},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+const OPCODE_RRQ: u16 = 1;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+const OPCODE_OACK: u16 = 6;
+
+/// The block size when the client doesn't negotiate one.
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// How many times a packet is resent before giving up.
+const RETRIES: usize = 5;
+
+/// A parsed read request.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReadRequest {
+    pub filename: String,
+    pub block_size: Option<usize>,
+    pub tsize: bool,
+}
+
+/// Parse a read request packet.
+pub fn parse_request(packet: &[u8]) -> Result<ReadRequest> {
+    if packet.len() < 2 || u16::from_be_bytes([packet[0], packet[1]]) != OPCODE_RRQ {
+        bail!("Not a read request");
+    }
+
+    let mut fields = packet[2..]
+        .split(|&b| b == 0)
+        .map(|field| String::from_utf8_lossy(field).to_string());
+
+    let filename = fields.next().unwrap_or_default();
+    let mode = fields.next().unwrap_or_default().to_lowercase();
+    if filename.is_empty() {
+        bail!("Missing filename");
+    }
+    if mode != "octet" {
+        bail!("Unsupported transfer mode: {}", mode);
+    }
+
+    let mut request = ReadRequest {
+        filename,
+        block_size: None,
+        tsize: false,
+    };
+
+    while let (Some(option), Some(value)) = (fields.next(), fields.next()) {
+        match option.to_lowercase().as_str() {
+            "blksize" => request.block_size = value.parse::<usize>().ok().map(|s| s.clamp(8, 1468)),
+            "tsize" => request.tsize = true,
+            _ => {}
+        }
+    }
+
+    Ok(request)
+}
+
+/// Resolve a requested filename inside the root without allowing escapes.
+fn resolve(root: &Path, filename: &str) -> Option<PathBuf> {
+    let relative = Path::new(filename.trim_start_matches('/'));
+    if relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        Some(root.join(relative))
+    } else {
+        None
+    }
+}
+
+fn error_packet(message: &str) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend(OPCODE_ERROR.to_be_bytes());
+    // File not found
+    packet.extend(1u16.to_be_bytes());
+    packet.extend(message.as_bytes());
+    packet.push(0);
+    packet
+}
+
+/// Send a packet and wait for the client to acknowledge the given block.
+fn send_acked(socket: &UdpSocket, packet: &[u8], block: u16) -> Result<()> {
+    let mut buffer = [0u8; 516];
+
+    for _ in 0..RETRIES {
+        socket.send(packet)?;
+
+        loop {
+            match socket.recv(&mut buffer) {
+                Ok(4..) if u16::from_be_bytes([buffer[0], buffer[1]]) == OPCODE_ACK => {
+                    if u16::from_be_bytes([buffer[2], buffer[3]]) == block {
+                        return Ok(());
+                    }
+                    // Ignore duplicate ACKs for earlier blocks
+                }
+                Ok(_) if u16::from_be_bytes([buffer[0], buffer[1]]) == OPCODE_ERROR => {
+                    bail!("Transfer aborted by client");
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    }
+
+    bail!("Timed out waiting for block {} to be acknowledged", block)
+}
+
+/// Send a file to a client from a new socket.
+fn transfer(root: &Path, client: SocketAddr, request: ReadRequest) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(client)?;
+    socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+    let Some(mut file) = resolve(root, &request.filename).and_then(|path| File::open(path).ok())
+    else {
+        warn!(filename = %request.filename, "Requested file not found");
+        socket.send(&error_packet("File not found"))?;
+        return Ok(());
+    };
+
+    info!(client = %client, filename = %request.filename, "Sending file over TFTP");
+
+    // Acknowledge any options the client asked for
+    if request.block_size.is_some() || request.tsize {
+        let mut oack = Vec::new();
+        oack.extend(OPCODE_OACK.to_be_bytes());
+        if let Some(block_size) = request.block_size {
+            oack.extend(format!("blksize\0{block_size}\0").as_bytes());
+        }
+        if request.tsize {
+            oack.extend(format!("tsize\0{}\0", file.metadata()?.len()).as_bytes());
+        }
+        send_acked(&socket, &oack, 0)?;
+    }
+
+    let block_size = request.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    let mut data = vec![0u8; block_size];
+    let mut block: u16 = 1;
+
+    loop {
+        // Fill the block completely unless the end of the file was reached
+        let mut size = 0;
+        while size < block_size {
+            match file.read(&mut data[size..])? {
+                0 => break,
+                read => size += read,
+            }
+        }
+
+        let mut packet = Vec::with_capacity(size + 4);
+        packet.extend(OPCODE_DATA.to_be_bytes());
+        packet.extend(block.to_be_bytes());
+        packet.extend(&data[..size]);
+        send_acked(&socket, &packet, block)?;
+
+        // A short block ends the transfer
+        if size < block_size {
+            return Ok(());
+        }
+        block = block.wrapping_add(1);
+    }
+}
+
+/// Serve files from the given directory until the process exits.
+pub fn serve(root: impl AsRef<Path>, port: u16) -> Result<()> {
+    let root = root.as_ref().to_path_buf();
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    info!(port = port, root = ?root, "Starting TFTP server");
+
+    let mut buffer = [0u8; 1024];
+    loop {
+        let (size, client) = socket.recv_from(&mut buffer)?;
+
+        match parse_request(&buffer[..size]) {
+            Ok(request) => {
+                let root = root.clone();
+                std::thread::spawn(move || {
+                    if let Err(err) = transfer(&root, client, request) {
+                        warn!(client = %client, error = %err, "TFTP transfer failed");
+                    }
+                });
+            }
+            Err(err) => debug!(client = %client, error = %err, "Ignoring TFTP packet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request(b"\x00\x01ipxe.efi\x00octet\x00blksize\x001468\x00tsize\x000\x00")
+                .unwrap(),
+            ReadRequest {
+                filename: String::from("ipxe.efi"),
+                block_size: Some(1468),
+                tsize: true,
+            }
+        );
+        assert!(parse_request(b"\x00\x01ipxe.efi\x00netascii\x00").is_err());
+        assert!(parse_request(b"\x00\x02ipxe.efi\x00octet\x00").is_err());
+
+        assert_eq!(resolve(Path::new("/srv"), "/../etc/passwd"), None);
+        assert_eq!(
+            resolve(Path::new("/srv"), "/ipxe.efi"),
+            Some(PathBuf::from("/srv/ipxe.efi"))
+        );
+    }
+}
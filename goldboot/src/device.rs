@@ -0,0 +1,525 @@
+//! Information about the block devices attached to the current system. This is
+//! mostly gathered from sysfs and procfs, so lookups fail on systems other than
+//! Linux.
+
+use anyhow::bail;
+use anyhow::Result;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tracing::warn;
+use ubyte::ToByteUnit;
+
+/// Check whether the given path is a block device node.
+#[cfg(unix)]
+pub fn is_block_device(path: impl AsRef<Path>) -> bool {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+/// Block devices can only be written on Unix.
+#[cfg(not(unix))]
+pub fn is_block_device(_path: impl AsRef<Path>) -> bool {
+    false
+}
+
+/// A block device such as a disk or one of its partitions.
+#[derive(Debug, Clone)]
+pub struct BlockDevice {
+    /// The kernel name of the device (e.g. "sda")
+    pub name: String,
+
+    /// The device node (e.g. "/dev/sda")
+    pub path: PathBuf,
+}
+
+impl BlockDevice {
+    /// Find the block device corresponding to the given device node.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = std::fs::canonicalize(path.as_ref())?;
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => bail!("Invalid device path: {}", path.display()),
+        };
+
+        if !Path::new("/sys/class/block").join(&name).exists() {
+            bail!("Not a block device: {}", path.display());
+        }
+
+        Ok(Self { name, path })
+    }
+
+    /// Check that the device is safe to overwrite with an image of the given
+    /// size.
+    pub fn check_target(&self, image_size: u64) -> Result<()> {
+        // Never overwrite filesystems or swap areas that are in use
+        let swaps = self.swaps()?;
+        if !swaps.is_empty() {
+            bail!("{} has active swap: {:?}", self.path.display(), swaps);
+        }
+
+        let mounts = self.mounts()?;
+        if mounts.iter().any(|mountpoint| mountpoint == Path::new("/")) {
+            bail!(
+                "{} contains the running root filesystem",
+                self.path.display()
+            );
+        }
+        if !mounts.is_empty() {
+            bail!(
+                "{} has mounted filesystems: {:?}",
+                self.path.display(),
+                mounts
+            );
+        }
+
+        let size = self.size()?;
+        if image_size > size {
+            warn!(
+                image_size = %image_size.bytes(),
+                device_size = %size.bytes(),
+                "The image is larger than the device and will not fit"
+            );
+        }
+        Ok(())
+    }
+
+    /// The device's directory in sysfs.
+    fn sysfs(&self) -> PathBuf {
+        Path::new("/sys/class/block").join(&self.name)
+    }
+
+    /// Read an attribute from the device's sysfs directory.
+    fn attribute(&self, name: &str) -> Option<String> {
+        std::fs::read_to_string(self.sysfs().join(name))
+            .ok()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    }
+
+    /// The device's model name if available.
+    pub fn model(&self) -> Option<String> {
+        self.attribute("device/model")
+            .or_else(|| self.attribute("device/name"))
+    }
+
+    /// The size of the device in bytes.
+    pub fn size(&self) -> Result<u64> {
+        // Always in units of 512 byte sectors regardless of the real sector size
+        match self.attribute("size") {
+            Some(size) => Ok(size.parse::<u64>()? * 512),
+            None => bail!("Failed to get size of device: {}", self.name),
+        }
+    }
+
+    /// Whether the kernel considers the device removable (USB sticks, cards).
+    pub fn removable(&self) -> bool {
+        self.attribute("removable").as_deref() == Some("1")
+    }
+
+    /// Detect the type of partition table on the device.
+    pub fn partition_table(&self) -> Result<PartitionTable> {
+        let mut sectors = [0u8; 1024];
+        File::open(&self.path)?.read_exact(&mut sectors)?;
+        Ok(PartitionTable::detect(&sectors))
+    }
+
+    /// Find the mountpoints of all filesystems on this device, including those
+    /// on its partitions and on virtual devices stacked on top of it (LVM,
+    /// dm-crypt, RAID).
+    ///
+    /// Filesystems are matched by device number since mount sources like
+    /// "/dev/root" don't have to exist.
+    pub fn mounts(&self) -> Result<Vec<PathBuf>> {
+        let numbers = self.in_use_numbers()?;
+
+        Ok(
+            parse_mountinfo(&std::fs::read_to_string("/proc/self/mountinfo")?)
+                .into_iter()
+                .filter(|(number, _)| numbers.contains(number))
+                .map(|(_, mountpoint)| mountpoint)
+                .collect(),
+        )
+    }
+
+    /// Find the swap areas that are active on this device or anything stacked
+    /// on top of it. Swap files are covered by [`Self::mounts`].
+    pub fn swaps(&self) -> Result<Vec<PathBuf>> {
+        let numbers = self.in_use_numbers()?;
+
+        Ok(parse_swaps(&std::fs::read_to_string("/proc/swaps")?)
+            .into_iter()
+            .filter(|path| device_number(path).is_some_and(|number| numbers.contains(&number)))
+            .collect())
+    }
+
+    /// Get the device numbers (like "8:1") of everything in
+    /// [`Self::in_use_names`].
+    fn in_use_numbers(&self) -> Result<HashSet<String>> {
+        Ok(self
+            .in_use_names()?
+            .into_iter()
+            .filter_map(|name| {
+                std::fs::read_to_string(Path::new("/sys/class/block").join(name).join("dev"))
+                    .ok()
+                    .map(|number| number.trim().to_string())
+            })
+            .collect())
+    }
+
+    /// Get the names of this device, its partitions, and everything that holds
+    /// any of them.
+    fn in_use_names(&self) -> Result<HashSet<String>> {
+        let mut names = HashSet::new();
+        let mut pending = vec![self.name.clone()];
+        pending.extend(self.partitions()?.into_iter().map(|p| p.name));
+
+        while let Some(name) = pending.pop() {
+            if let Ok(holders) = Path::new("/sys/class/block")
+                .join(&name)
+                .join("holders")
+                .read_dir()
+            {
+                for holder in holders.flatten() {
+                    pending.push(holder.file_name().to_string_lossy().to_string());
+                }
+            }
+            names.insert(name);
+        }
+
+        Ok(names)
+    }
+
+    /// The device's serial number if available.
+    pub fn serial(&self) -> Option<String> {
+        self.attribute("device/serial")
+            .or_else(|| self.attribute("serial"))
+            .or_else(|| self.attribute("device/wwid"))
+    }
+
+    /// The partition number if this device is a partition.
+    pub fn partition_number(&self) -> Option<u32> {
+        self.attribute("partition")?.parse().ok()
+    }
+
+    /// Get the partitions on this device ordered by partition number.
+    pub fn partitions(&self) -> Result<Vec<BlockDevice>> {
+        let mut partitions = Vec::new();
+
+        for entry in self.sysfs().read_dir()? {
+            let entry = entry?;
+            if let Ok(number) = std::fs::read_to_string(entry.path().join("partition")) {
+                let name = entry.file_name().to_string_lossy().to_string();
+                partitions.push((
+                    number.trim().parse::<u32>()?,
+                    BlockDevice {
+                        path: Path::new("/dev").join(&name),
+                        name,
+                    },
+                ));
+            }
+        }
+
+        partitions.sort_by_key(|(number, _)| *number);
+        Ok(partitions.into_iter().map(|(_, device)| device).collect())
+    }
+}
+
+/// The type of partition table present on a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTable {
+    Gpt,
+    Mbr,
+    None,
+}
+
+impl PartitionTable {
+    /// Detect the partition table given the first two 512 byte sectors of a
+    /// device.
+    pub fn detect(sectors: &[u8]) -> Self {
+        if sectors.len() >= 520 && &sectors[512..520] == b"EFI PART" {
+            PartitionTable::Gpt
+        } else if sectors.len() >= 512 && sectors[510..512] == [0x55, 0xaa] {
+            PartitionTable::Mbr
+        } else {
+            PartitionTable::None
+        }
+    }
+}
+
+impl Display for PartitionTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                PartitionTable::Gpt => "GPT",
+                PartitionTable::Mbr => "MBR",
+                PartitionTable::None => "none",
+            }
+        )
+    }
+}
+
+/// Ask the kernel to reload the partition table after it was overwritten.
+pub(crate) fn reread_partitions(device: &Path) {
+    match Command::new("blockdev")
+        .arg("--rereadpt")
+        .arg(device)
+        .status()
+    {
+        Ok(status) if status.success() => {}
+        _ => warn!(device = ?device, "Failed to reload partition table"),
+    }
+}
+
+/// A loop device backed by a disk image file which is detached on drop.
+pub(crate) struct LoopDevice {
+    pub path: PathBuf,
+}
+
+impl LoopDevice {
+    pub fn attach(file: &Path) -> Result<Self> {
+        let output = Command::new("losetup")
+            .args(["--find", "--show", "--partscan"])
+            .arg(file)
+            .output()?;
+
+        if !output.status.success() {
+            bail!("Failed to attach loop device");
+        }
+
+        Ok(Self {
+            path: PathBuf::from(String::from_utf8(output.stdout)?.trim()),
+        })
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        match Command::new("losetup")
+            .arg("--detach")
+            .arg(&self.path)
+            .status()
+        {
+            Ok(status) if status.success() => {}
+            _ => warn!(device = ?self.path, "Failed to detach loop device"),
+        }
+    }
+}
+
+/// A temporary mount which is unmounted on drop.
+pub(crate) struct Mount {
+    /// Only taken on drop
+    directory: Option<tempfile::TempDir>,
+}
+
+impl Mount {
+    pub fn new(device: &Path) -> Result<Self> {
+        let directory = tempfile::tempdir()?;
+
+        if !Command::new("mount")
+            .arg(device)
+            .arg(directory.path())
+            .status()?
+            .success()
+        {
+            bail!("Failed to mount: {}", device.display());
+        }
+
+        Ok(Self {
+            directory: Some(directory),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.directory.as_ref().unwrap().path()
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let Some(directory) = self.directory.take() else {
+            return;
+        };
+
+        let unmount = |args: &[&str]| {
+            Command::new("umount")
+                .args(args)
+                .arg(directory.path())
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+
+        // Fall back to detaching the mount so it can be cleaned up later
+        if !unmount(&[]) && !unmount(&["--lazy"]) {
+            // Removing the directory now would delete the filesystem's content
+            let path = directory.into_path();
+            warn!(path = ?path, "Failed to unmount");
+        }
+    }
+}
+
+/// Parse the content of /proc/mounts into (source, mountpoint) pairs.
+pub(crate) fn parse_mounts(content: &str) -> Vec<(PathBuf, PathBuf)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(source), Some(mountpoint)) if source.starts_with('/') => Some((
+                    PathBuf::from(unescape_mount(source)),
+                    PathBuf::from(unescape_mount(mountpoint)),
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Parse the content of /proc/self/mountinfo into (device number, mountpoint)
+/// pairs.
+pub(crate) fn parse_mountinfo(content: &str) -> Vec<(String, PathBuf)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            // The mount ID and parent ID come before the device number, and the
+            // root of the mount comes before the mountpoint
+            let mut fields = line.split_whitespace().skip(2);
+            match (fields.next(), fields.nth(1)) {
+                (Some(number), Some(mountpoint)) => Some((
+                    number.to_string(),
+                    PathBuf::from(unescape_mount(mountpoint)),
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Parse the content of /proc/swaps into the paths of the swap areas.
+pub(crate) fn parse_swaps(content: &str) -> Vec<PathBuf> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|path| PathBuf::from(unescape_mount(path)))
+        .collect()
+}
+
+/// Get the device number (like "8:1") of a device node.
+#[cfg(unix)]
+fn device_number(path: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(path).ok()?;
+    if !metadata.file_type().is_block_device() {
+        return None;
+    }
+
+    let rdev = metadata.rdev();
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    Some(format!("{major}:{minor}"))
+}
+
+#[cfg(not(unix))]
+fn device_number(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Decode the octal escapes (like "\040" for space) used in /proc/mounts.
+fn unescape_mount(field: &str) -> String {
+    let mut result = String::new();
+    let mut chars = field.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            let code: String = chars.clone().take(3).collect();
+            if let Ok(value) = u8::from_str_radix(&code, 8) {
+                result.push(value as char);
+                chars.nth(2);
+                continue;
+            }
+        }
+        result.push(ch);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = parse_mounts(
+            "proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+/dev/nvme0n1p2 / ext4 rw,relatime 0 0
+/dev/sdb1 /run/media/my\\040stick vfat rw 0 0
+tmpfs /tmp tmpfs rw 0 0",
+        );
+
+        assert_eq!(
+            mounts,
+            vec![
+                (PathBuf::from("/dev/nvme0n1p2"), PathBuf::from("/")),
+                (
+                    PathBuf::from("/dev/sdb1"),
+                    PathBuf::from("/run/media/my stick")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(
+            "22 1 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+26 1 259:2 / / rw,relatime shared:1 - ext4 /dev/root rw
+41 26 8:17 / /run/media/my\\040stick rw - vfat /dev/sdb1 rw",
+        );
+
+        assert_eq!(
+            mounts,
+            vec![
+                (String::from("0:21"), PathBuf::from("/proc")),
+                (String::from("259:2"), PathBuf::from("/")),
+                (String::from("8:17"), PathBuf::from("/run/media/my stick")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_swaps() {
+        assert_eq!(
+            parse_swaps(
+                "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/nvme0n1p3                          partition\t8388604\t\t0\t\t-2
+/swap\\040file                          file\t\t1048572\t\t0\t\t-3"
+            ),
+            vec![PathBuf::from("/dev/nvme0n1p3"), PathBuf::from("/swap file")]
+        );
+    }
+
+    #[test]
+    fn test_detect_partition_table() {
+        let mut sectors = [0u8; 1024];
+        assert_eq!(PartitionTable::detect(&sectors), PartitionTable::None);
+
+        sectors[510] = 0x55;
+        sectors[511] = 0xaa;
+        assert_eq!(PartitionTable::detect(&sectors), PartitionTable::Mbr);
+
+        sectors[512..520].copy_from_slice(b"EFI PART");
+        assert_eq!(PartitionTable::detect(&sectors), PartitionTable::Gpt);
+    }
+}
@@ -0,0 +1,868 @@
+//! Alloy images combine several independently cast operating systems into one
+//! multiboot disk.
+//!
+//! Each element is cast onto its own GPT disk. The partitions of every element
+//! are then laid out on a new GPT disk after a single EFI system partition
+//! (ESP) which contains the union of the elements' ESPs. systemd-boot is
+//! installed as the fallback loader with a menu entry for each element.
+//!
+//! The disk GUID is inherited from the first element, so elements that identify
+//! their boot disk that way (like Windows) should come first. Elements refer to
+//! their ESP by its volume ID (in /etc/fstab for example), and the shared ESP
+//! keeps the first element's. Any other element whose ESP has a different
+//! volume ID, or has files that differ from those of the ESPs merged before it
+//! (like two GRUB configurations), keeps its own ESP as a basic data partition.
+//! Only its loaders are copied into the shared ESP, under `EFI/goldboot/<n>`.
+//!
+//! Elements that boot with systemd-boot bring their own menu entries instead,
+//! so their ESP has to be merged.
+
+use anyhow::{anyhow, bail, Result};
+use binrw::{BinRead, BinReaderExt, BinWrite};
+use fatfs::{Dir, FileSystem, FormatVolumeOptions, FsOptions, ReadWriteSeek};
+use fscommon::StreamSlice;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info};
+
+/// The logical sector size of element disks.
+const SECTOR_SIZE: u64 = 512;
+
+/// Partitions start on 1 MiB boundaries.
+const ALIGNMENT: u64 = 2048;
+
+/// The number of entries in a new partition table.
+const ENTRY_COUNT: u32 = 128;
+
+/// The size of a partition entry in a new partition table.
+const ENTRY_SIZE: u32 = 128;
+
+/// The number of sectors occupied by a new partition entry array.
+const ENTRY_SECTORS: u64 = (ENTRY_COUNT * ENTRY_SIZE) as u64 / SECTOR_SIZE;
+
+/// The EFI system partition type (C12A7328-F81F-11D2-BA4B-00A0C93EC93B).
+const ESP_TYPE: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+
+/// The basic data partition type (EBD0A0A2-B9E5-4433-87C0-68B6B72699C7) given to
+/// ESPs that are kept next to the shared one.
+const BASIC_DATA_TYPE: [u8; 16] = [
+    0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7,
+];
+
+/// Loaders that identify an operating system on an ESP in order of preference.
+/// They're searched for in every vendor directory.
+const LOADERS: [&str; 4] = [
+    "bootmgfw.efi",
+    "shimx64.efi",
+    "grubx64.efi",
+    "systemd-bootx64.efi",
+];
+
+/// Where to look for systemd-boot if none of the elements include it.
+const SYSTEMD_BOOT_PATHS: [&str; 2] = [
+    "/usr/lib/systemd/boot/efi/systemd-bootx64.efi",
+    "/usr/share/systemd/bootctl/systemd-bootx64.efi",
+];
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little, magic = b"EFI PART")]
+pub struct GptHeader {
+    pub revision: u32,
+    pub header_size: u32,
+    pub header_crc32: u32,
+    pub reserved: u32,
+    pub current_lba: u64,
+    pub backup_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: [u8; 16],
+    pub entries_lba: u64,
+    pub entry_count: u32,
+    pub entry_size: u32,
+    pub entries_crc32: u32,
+}
+
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub struct GptEntry {
+    pub type_guid: [u8; 16],
+    pub unique_guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+
+    /// The partition name in UTF-16
+    pub name: [u16; 36],
+}
+
+impl GptEntry {
+    fn empty() -> Self {
+        Self {
+            type_guid: [0; 16],
+            unique_guid: [0; 16],
+            first_lba: 0,
+            last_lba: 0,
+            attributes: 0,
+            name: [0; 36],
+        }
+    }
+
+    pub fn is_esp(&self) -> bool {
+        self.type_guid == ESP_TYPE
+    }
+
+    /// The number of sectors in the partition.
+    pub fn sectors(&self) -> u64 {
+        self.last_lba + 1 - self.first_lba
+    }
+
+    /// The byte range of the partition on its disk.
+    fn range(&self) -> (u64, u64) {
+        (
+            self.first_lba * SECTOR_SIZE,
+            (self.last_lba + 1) * SECTOR_SIZE,
+        )
+    }
+}
+
+/// A GUID partition table.
+pub struct Gpt {
+    pub disk_guid: [u8; 16],
+
+    /// Partitions that are in use
+    pub entries: Vec<GptEntry>,
+}
+
+impl Gpt {
+    /// Read the primary partition table from the given disk.
+    pub fn read(disk: &mut (impl Read + Seek)) -> Result<Self> {
+        disk.seek(SeekFrom::Start(SECTOR_SIZE))?;
+        let header: GptHeader = disk.read_le()?;
+
+        let mut entries = Vec::new();
+        for i in 0..header.entry_count as u64 {
+            disk.seek(SeekFrom::Start(
+                header.entries_lba * SECTOR_SIZE + i * header.entry_size as u64,
+            ))?;
+            let entry: GptEntry = disk.read_le()?;
+            if entry.type_guid != [0; 16] {
+                entries.push(entry);
+            }
+        }
+
+        Ok(Self {
+            disk_guid: header.disk_guid,
+            entries,
+        })
+    }
+
+    /// Write a protective MBR and the primary and backup partition tables to a
+    /// disk of the given size.
+    pub fn write(&self, disk: &mut (impl Write + Seek), sectors: u64) -> Result<()> {
+        if self.entries.len() > ENTRY_COUNT as usize {
+            bail!("Too many partitions: {}", self.entries.len());
+        }
+
+        let mut entries = Cursor::new(Vec::new());
+        for i in 0..ENTRY_COUNT as usize {
+            self.entries
+                .get(i)
+                .cloned()
+                .unwrap_or_else(GptEntry::empty)
+                .write_le(&mut entries)?;
+        }
+        let entries = entries.into_inner();
+
+        // The protective MBR covers the whole disk with a single partition
+        let mut mbr = [0u8; 512];
+        mbr[446..462].copy_from_slice(&[
+            0x00, 0x00, 0x02, 0x00, 0xee, 0xff, 0xff, 0xff, 0x01, 0x00, 0x00, 0x00, 0, 0, 0, 0,
+        ]);
+        mbr[458..462].copy_from_slice(&(sectors - 1).min(u32::MAX as u64).to_le_bytes()[..4]);
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        disk.seek(SeekFrom::Start(0))?;
+        disk.write_all(&mbr)?;
+
+        let mut header = GptHeader {
+            revision: 0x00010000,
+            header_size: 92,
+            header_crc32: 0,
+            reserved: 0,
+            current_lba: 1,
+            backup_lba: sectors - 1,
+            first_usable_lba: 2 + ENTRY_SECTORS,
+            last_usable_lba: sectors - 2 - ENTRY_SECTORS,
+            disk_guid: self.disk_guid,
+            entries_lba: 2,
+            entry_count: ENTRY_COUNT,
+            entry_size: ENTRY_SIZE,
+            entries_crc32: crc32fast::hash(&entries),
+        };
+
+        let primary = header.clone();
+        header.current_lba = sectors - 1;
+        header.backup_lba = 1;
+        header.entries_lba = sectors - 1 - ENTRY_SECTORS;
+
+        for mut header in [primary, header] {
+            let mut bytes = Cursor::new(Vec::new());
+            header.write_le(&mut bytes)?;
+            header.header_crc32 = crc32fast::hash(bytes.get_ref());
+
+            let mut bytes = Cursor::new(Vec::new());
+            header.write_le(&mut bytes)?;
+
+            disk.seek(SeekFrom::Start(header.current_lba * SECTOR_SIZE))?;
+            disk.write_all(bytes.get_ref())?;
+            disk.seek(SeekFrom::Start(header.entries_lba * SECTOR_SIZE))?;
+            disk.write_all(&entries)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A raw disk that was cast by a single element.
+pub struct AlloyElement {
+    /// The title of the element's boot menu entry
+    pub title: String,
+
+    /// The path to the raw disk
+    pub path: PathBuf,
+}
+
+fn align(sector: u64) -> u64 {
+    sector.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Copy a range of bytes between disks without writing zeros, so the
+/// destination stays sparse.
+fn copy_range(source: &mut File, dest: &mut File, from: u64, to: u64, size: u64) -> Result<()> {
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut copied = 0;
+
+    source.seek(SeekFrom::Start(from))?;
+    while copied < size {
+        let chunk = (size - copied).min(buffer.len() as u64) as usize;
+        source.read_exact(&mut buffer[..chunk])?;
+
+        if buffer[..chunk].iter().any(|&b| b != 0) {
+            dest.seek(SeekFrom::Start(to + copied))?;
+            dest.write_all(&buffer[..chunk])?;
+        }
+        copied += chunk as u64;
+    }
+
+    Ok(())
+}
+
+/// Find the loader that boots the operating system on an ESP.
+fn find_loader<T: ReadWriteSeek>(root: &Dir<T>) -> Option<String> {
+    let vendors: Vec<String> = root
+        .open_dir("EFI")
+        .ok()?
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.is_dir())
+        .map(|entry| entry.file_name())
+        .filter(|name| name != "." && name != ".." && !name.eq_ignore_ascii_case("BOOT"))
+        .collect();
+
+    for loader in LOADERS {
+        for vendor in &vendors {
+            // Windows keeps its loader one level deeper
+            for path in [
+                format!("EFI/{vendor}/{loader}"),
+                format!("EFI/{vendor}/Boot/{loader}"),
+            ] {
+                if root.open_file(&path).is_ok() {
+                    return Some(path);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Get the digest of every file on an ESP by its lowercase path. The fallback
+/// loader and the systemd-boot configuration are left out since the shared ESP
+/// gets its own.
+fn esp_files<T: ReadWriteSeek>(
+    dir: &Dir<T>,
+    path: &str,
+    files: &mut HashMap<String, [u8; 32]>,
+) -> Result<()> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let child = format!("{path}/{name}").to_lowercase();
+        if entry.is_dir() {
+            if child != "/efi/boot" {
+                esp_files(&entry.to_dir(), &child, files)?;
+            }
+        } else if child != "/loader/loader.conf" {
+            let mut data = Vec::new();
+            entry.to_file().read_to_end(&mut data)?;
+            files.insert(child, Sha256::digest(&data).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the contents of one FAT directory into another, skipping fallback
+/// loaders.
+fn copy_dir<S: ReadWriteSeek, D: ReadWriteSeek>(
+    source: &Dir<S>,
+    dest: &Dir<D>,
+    path: &str,
+) -> Result<()> {
+    for entry in source.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let child = format!("{path}/{name}");
+        if entry.is_dir() {
+            if child.eq_ignore_ascii_case("/EFI/BOOT") {
+                continue;
+            }
+            copy_dir(&entry.to_dir(), &dest.create_dir(&name)?, &child)?;
+        } else {
+            let mut data = Vec::new();
+            entry.to_file().read_to_end(&mut data)?;
+            write_file(dest, &name, &data)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a file to a FAT filesystem, replacing it if it exists.
+fn write_file<T: ReadWriteSeek>(root: &Dir<T>, path: &str, data: &[u8]) -> Result<()> {
+    let mut file = root.create_file(path)?;
+    file.truncate()?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// Merge the disks of several elements into one raw disk of the given size.
+pub fn merge(elements: &[AlloyElement], size: u64, dest: impl AsRef<Path>) -> Result<()> {
+    let sectors = size / SECTOR_SIZE;
+
+    let mut disks = Vec::new();
+    for element in elements {
+        let mut file = File::open(&element.path)?;
+        let gpt = Gpt::read(&mut file)
+            .map_err(|err| anyhow!("Failed to read partition table of {}: {err}", element.title))?;
+        let esp = gpt
+            .entries
+            .iter()
+            .find(|entry| entry.is_esp())
+            .cloned()
+            .ok_or_else(|| anyhow!("{} has no EFI system partition", element.title))?;
+        disks.push((file, gpt, esp));
+    }
+
+    let mut volume_ids = Vec::new();
+    let mut loaders = Vec::new();
+    let mut files = Vec::new();
+    for (file, _, esp) in disks.iter_mut() {
+        let (start, end) = esp.range();
+        let fs = FileSystem::new(StreamSlice::new(file, start, end)?, FsOptions::new())?;
+
+        let mut element_files = HashMap::new();
+        esp_files(&fs.root_dir(), "", &mut element_files)?;
+        volume_ids.push(fs.volume_id());
+        loaders.push(find_loader(&fs.root_dir()));
+        files.push(element_files);
+    }
+
+    // Only one volume ID can be kept and files can't differ, so elements that
+    // don't fit keep their own ESP
+    let mut merged: HashMap<String, [u8; 32]> = HashMap::new();
+    let mut keep_esp = Vec::new();
+    for ((element, volume_id), files) in elements.iter().zip(&volume_ids).zip(files) {
+        let conflict = files
+            .iter()
+            .find(|(path, digest)| merged.get(*path).is_some_and(|other| other != *digest));
+
+        if *volume_id != volume_ids[0] {
+            info!(
+                element = %element.title,
+                volume_id = format!("{volume_id:08X}"),
+                "Keeping ESP with a different volume ID"
+            );
+            keep_esp.push(true);
+        } else if let Some((path, _)) = conflict {
+            info!(element = %element.title, path = %path, "Keeping ESP with a conflicting file");
+            keep_esp.push(true);
+        } else {
+            merged.extend(files);
+            keep_esp.push(false);
+        }
+    }
+
+    // A second systemd-boot can only show the same menu again, so these
+    // elements must add their entries to the shared ESP
+    for ((element, loader), keep_esp) in elements.iter().zip(&loaders).zip(&keep_esp) {
+        if *keep_esp
+            && loader
+                .as_ref()
+                .is_some_and(|loader| loader.ends_with("systemd-bootx64.efi"))
+        {
+            bail!(
+                "{} boots with systemd-boot, so its ESP must share the first element's volume ID and not conflict with the others",
+                element.title
+            );
+        }
+    }
+
+    let mut dest = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(dest)?;
+    dest.set_len(size)?;
+
+    // The shared ESP has room for the contents of all others
+    let esp_sectors = align(disks.iter().map(|(_, _, esp)| esp.sectors()).sum());
+    let mut shared_esp = disks[0].2.clone();
+    shared_esp.first_lba = ALIGNMENT;
+    shared_esp.last_lba = ALIGNMENT + esp_sectors - 1;
+
+    let mut entries = vec![shared_esp.clone()];
+    let mut next = shared_esp.last_lba + 1;
+
+    for ((element, (file, gpt, _)), keep_esp) in
+        elements.iter().zip(disks.iter_mut()).zip(&keep_esp)
+    {
+        for entry in gpt
+            .entries
+            .iter()
+            .filter(|entry| *keep_esp || !entry.is_esp())
+        {
+            let mut moved = entry.clone();
+            if entry.is_esp() {
+                moved.type_guid = BASIC_DATA_TYPE;
+            }
+            moved.first_lba = align(next);
+            moved.last_lba = moved.first_lba + entry.sectors() - 1;
+
+            if moved.last_lba > sectors - 2 - ENTRY_SECTORS {
+                bail!("The image is too small to hold every element");
+            }
+
+            debug!(
+                element = %element.title,
+                from = entry.first_lba,
+                to = moved.first_lba,
+                sectors = entry.sectors(),
+                "Copying partition"
+            );
+            copy_range(
+                file,
+                &mut dest,
+                entry.first_lba * SECTOR_SIZE,
+                moved.first_lba * SECTOR_SIZE,
+                entry.sectors() * SECTOR_SIZE,
+            )?;
+
+            next = moved.last_lba + 1;
+            entries.push(moved);
+        }
+    }
+
+    Gpt {
+        disk_guid: disks[0].1.disk_guid,
+        entries,
+    }
+    .write(&mut dest, sectors)?;
+
+    // Build the shared ESP
+    let (start, end) = shared_esp.range();
+    let volume_id = volume_ids[0];
+    let volume_label = {
+        let (file, _, esp) = &mut disks[0];
+        let (esp_start, esp_end) = esp.range();
+        let fs = FileSystem::new(
+            StreamSlice::new(file, esp_start, esp_end)?,
+            FsOptions::new(),
+        )?;
+        let bytes = fs.volume_label_as_bytes();
+        let mut label = [b' '; 11];
+        label[..bytes.len().min(11)].copy_from_slice(&bytes[..bytes.len().min(11)]);
+        label
+    };
+
+    fatfs::format_volume(
+        StreamSlice::new(&mut dest, start, end)?,
+        FormatVolumeOptions::new()
+            .volume_id(volume_id)
+            .volume_label(volume_label),
+    )?;
+    let fs = FileSystem::new(StreamSlice::new(&mut dest, start, end)?, FsOptions::new())?;
+    let root = fs.root_dir();
+
+    let mut menu = Vec::new();
+    for (index, (element, (file, _, esp))) in elements.iter().zip(disks.iter_mut()).enumerate() {
+        let (esp_start, esp_end) = esp.range();
+        let source = FileSystem::new(
+            StreamSlice::new(file, esp_start, esp_end)?,
+            FsOptions::new(),
+        )?;
+
+        // Everything else stays on the element's own ESP
+        if keep_esp[index] {
+            copy_dir(
+                &source.root_dir().open_dir("EFI")?,
+                &root
+                    .create_dir("EFI")?
+                    .create_dir("goldboot")?
+                    .create_dir(&index.to_string())?,
+                "/EFI",
+            )?;
+        } else {
+            copy_dir(&source.root_dir(), &root, "")?;
+        }
+
+        let loader = match loaders[index].clone() {
+            Some(loader) if loader.ends_with("systemd-bootx64.efi") => {
+                info!(element = %element.title, "Using the element's own boot menu entries");
+                continue;
+            }
+            Some(loader) if keep_esp[index] => {
+                loader.replacen("EFI/", &format!("EFI/goldboot/{index}/"), 1)
+            }
+            Some(loader) => loader,
+            None => {
+                // Keep the element's fallback loader under a new name
+                let mut data = Vec::new();
+                source
+                    .root_dir()
+                    .open_file("EFI/BOOT/BOOTX64.EFI")
+                    .map_err(|_| anyhow!("Failed to find a loader for {}", element.title))?
+                    .read_to_end(&mut data)?;
+
+                let path = format!("EFI/goldboot/{index}.efi");
+                root.create_dir("EFI")?.create_dir("goldboot")?;
+                write_file(&root, &path, &data)?;
+                path
+            }
+        };
+
+        info!(element = %element.title, loader = %loader, "Adding boot menu entry");
+        menu.push((element.title.clone(), loader));
+    }
+
+    // Install systemd-boot as the fallback loader
+    let systemd_boot = match root.open_file("EFI/systemd/systemd-bootx64.efi") {
+        Ok(mut file) => {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            data
+        }
+        Err(_) => SYSTEMD_BOOT_PATHS
+            .iter()
+            .find_map(|path| std::fs::read(path).ok())
+            .ok_or_else(|| anyhow!("systemd-boot is required to build alloy images"))?,
+    };
+
+    root.create_dir("EFI")?.create_dir("BOOT")?;
+    write_file(&root, "EFI/BOOT/BOOTX64.EFI", &systemd_boot)?;
+
+    root.create_dir("loader")?.create_dir("entries")?;
+    let mut loader_conf = String::from("timeout 5\nauto-entries no\n");
+    if !menu.is_empty() {
+        loader_conf.insert_str(0, "default goldboot-0.conf\n");
+    }
+    write_file(&root, "loader/loader.conf", loader_conf.as_bytes())?;
+    for (index, (title, loader)) in menu.iter().enumerate() {
+        write_file(
+            &root,
+            &format!("loader/entries/goldboot-{index}.conf"),
+            format!("title {title}\nefi /{loader}\n").as_bytes(),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a disk with an ESP and a root partition.
+    fn create_element(
+        path: &Path,
+        vendor: &str,
+        loader: &str,
+        unique: u8,
+        volume_id: u32,
+    ) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let sectors = 128 * ALIGNMENT;
+        file.set_len(sectors * SECTOR_SIZE)?;
+
+        let esp = GptEntry {
+            type_guid: ESP_TYPE,
+            unique_guid: [unique; 16],
+            first_lba: ALIGNMENT,
+            last_lba: 66 * ALIGNMENT - 1,
+            ..GptEntry::empty()
+        };
+        let root = GptEntry {
+            type_guid: [0xaf; 16],
+            unique_guid: [unique + 1; 16],
+            first_lba: 66 * ALIGNMENT,
+            last_lba: 67 * ALIGNMENT - 1,
+            ..GptEntry::empty()
+        };
+        Gpt {
+            disk_guid: [unique; 16],
+            entries: vec![esp.clone(), root.clone()],
+        }
+        .write(&mut file, sectors)?;
+
+        file.seek(SeekFrom::Start(root.range().0))?;
+        file.write_all(&[unique; 512])?;
+
+        let (start, end) = esp.range();
+        fatfs::format_volume(
+            StreamSlice::new(&mut file, start, end)?,
+            FormatVolumeOptions::new().volume_id(volume_id),
+        )?;
+        let fs = FileSystem::new(StreamSlice::new(&mut file, start, end)?, FsOptions::new())?;
+        let dir = fs.root_dir().create_dir("EFI")?;
+        dir.create_dir(vendor)?;
+        dir.create_dir("BOOT")?;
+        write_file(
+            &fs.root_dir(),
+            &format!("EFI/{vendor}/{loader}"),
+            format!("{loader} {unique}").as_bytes(),
+        )?;
+        write_file(&fs.root_dir(), "EFI/BOOT/BOOTX64.EFI", b"fallback")?;
+
+        if loader == "systemd-bootx64.efi" {
+            fs.root_dir().create_dir("loader")?.create_dir("entries")?;
+            write_file(
+                &fs.root_dir(),
+                &format!("loader/entries/{unique}.conf"),
+                b"title Linux\nlinux /vmlinuz\n",
+            )?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        create_element(
+            &tmp.path().join("a.raw"),
+            "debian",
+            "shimx64.efi",
+            1,
+            0xabcd1234,
+        )?;
+        create_element(
+            &tmp.path().join("b.raw"),
+            "systemd",
+            "systemd-bootx64.efi",
+            3,
+            0xabcd1234,
+        )?;
+
+        let elements = [
+            AlloyElement {
+                title: String::from("A"),
+                path: tmp.path().join("a.raw"),
+            },
+            AlloyElement {
+                title: String::from("B"),
+                path: tmp.path().join("b.raw"),
+            },
+        ];
+        merge(
+            &elements,
+            512 * ALIGNMENT * SECTOR_SIZE,
+            tmp.path().join("alloy.raw"),
+        )?;
+
+        let mut file = File::open(tmp.path().join("alloy.raw"))?;
+        let gpt = Gpt::read(&mut file)?;
+        assert_eq!(gpt.disk_guid, [1; 16]);
+        assert_eq!(gpt.entries.len(), 3);
+        assert!(gpt.entries[0].is_esp());
+        assert_eq!(gpt.entries[1].unique_guid, [2; 16]);
+        assert_eq!(gpt.entries[2].unique_guid, [4; 16]);
+
+        // Partition contents move with the partition
+        let mut data = [0u8; 512];
+        file.seek(SeekFrom::Start(gpt.entries[2].range().0))?;
+        file.read_exact(&mut data)?;
+        assert_eq!(data, [3; 512]);
+
+        let (start, end) = gpt.entries[0].range();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmp.path().join("alloy.raw"))?;
+        let fs = FileSystem::new(StreamSlice::new(&mut file, start, end)?, FsOptions::new())?;
+
+        let mut entry = String::new();
+        fs.root_dir()
+            .open_file("loader/entries/goldboot-0.conf")?
+            .read_to_string(&mut entry)?;
+        assert_eq!(entry, "title A\nefi /EFI/debian/shimx64.efi\n");
+
+        // systemd-boot elements only bring their own entries
+        assert!(fs.root_dir().open_file("loader/entries/3.conf").is_ok());
+        assert!(fs
+            .root_dir()
+            .open_file("loader/entries/goldboot-1.conf")
+            .is_err());
+
+        // The second element's systemd-boot becomes the fallback loader
+        let mut fallback = Vec::new();
+        fs.root_dir()
+            .open_file("EFI/BOOT/BOOTX64.EFI")?
+            .read_to_end(&mut fallback)?;
+        assert_eq!(fallback, b"systemd-bootx64.efi 3");
+        assert_eq!(fs.volume_id(), 0xabcd1234);
+        drop(fs);
+
+        // A third element provides systemd-boot from here on
+        create_element(
+            &tmp.path().join("c.raw"),
+            "systemd",
+            "systemd-bootx64.efi",
+            5,
+            0xabcd1234,
+        )?;
+        let elements = [
+            AlloyElement {
+                title: String::from("A"),
+                path: tmp.path().join("a.raw"),
+            },
+            AlloyElement {
+                title: String::from("B"),
+                path: tmp.path().join("b.raw"),
+            },
+            AlloyElement {
+                title: String::from("C"),
+                path: tmp.path().join("c.raw"),
+            },
+        ];
+
+        // Elements with another ESP volume ID keep their own ESP
+        create_element(&tmp.path().join("b.raw"), "arch", "grubx64.efi", 3, 0x1)?;
+        merge(
+            &elements,
+            512 * ALIGNMENT * SECTOR_SIZE,
+            tmp.path().join("alloy.raw"),
+        )?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmp.path().join("alloy.raw"))?;
+        let gpt = Gpt::read(&mut file)?;
+        assert_eq!(gpt.entries.len(), 5);
+        assert_eq!(gpt.entries.iter().filter(|entry| entry.is_esp()).count(), 1);
+        assert_eq!(gpt.entries[2].unique_guid, [3; 16]);
+        assert_eq!(gpt.entries[2].type_guid, BASIC_DATA_TYPE);
+        assert_eq!(gpt.entries[3].unique_guid, [4; 16]);
+
+        let (start, end) = gpt.entries[2].range();
+        let fs = FileSystem::new(StreamSlice::new(&mut file, start, end)?, FsOptions::new())?;
+        assert_eq!(fs.volume_id(), 0x1);
+        drop(fs);
+
+        let (start, end) = gpt.entries[0].range();
+        let fs = FileSystem::new(StreamSlice::new(&mut file, start, end)?, FsOptions::new())?;
+        assert_eq!(fs.volume_id(), 0xabcd1234);
+        let mut entry = String::new();
+        fs.root_dir()
+            .open_file("loader/entries/goldboot-1.conf")?
+            .read_to_string(&mut entry)?;
+        assert_eq!(entry, "title B\nefi /EFI/goldboot/1/arch/grubx64.efi\n");
+        assert!(fs
+            .root_dir()
+            .open_file("EFI/goldboot/1/arch/grubx64.efi")
+            .is_ok());
+        drop(fs);
+
+        // So do elements with files that differ from another element's
+        create_element(
+            &tmp.path().join("b.raw"),
+            "debian",
+            "shimx64.efi",
+            3,
+            0xabcd1234,
+        )?;
+        merge(
+            &elements,
+            512 * ALIGNMENT * SECTOR_SIZE,
+            tmp.path().join("alloy.raw"),
+        )?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmp.path().join("alloy.raw"))?;
+        let gpt = Gpt::read(&mut file)?;
+        assert_eq!(gpt.entries.len(), 5);
+        assert_eq!(gpt.entries[2].type_guid, BASIC_DATA_TYPE);
+
+        let (start, end) = gpt.entries[0].range();
+        let fs = FileSystem::new(StreamSlice::new(&mut file, start, end)?, FsOptions::new())?;
+        let mut loader = String::new();
+        fs.root_dir()
+            .open_file("EFI/debian/shimx64.efi")?
+            .read_to_string(&mut loader)?;
+        assert_eq!(loader, "shimx64.efi 1");
+        let mut entry = String::new();
+        fs.root_dir()
+            .open_file("loader/entries/goldboot-1.conf")?
+            .read_to_string(&mut entry)?;
+        assert_eq!(entry, "title B\nefi /EFI/goldboot/1/debian/shimx64.efi\n");
+        drop(fs);
+
+        // systemd-boot elements can't be booted from an ESP that's kept
+        create_element(
+            &tmp.path().join("b.raw"),
+            "systemd",
+            "systemd-bootx64.efi",
+            3,
+            0x1,
+        )?;
+        assert!(merge(
+            &elements,
+            512 * ALIGNMENT * SECTOR_SIZE,
+            tmp.path().join("alloy.raw"),
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+//! A text syntax for boot sequences so they can be adjusted from the config
+//! file. Plain text is typed and commands go in angle brackets, for example:
+//!
+//! ```text
+//! <waitScreen:5b3ca88689e9d671903b3040889c7fa1cb5f244a,600>root<enter><wait5>
+//! ```
+//!
+//! Values given by the mold can be substituted with `${name}`. Everything after
+//! the colon in `<expect:regex>` and `<serial:text>` is used as is, so they can
+//! contain commas. Relative paths to reference screenshots are resolved against
+//! the directory of the file that contains the step.
+
+use super::{
+    keyboard::{self, KeyboardLayout},
+    vnc::{MouseButton, ReferenceScreen, VncCmd},
+};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use validator::Validate;
+
+/// Changes to a mold's boot sequence. Each string is one step.
+#[derive(Clone, Serialize, Deserialize, Validate, Default, Debug)]
+pub struct BootCommand {
+    /// Steps that run instead of the mold's own boot sequence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replace: Option<Vec<String>>,
+
+    /// Steps that run before the boot sequence
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub before: Vec<String>,
+
+    /// Steps that run after the boot sequence
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+}
+
+impl BootCommand {
+    /// Apply the changes to the given boot sequence.
+    pub fn apply(
+        &self,
+        sequence: Vec<Vec<VncCmd>>,
+        vars: &[(&str, &str)],
+        dir: &Path,
+    ) -> Result<Vec<Vec<VncCmd>>> {
+        let parse_all = |steps: &Vec<String>| -> Result<Vec<Vec<VncCmd>>> {
+            steps.iter().map(|step| parse(step, vars, dir)).collect()
+        };
+
+        let mut commands = parse_all(&self.before)?;
+        match &self.replace {
+            Some(steps) => commands.extend(parse_all(steps)?),
+            None => commands.extend(sequence),
+        }
+        commands.extend(parse_all(&self.after)?);
+        Ok(commands)
+    }
+}
+
+/// Replace `${name}` with the value of the given variable.
+fn substitute(text: &str, vars: &[(&str, &str)]) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated variable in: {}", text))?;
+        let name = &rest[start + 2..start + end];
+
+        let (_, value) = vars
+            .iter()
+            .find(|(var, _)| *var == name)
+            .ok_or_else(|| anyhow!("Unknown variable: {}", name))?;
+        output.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Parse a duration like "5", "10s", or "2m" into seconds.
+fn seconds(value: &str) -> Result<u64> {
+    Ok(match value {
+        "" => 1,
+        _ if value.ends_with('m') => value[..value.len() - 1].parse::<u64>()? * 60,
+        _ => value.trim_end_matches('s').parse()?,
+    })
+}
+
+/// Wrap a wait in a timeout if one was given.
+fn with_timeout(command: VncCmd, timeout: Option<&&str>) -> Result<VncCmd> {
+    Ok(match timeout {
+        Some(timeout) => VncCmd::WithTimeout(seconds(timeout)?, Box::new(command)),
+        None => command,
+    })
+}
+
+/// Parse a single command from between angle brackets.
+fn parse_command(tag: &str, dir: &Path) -> Result<VncCmd> {
+    // Serial console commands take the rest of the tag as one argument
+    if let Some((name, arg)) = tag.split_once(':') {
+        match name.to_lowercase().as_str() {
+            "expect" => return Ok(VncCmd::Expect(arg.to_string())),
+            "serial" => return Ok(VncCmd::SendLine(arg.to_string())),
+            _ => {}
+        }
+    }
+
+    let (name, args) = match tag.split_once(':') {
+        Some((name, args)) => (name, args.split(',').map(str::trim).collect()),
+        None => (tag, Vec::new()),
+    };
+
+    let arg = |index: usize| {
+        args.get(index)
+            .copied()
+            .ok_or_else(|| anyhow!("Missing argument {} in: <{}>", index + 1, tag))
+    };
+
+    Ok(match name.to_lowercase().as_str() {
+        "enter" | "return" => VncCmd::Enter,
+        "esc" | "escape" => VncCmd::Escape,
+        "tab" => VncCmd::Tab,
+        "spacebar" => VncCmd::Spacebar,
+        "leftsuper" => VncCmd::LeftSuper,
+        "waitscreen" => with_timeout(VncCmd::WaitScreen(arg(0)?.to_string()), args.get(1))?,
+        "waitscreenrect" => with_timeout(
+            VncCmd::WaitScreenRect(
+                arg(0)?.to_string(),
+                arg(1)?.parse()?,
+                arg(2)?.parse()?,
+                arg(3)?.parse()?,
+                arg(4)?.parse()?,
+            ),
+            args.get(5),
+        )?,
+        "waitsimilar" => with_timeout(
+            VncCmd::WaitScreenSimilar(ReferenceScreen::open(dir.join(arg(0)?))?, arg(1)?.parse()?),
+            args.get(2),
+        )?,
+        "waitimage" => with_timeout(
+            VncCmd::WaitImage(ReferenceScreen::open(dir.join(arg(0)?))?, arg(1)?.parse()?),
+            args.get(2),
+        )?,
+        "clickimage" => with_timeout(
+            VncCmd::ClickImage(ReferenceScreen::open(dir.join(arg(0)?))?, arg(1)?.parse()?),
+            args.get(2),
+        )?,
+        "click" => VncCmd::Click(
+            match args.get(2).copied().unwrap_or("left") {
+                "left" => MouseButton::Left,
+                "middle" => MouseButton::Middle,
+                "right" => MouseButton::Right,
+                button => bail!("Unknown mouse button: {}", button),
+            },
+            arg(0)?.parse()?,
+            arg(1)?.parse()?,
+        ),
+        "move" => VncCmd::MouseMove(arg(0)?.parse()?, arg(1)?.parse()?),
+        "scroll" => VncCmd::Scroll(arg(0)?.parse()?),
+        "layout" => VncCmd::Layout(arg(0)?.parse::<KeyboardLayout>()?),
+        wait if wait.starts_with("wait") => VncCmd::Wait(seconds(&wait[4..])?),
+        _ => {
+            // Anything else has to be a key chord
+            keyboard::chord(tag)?;
+            VncCmd::Chord(tag.to_string())
+        }
+    })
+}
+
+/// Parse one step of a boot sequence.
+pub fn parse(step: &str, vars: &[(&str, &str)], dir: &Path) -> Result<Vec<VncCmd>> {
+    let step = substitute(step, vars)?;
+    let mut commands = Vec::new();
+    let mut text = String::new();
+    let mut rest = step.as_str();
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('>')
+            .ok_or_else(|| anyhow!("Unterminated command in: {}", step))?;
+        let tag = rest[start + 1..start + end].trim();
+        rest = &rest[start + end + 1..];
+
+        match tag {
+            "lt" => text.push('<'),
+            "gt" => text.push('>'),
+            _ => {
+                if !text.is_empty() {
+                    commands.push(VncCmd::Type(std::mem::take(&mut text)));
+                }
+                commands.push(parse_command(tag, dir)?);
+            }
+        }
+    }
+
+    text.push_str(rest);
+    if !text.is_empty() {
+        commands.push(VncCmd::Type(text));
+    }
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let commands = parse(
+            "<wait30><waitScreen:5b3ca886,120>${user}<enter>a<lt>b<ctrl+alt+Delete><wait2m>",
+            &[("user", "root")],
+            Path::new("."),
+        )?;
+
+        assert_eq!(commands.len(), 7);
+        assert!(matches!(commands[0], VncCmd::Wait(30)));
+        assert!(matches!(
+            &commands[1],
+            VncCmd::WithTimeout(120, command) if matches!(&**command, VncCmd::WaitScreen(hash) if hash == "5b3ca886")
+        ));
+        assert!(matches!(&commands[2], VncCmd::Type(text) if text == "root"));
+        assert!(matches!(commands[3], VncCmd::Enter));
+        assert!(matches!(&commands[4], VncCmd::Type(text) if text == "a<b"));
+        assert!(matches!(&commands[5], VncCmd::Chord(chord) if chord == "ctrl+alt+Delete"));
+        assert!(matches!(commands[6], VncCmd::Wait(120)));
+
+        let commands = parse("<expect:login: $><serial:echo a,b>", &[], Path::new("."))?;
+        assert!(matches!(&commands[0], VncCmd::Expect(pattern) if pattern == "login: $"));
+        assert!(matches!(&commands[1], VncCmd::SendLine(text) if text == "echo a,b"));
+
+        assert!(parse("<wait", &[], Path::new(".")).is_err());
+        assert!(parse("<nothing>", &[], Path::new(".")).is_err());
+        assert!(parse("${missing}", &[], Path::new(".")).is_err());
+
+        // Reference screenshots are found next to the file with the step
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("login.png"), [0u8; 4])?;
+        let commands = parse("<waitImage:login.png,0.9>", &[], tmp.path())?;
+        assert!(
+            matches!(&commands[0], VncCmd::WaitImage(reference, _) if reference.name == tmp.path().join("login.png").to_string_lossy())
+        );
+        assert!(parse("<waitImage:login.png,0.9>", &[], Path::new(".")).is_err());
+
+        // Overrides can surround or replace the mold's sequence
+        let boot = BootCommand {
+            replace: None,
+            before: vec![String::from("<esc>")],
+            after: vec![String::from("<layout:de>")],
+        };
+        let commands = boot.apply(vec![vec![VncCmd::Tab]], &[], Path::new("."))?;
+        assert_eq!(commands.len(), 3);
+        assert!(matches!(commands[1][0], VncCmd::Tab));
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+//! Caches intermediate build artifacts so that a cast only repeats the steps
+//! whose inputs changed. The base install and each fabricator produce one layer
+//! which is stored as a qcow2 overlay on top of the previous layer.
+//!
+//! When caching is disabled, the same layers are only kept as checkpoints next
+//! to the foundry config when the cast is resumable (`--resume`).
+//!
+//! Layers that haven't been used in a while are evicted, and the least recently
+//! used layers are evicted when the cache grows too large.
+
+use super::{
+    fabricators::Fabricate,
+    molds::ImageMold,
+    sources::{ImageSource, SourceCache},
+    ImageElement,
+};
+use crate::cli::progress::ProgressBar;
+use anyhow::{bail, Result};
+use filetime::FileTime;
+use goldboot_image::ImageArch;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, info};
+
+/// Layers that haven't been used for this long are evicted.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The least recently used layers are evicted until the cache fits in this size.
+const MAX_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// Cache for build layers.
+pub struct BuildCache {
+    /// Cache location on disk
+    pub directory: PathBuf,
+}
+
+impl BuildCache {
+    /// Get the default platform-dependent build cache.
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Result<Self> {
+        let directory = if cfg!(target_os = "linux") {
+            PathBuf::from(format!(
+                "/home/{}/.cache/goldboot/builds",
+                whoami::username()
+            ))
+        } else if cfg!(target_os = "macos") {
+            PathBuf::from(format!(
+                "/Users/{}/.cache/goldboot/builds",
+                whoami::username()
+            ))
+        } else if cfg!(target_os = "windows") {
+            PathBuf::from(format!(
+                "C:/Users/{}/AppData/Local/goldboot/cache/builds",
+                whoami::username()
+            ))
+        } else {
+            bail!("Unsupported platform");
+        };
+
+        Self::new(directory)
+    }
+
+    /// Open a build cache in the given directory.
+    pub fn new(directory: impl AsRef<Path>) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+
+        // Make sure it exists before we return
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self { directory })
+    }
+
+    /// Compute the key of every layer in the given element's build. The first
+    /// key is for the base install and the rest are for each fabricator. Each
+    /// key includes the previous one, so a change invalidates all later layers.
+    pub fn keys(element: &ImageElement, arch: ImageArch, size: u64) -> Result<Vec<String>> {
+        let mut hasher = Sha256::new()
+            .chain_update(ron::to_string(&arch)?)
+            .chain_update(ron::to_string(&element.mold)?)
+            .chain_update(ron::to_string(&element.source)?)
+            .chain_update(ron::to_string(&element.boot)?)
+            .chain_update(size.to_le_bytes());
+
+        // Scripted molds are only referenced by path in the config
+        if let ImageMold::Scripted(mold) = &element.mold {
+            hasher.update(ron::to_string(&mold.definition)?);
+            for input in mold.inputs() {
+                hasher.update(std::fs::read(input)?);
+            }
+        }
+
+        match &element.source {
+            // Base images can be replaced under the same name
+            ImageSource::Mold { base } => hasher.update(super::sources::mold::base_id(base)?),
+            // Without a checksum, the media behind the URL can change
+            ImageSource::Iso {
+                url,
+                checksum: None,
+            } => {
+                let path = SourceCache::default()?.get(url.clone(), None)?;
+                info!("Computing SHA256 checksum");
+                ProgressBar::Hash.copy(
+                    &mut File::open(&path)?,
+                    &mut hasher,
+                    std::fs::metadata(&path)?.len(),
+                )?;
+            }
+            _ => {}
+        }
+
+        let mut keys = vec![hex::encode(hasher.finalize())];
+
+        for fabricator in element.fabricators.iter().flatten() {
+            let mut hasher = Sha256::new()
+                .chain_update(keys.last().unwrap())
+                .chain_update(ron::to_string(fabricator)?);
+
+            for input in fabricator.inputs() {
+                hasher.update(std::fs::read(input)?);
+            }
+
+            keys.push(hex::encode(hasher.finalize()));
+        }
+
+        Ok(keys)
+    }
+
+    /// Get the path of the layer with the given key.
+    pub fn layer(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{key}.qcow2"))
+    }
+
+    /// Check whether the layer with the given key exists.
+    pub fn contains(&self, key: &str) -> bool {
+        self.layer(key).is_file()
+    }
+
+    /// Mark the layer with the given key as recently used.
+    pub fn touch(&self, key: &str) -> Result<()> {
+        filetime::set_file_mtime(self.layer(key), FileTime::now())?;
+        Ok(())
+    }
+
+    /// Remove the layer with the given key if it exists.
+    pub fn remove(&self, key: &str) -> Result<()> {
+        match std::fs::remove_file(self.layer(key)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Move a finished disk into the cache as the layer with the given key. If
+    /// another worker already stored the same layer, it's kept instead since
+    /// other layers may be on top of it.
+    pub fn store(&self, disk: impl AsRef<Path>, key: &str) -> Result<()> {
+        let disk = disk.as_ref();
+        let layer = self.layer(key);
+        debug!(disk = ?disk, layer = ?layer, "Storing build layer");
+
+        // Unlike a rename, linking never replaces an existing layer
+        match std::fs::hard_link(disk, &layer) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+            // The cache is usually on a different filesystem than the worker
+            Err(_) => {
+                let mut partial = tempfile::Builder::new()
+                    .suffix(".partial")
+                    .tempfile_in(&self.directory)?;
+                std::io::copy(&mut File::open(disk)?, &mut partial)?;
+
+                if let Err(err) = partial.persist_noclobber(&layer) {
+                    if err.error.kind() != ErrorKind::AlreadyExists {
+                        return Err(err.error.into());
+                    }
+                }
+            }
+        }
+
+        std::fs::remove_file(disk)?;
+        Ok(())
+    }
+
+    /// Remove layers that haven't been used recently and then the least
+    /// recently used layers until the cache is small enough.
+    pub fn evict(&self) -> Result<()> {
+        let mut files = Vec::new();
+        for entry in self.directory.read_dir()? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                files.push((entry.path(), metadata.len(), metadata.modified()?));
+            }
+        }
+
+        // Newest first
+        files.sort_by_key(|(_, _, modified)| std::cmp::Reverse(*modified));
+
+        let mut total = 0;
+        for (path, size, modified) in files {
+            total += size;
+
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            if age > MAX_AGE || total > MAX_SIZE {
+                debug!(layer = ?path, "Evicting build layer");
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a new disk that's an overlay on top of the layer with the given
+    /// key.
+    pub fn overlay(&self, key: &str, disk: impl AsRef<Path>) -> Result<()> {
+        super::qemu::overlay(self.layer(key), disk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::foundry::fabricators::{ansible::Ansible, Fabricator};
+
+    #[test]
+    fn test_keys() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let playbook = tmp.path().join("playbook.yml");
+        std::fs::write(&playbook, "- hosts: all\n")?;
+        let iso = tmp.path().join("install.iso");
+        std::fs::write(&iso, "install")?;
+
+        let element = ImageElement {
+            boot: None,
+            fabricators: Some(vec![
+                Fabricator::Ansible(Ansible {
+                    playbook: playbook.to_string_lossy().to_string(),
+                    inventory: None,
+                }),
+                Fabricator::Ansible(Ansible {
+                    playbook: playbook.to_string_lossy().to_string(),
+                    inventory: None,
+                }),
+            ]),
+            source: ImageSource::Iso {
+                url: iso.to_string_lossy().to_string(),
+                checksum: None,
+            },
+            ..Default::default()
+        };
+
+        let keys = BuildCache::keys(&element, ImageArch::Amd64, 1024)?;
+        assert_eq!(keys.len(), 3);
+        assert_ne!(keys[1], keys[2]);
+        assert_eq!(keys, BuildCache::keys(&element, ImageArch::Amd64, 1024)?);
+
+        // Changing a playbook only invalidates the fabricator layers
+        std::fs::write(&playbook, "- hosts: localhost\n")?;
+        let changed = BuildCache::keys(&element, ImageArch::Amd64, 1024)?;
+        assert_eq!(keys[0], changed[0]);
+        assert_ne!(keys[1], changed[1]);
+
+        // The install media is checked when there's no checksum
+        std::fs::write(&iso, "updated")?;
+        let updated = BuildCache::keys(&element, ImageArch::Amd64, 1024)?;
+        assert_ne!(changed[0], updated[0]);
+        assert_ne!(changed[2], updated[2]);
+
+        // Everything depends on the architecture
+        let arm = BuildCache::keys(&element, ImageArch::Arm64, 1024)?;
+        assert_ne!(keys[0], arm[0]);
+        assert_ne!(keys[2], arm[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cache = BuildCache::new(tmp.path().join("cache"))?;
+
+        let disk = tmp.path().join("disk.qcow2");
+        std::fs::write(&disk, "first")?;
+        cache.store(&disk, "a")?;
+        assert!(cache.contains("a"));
+        assert!(!disk.exists());
+
+        // The first stored layer wins
+        std::fs::write(&disk, "second")?;
+        cache.store(&disk, "a")?;
+        assert_eq!(std::fs::read(cache.layer("a"))?, b"first");
+
+        // Old layers are evicted
+        filetime::set_file_mtime(
+            cache.layer("a"),
+            FileTime::from_system_time(SystemTime::now() - MAX_AGE * 2),
+        )?;
+        std::fs::write(&disk, "b")?;
+        cache.store(&disk, "b")?;
+        cache.evict()?;
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b"));
+        Ok(())
+    }
+}
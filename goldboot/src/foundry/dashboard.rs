@@ -0,0 +1,329 @@
+//! A local web UI that shows what each worker's VM is doing during a cast and
+//! lets breakpoints be controlled from a browser.
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+};
+use tokio::runtime::Runtime;
+use tracing::info;
+
+/// How many log lines are kept for each worker.
+const LOG_LINES: usize = 200;
+
+/// What the dashboard knows about a worker.
+#[derive(Clone, Serialize, Debug, Default)]
+pub struct WorkerStatus {
+    pub id: usize,
+    pub mold: String,
+    pub vnc_port: u16,
+
+    /// Either "running", "succeeded", or "failed"
+    pub state: String,
+    pub error: Option<String>,
+
+    /// The current step of the boot sequence
+    pub step: usize,
+    pub command: String,
+
+    /// The command that's waiting at a breakpoint
+    pub breakpoint: Option<String>,
+
+    /// Recent output from fabricators
+    pub log: VecDeque<String>,
+
+    /// The latest screenshot as a PNG
+    #[serde(skip)]
+    screen: Option<Vec<u8>>,
+
+    #[serde(skip)]
+    action: Option<BreakpointAction>,
+}
+
+/// The choices at a breakpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakpointAction {
+    Continue,
+    Screenshot,
+    Quit,
+}
+
+impl BreakpointAction {
+    /// The equivalent breakpoint input on the terminal.
+    pub fn input(&self) -> &'static str {
+        match self {
+            BreakpointAction::Continue => "c",
+            BreakpointAction::Screenshot => "s",
+            BreakpointAction::Quit => "q",
+        }
+    }
+}
+
+pub struct Dashboard {
+    workers: Mutex<Vec<WorkerStatus>>,
+
+    /// Signaled when a breakpoint action arrives
+    actions: Condvar,
+
+    /// A random token for this run that API requests must include. It keeps
+    /// other sites open in the browser from reading or controlling the cast.
+    token: String,
+}
+
+impl Dashboard {
+    fn new() -> Self {
+        Self {
+            workers: Mutex::new(Vec::new()),
+            actions: Condvar::new(),
+            token: rand::thread_rng()
+                .sample_iter(&rand::distributions::Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect(),
+        }
+    }
+
+    /// Start serving the dashboard on the given local port.
+    pub fn serve(port: u16) -> Result<Arc<Self>> {
+        let dashboard = Arc::new(Self::new());
+        let router = Router::new()
+            .route("/api/workers", get(workers))
+            .route("/api/workers/:id/screen.png", get(screen))
+            .route("/api/workers/:id/:action", post(breakpoint))
+            .route_layer(middleware::from_fn_with_state(dashboard.clone(), authorize))
+            .route("/", get(index))
+            .with_state(dashboard.clone());
+
+        // Bind now so a port conflict fails the cast
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+
+        std::thread::spawn(move || {
+            Runtime::new().unwrap().block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                axum::serve(listener, router).await.unwrap();
+            });
+        });
+
+        info!(
+            "Dashboard available at: http://127.0.0.1:{}/?token={}",
+            port, dashboard.token
+        );
+        Ok(dashboard)
+    }
+
+    /// Add a worker and get a handle for reporting its progress.
+    pub fn register(self: &Arc<Self>, mold: &str, vnc_port: u16) -> DashboardHandle {
+        let mut workers = self.workers.lock().unwrap();
+        let id = workers.len();
+        workers.push(WorkerStatus {
+            id,
+            mold: mold.to_string(),
+            vnc_port,
+            state: String::from("running"),
+            ..Default::default()
+        });
+
+        DashboardHandle {
+            dashboard: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Reports the progress of a single worker to the dashboard.
+#[derive(Clone)]
+pub struct DashboardHandle {
+    dashboard: Arc<Dashboard>,
+    id: usize,
+}
+
+impl DashboardHandle {
+    fn update(&self, update: impl FnOnce(&mut WorkerStatus)) {
+        update(&mut self.dashboard.workers.lock().unwrap()[self.id]);
+    }
+
+    pub fn step(&self, step: usize, command: &str) {
+        self.update(|status| {
+            status.step = step;
+            status.command = command.to_string();
+        });
+    }
+
+    pub fn screen(&self, png: Vec<u8>) {
+        self.update(|status| status.screen = Some(png));
+    }
+
+    pub fn log(&self, line: &str) {
+        self.update(|status| {
+            status.log.push_back(line.to_string());
+            if status.log.len() > LOG_LINES {
+                status.log.pop_front();
+            }
+        });
+    }
+
+    pub fn finish(&self, result: &Result<()>) {
+        self.update(|status| match result {
+            Ok(()) => status.state = String::from("succeeded"),
+            Err(err) => {
+                status.state = String::from("failed");
+                status.error = Some(err.to_string());
+            }
+        });
+    }
+
+    /// Block until a breakpoint action is chosen on the dashboard.
+    pub fn breakpoint(&self, command: &str) -> BreakpointAction {
+        let mut workers = self.dashboard.workers.lock().unwrap();
+        workers[self.id].breakpoint = Some(command.to_string());
+        workers[self.id].action = None;
+
+        let mut workers = self
+            .dashboard
+            .actions
+            .wait_while(workers, |workers| workers[self.id].action.is_none())
+            .unwrap();
+        workers[self.id].breakpoint = None;
+        workers[self.id].action.take().unwrap()
+    }
+}
+
+#[derive(Deserialize)]
+struct Auth {
+    token: Option<String>,
+}
+
+/// Reject API requests that don't have the token of this run.
+async fn authorize(
+    State(dashboard): State<Arc<Dashboard>>,
+    Query(auth): Query<Auth>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if auth.token.as_deref() != Some(dashboard.token.as_str()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+async fn index() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+async fn workers(State(dashboard): State<Arc<Dashboard>>) -> Json<Vec<WorkerStatus>> {
+    Json(dashboard.workers.lock().unwrap().clone())
+}
+
+async fn screen(State(dashboard): State<Arc<Dashboard>>, Path(id): Path<usize>) -> Response {
+    let workers = dashboard.workers.lock().unwrap();
+    match workers.get(id).and_then(|worker| worker.screen.clone()) {
+        Some(png) => (
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            png,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn breakpoint(
+    State(dashboard): State<Arc<Dashboard>>,
+    Path((id, action)): Path<(usize, String)>,
+) -> StatusCode {
+    let action = match action.as_str() {
+        "continue" => BreakpointAction::Continue,
+        "screenshot" => BreakpointAction::Screenshot,
+        "quit" => BreakpointAction::Quit,
+        _ => return StatusCode::NOT_FOUND,
+    };
+
+    let mut workers = dashboard.workers.lock().unwrap();
+    match workers.get_mut(id) {
+        Some(worker) if worker.breakpoint.is_some() => {
+            worker.action = Some(action);
+            dashboard.actions.notify_all();
+            StatusCode::NO_CONTENT
+        }
+        // The worker isn't waiting for anything
+        Some(_) => StatusCode::CONFLICT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakpoint() -> Result<()> {
+        let dashboard = Arc::new(Dashboard::new());
+        let handle = dashboard.register("ArchLinux", 5901);
+        handle.step(3, "Enter");
+        handle.log("(fabricator) done");
+
+        let runtime = Runtime::new()?;
+        let press = |action: &str| {
+            runtime.block_on(breakpoint(
+                State(dashboard.clone()),
+                Path((0, action.to_string())),
+            ))
+        };
+
+        // Nothing is waiting yet
+        assert_eq!(press("continue"), StatusCode::CONFLICT);
+
+        let waiting = std::thread::spawn({
+            let handle = handle.clone();
+            move || handle.breakpoint("Enter")
+        });
+        while dashboard.workers.lock().unwrap()[0].breakpoint.is_none() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(press("screenshot"), StatusCode::NO_CONTENT);
+        assert_eq!(waiting.join().unwrap(), BreakpointAction::Screenshot);
+
+        handle.finish(&Ok(()));
+        let Json(statuses) = runtime.block_on(workers(State(dashboard.clone())));
+        assert_eq!(statuses[0].step, 3);
+        assert_eq!(statuses[0].state, "succeeded");
+        assert_eq!(statuses[0].log, vec!["(fabricator) done"]);
+        assert!(statuses[0].breakpoint.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_token() -> Result<()> {
+        let port = rand::thread_rng().gen_range(20000..30000);
+        let dashboard = Dashboard::serve(port)?;
+        let url = format!("http://127.0.0.1:{port}/api/workers");
+
+        let status = |url: &str| -> Result<reqwest::StatusCode> {
+            Ok(reqwest::blocking::get(url)?.status())
+        };
+        assert_eq!(status(&url)?, reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(
+            status(&format!("{url}?token=wrong"))?,
+            reqwest::StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(&format!("{url}?token={}", dashboard.token))?,
+            reqwest::StatusCode::OK
+        );
+        Ok(())
+    }
+}
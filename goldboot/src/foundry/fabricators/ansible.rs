@@ -4,7 +4,10 @@ use anyhow::bail;
 use anyhow::Result;
 use dialoguer::theme::Theme;
 use serde::{Deserialize, Serialize};
-use std::{path::Path, process::Command};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
 use tracing::info;
 use validator::Validate;
 
@@ -53,8 +56,15 @@ impl Ansible {
 }
 
 impl Fabricate for Ansible {
-    fn run(&self, _ssh: &mut SshConnection) -> Result<()> {
-        todo!()
+    fn run(&self, ssh: &mut SshConnection) -> Result<()> {
+        Ansible::run(self, ssh)
+    }
+
+    fn inputs(&self) -> Vec<PathBuf> {
+        std::iter::once(&self.playbook)
+            .chain(self.inventory.iter())
+            .map(PathBuf::from)
+            .collect()
     }
 }
 
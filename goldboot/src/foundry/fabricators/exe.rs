@@ -5,7 +5,7 @@ use anyhow::bail;
 use anyhow::Result;
 use dialoguer::theme::Theme;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tracing::info;
 use validator::Validate;
 
@@ -25,6 +25,10 @@ impl Fabricate for HostExecutable {
         }
         Ok(())
     }
+
+    fn inputs(&self) -> Vec<PathBuf> {
+        vec![PathBuf::from(&self.path)]
+    }
 }
 
 impl Prompt for HostExecutable {
@@ -7,6 +7,7 @@ use ansible::Ansible;
 use anyhow::Result;
 use enum_dispatch::enum_dispatch;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 pub mod ansible;
 pub mod exe;
@@ -18,6 +19,12 @@ pub mod shell;
 #[enum_dispatch(Fabricator)]
 pub trait Fabricate {
     fn run(&self, ssh: &mut SshConnection) -> Result<()>;
+
+    /// Local files the fabricator reads. Changes to them invalidate cached
+    /// builds.
+    fn inputs(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
 }
 
 #[enum_dispatch]
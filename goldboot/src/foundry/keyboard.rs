@@ -0,0 +1,232 @@
+//! Translates text and key names into the X keysyms that VNC sends. QEMU maps
+//! keysyms to scancodes with a US keymap, so typing into a guest with another
+//! layout means pressing the US key that sits where the character is on the
+//! guest's layout.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+pub const SHIFT: u32 = 0xffe1;
+pub const CONTROL: u32 = 0xffe3;
+pub const ALT: u32 = 0xffe9;
+pub const ALT_GR: u32 = 0xffea;
+pub const SUPER: u32 = 0xffeb;
+pub const RETURN: u32 = 0xff0d;
+pub const TAB: u32 = 0xff09;
+pub const SPACE: u32 = 0x0020;
+
+/// The key on ISO keyboards between left shift and the first letter.
+const LSGT: char = '\u{1}';
+
+/// The keyboard layout that the guest is using.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    #[default]
+    Us,
+    De,
+    Fr,
+}
+
+/// A single key press with the modifiers it needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keystroke {
+    pub keysym: u32,
+    pub shift: bool,
+    pub alt_gr: bool,
+
+    /// Dead keys need a space afterwards to produce their character
+    pub dead: bool,
+}
+
+/// Each row is the US key followed by the characters it produces plainly, with
+/// shift, and with AltGr on the layout ('\0' for nothing).
+type Row = (char, char, char, char);
+
+#[rustfmt::skip]
+const US: &[Row] = &[
+    ('`', '`', '~', '\0'), ('1', '1', '!', '\0'), ('2', '2', '@', '\0'), ('3', '3', '#', '\0'),
+    ('4', '4', '$', '\0'), ('5', '5', '%', '\0'), ('6', '6', '^', '\0'), ('7', '7', '&', '\0'),
+    ('8', '8', '*', '\0'), ('9', '9', '(', '\0'), ('0', '0', ')', '\0'), ('-', '-', '_', '\0'),
+    ('=', '=', '+', '\0'), ('[', '[', '{', '\0'), (']', ']', '}', '\0'), ('\\', '\\', '|', '\0'),
+    (';', ';', ':', '\0'), ('\'', '\'', '"', '\0'), (',', ',', '<', '\0'), ('.', '.', '>', '\0'),
+    ('/', '/', '?', '\0'),
+];
+
+#[rustfmt::skip]
+const DE: &[Row] = &[
+    ('`', '^', '°', '\0'), ('1', '1', '!', '\0'), ('2', '2', '"', '²'), ('3', '3', '§', '³'),
+    ('4', '4', '$', '\0'), ('5', '5', '%', '\0'), ('6', '6', '&', '\0'), ('7', '7', '/', '{'),
+    ('8', '8', '(', '['), ('9', '9', ')', ']'), ('0', '0', '=', '}'), ('-', 'ß', '?', '\\'),
+    ('=', '´', '`', '\0'), ('q', 'q', 'Q', '@'), ('e', 'e', 'E', '€'), ('y', 'z', 'Z', '\0'),
+    ('z', 'y', 'Y', '\0'), ('m', 'm', 'M', 'µ'), ('[', 'ü', 'Ü', '\0'), (']', '+', '*', '~'),
+    (';', 'ö', 'Ö', '\0'), ('\'', 'ä', 'Ä', '\0'), ('\\', '#', '\'', '\0'), (',', ',', ';', '\0'),
+    ('.', '.', ':', '\0'), ('/', '-', '_', '\0'), (LSGT, '<', '>', '|'),
+];
+
+#[rustfmt::skip]
+const FR: &[Row] = &[
+    ('`', '²', '\0', '\0'), ('1', '&', '1', '\0'), ('2', 'é', '2', '~'), ('3', '"', '3', '#'),
+    ('4', '\'', '4', '{'), ('5', '(', '5', '['), ('6', '-', '6', '|'), ('7', 'è', '7', '`'),
+    ('8', '_', '8', '\\'), ('9', 'ç', '9', '^'), ('0', 'à', '0', '@'), ('-', ')', '°', ']'),
+    ('=', '=', '+', '}'), ('q', 'a', 'A', '\0'), ('w', 'z', 'Z', '\0'), ('e', 'e', 'E', '€'),
+    ('[', '^', '¨', '\0'), (']', '$', '£', '¤'), ('a', 'q', 'Q', '\0'), (';', 'm', 'M', '\0'),
+    ('\'', 'ù', '%', '\0'), ('\\', '*', 'µ', '\0'), ('z', 'w', 'W', '\0'), ('m', ',', '?', '\0'),
+    (',', ';', '.', '\0'), ('.', ':', '/', '\0'), ('/', '!', '§', '\0'), (LSGT, '<', '>', '\0'),
+];
+
+/// Get the keysym for a character.
+fn char_keysym(ch: char) -> u32 {
+    match ch {
+        LSGT => '<' as u32,
+        // Latin-1 keysyms match the code point
+        '\u{20}'..='\u{7e}' | '\u{a0}'..='\u{ff}' => ch as u32,
+        _ => 0x01000000 + ch as u32,
+    }
+}
+
+impl std::str::FromStr for KeyboardLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "us" => Ok(KeyboardLayout::Us),
+            "de" => Ok(KeyboardLayout::De),
+            "fr" => Ok(KeyboardLayout::Fr),
+            _ => Err(anyhow!("Unknown keyboard layout: {}", s)),
+        }
+    }
+}
+
+impl KeyboardLayout {
+    fn rows(&self) -> &'static [Row] {
+        match self {
+            KeyboardLayout::Us => US,
+            KeyboardLayout::De => DE,
+            KeyboardLayout::Fr => FR,
+        }
+    }
+
+    fn dead_keys(&self) -> &'static [char] {
+        match self {
+            KeyboardLayout::Us => &[],
+            KeyboardLayout::De => &['^', '´', '`'],
+            KeyboardLayout::Fr => &['^', '¨', '~', '`'],
+        }
+    }
+
+    /// Find the US key press that produces the given character on this layout.
+    pub fn keystroke(&self, ch: char) -> Result<Keystroke> {
+        let dead = self.dead_keys().contains(&ch);
+        let stroke = |key: char, shift: bool, alt_gr: bool| Keystroke {
+            keysym: char_keysym(key),
+            shift,
+            alt_gr,
+            dead,
+        };
+
+        for &(key, plain, shifted, alt_gr) in self.rows() {
+            if ch == plain {
+                return Ok(stroke(key, false, false));
+            } else if ch == shifted {
+                return Ok(stroke(key, true, false));
+            } else if ch == alt_gr {
+                return Ok(stroke(key, false, true));
+            }
+        }
+
+        // Everything else is where it is on a US keyboard
+        if ch == ' ' || ch.is_ascii_lowercase() || ch.is_ascii_digit() {
+            Ok(stroke(ch, false, false))
+        } else if ch.is_ascii_uppercase() {
+            Ok(stroke(ch.to_ascii_lowercase(), true, false))
+        } else {
+            Err(anyhow!("Can't type {:?} with the {:?} layout", ch, self))
+        }
+    }
+}
+
+/// Get the keysym for an X key name like "Return", "F5", or "a". Common
+/// modifiers can also be given by short names like "ctrl".
+pub fn keysym(name: &str) -> Option<u32> {
+    let mut chars = name.chars();
+    if let (Some(ch), None) = (chars.next(), chars.next()) {
+        return Some(char_keysym(ch));
+    }
+
+    if let Some(number) = name
+        .strip_prefix(['F', 'f'])
+        .and_then(|number| number.parse::<u32>().ok())
+    {
+        return (1..=12).contains(&number).then(|| 0xffbd + number);
+    }
+
+    Some(match name.to_lowercase().as_str() {
+        "ctrl" | "control" | "control_l" => CONTROL,
+        "control_r" => 0xffe4,
+        "alt" | "alt_l" => ALT,
+        "altgr" | "alt_r" => ALT_GR,
+        "shift" | "shift_l" => SHIFT,
+        "shift_r" => 0xffe2,
+        "super" | "win" | "super_l" => SUPER,
+        "return" | "enter" => RETURN,
+        "tab" => TAB,
+        "space" => SPACE,
+        "escape" | "esc" => 0xff1b,
+        "backspace" => 0xff08,
+        "delete" | "del" => 0xffff,
+        "insert" => 0xff63,
+        "home" => 0xff50,
+        "end" => 0xff57,
+        "page_up" | "prior" => 0xff55,
+        "page_down" | "next" => 0xff56,
+        "left" => 0xff51,
+        "up" => 0xff52,
+        "right" => 0xff53,
+        "down" => 0xff54,
+        "menu" => 0xff67,
+        _ => return None,
+    })
+}
+
+/// Parse a chord like "ctrl+alt+Delete" into keysyms in press order.
+pub fn chord(chord: &str) -> Result<Vec<u32>> {
+    chord
+        .split('+')
+        .map(|name| keysym(name.trim()).ok_or_else(|| anyhow!("Unknown key: {}", name)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keystroke() -> Result<()> {
+        assert_eq!(
+            KeyboardLayout::Us.keystroke('A')?,
+            Keystroke {
+                keysym: 'a' as u32,
+                shift: true,
+                alt_gr: false,
+                dead: false
+            }
+        );
+        assert_eq!(KeyboardLayout::Us.keystroke('|')?.keysym, '\\' as u32);
+
+        // The German layout swaps y and z and puts symbols elsewhere
+        assert_eq!(KeyboardLayout::De.keystroke('z')?.keysym, 'y' as u32);
+        assert_eq!(KeyboardLayout::De.keystroke('/')?.keysym, '7' as u32);
+        assert!(KeyboardLayout::De.keystroke('@')?.alt_gr);
+        assert!(KeyboardLayout::De.keystroke('^')?.dead);
+
+        // French is AZERTY with shifted digits
+        assert_eq!(KeyboardLayout::Fr.keystroke('a')?.keysym, 'q' as u32);
+        assert!(KeyboardLayout::Fr.keystroke('1')?.shift);
+        assert!(KeyboardLayout::Us.keystroke('é').is_err());
+
+        assert_eq!(chord("ctrl+alt+Delete")?, vec![CONTROL, ALT, 0xffff]);
+        assert_eq!(chord("F12")?, vec![0xffc9]);
+        assert!(chord("ctrl+nothing").is_err());
+        Ok(())
+    }
+}
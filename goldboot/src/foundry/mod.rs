@@ -1,8 +1,20 @@
-use self::{fabricators::Fabricator, molds::ImageMold, sources::ImageSource};
+use self::{
+    cache::BuildCache,
+    fabricators::Fabricator,
+    molds::ImageMold,
+    report::{BuildReport, ElementReport},
+    smoke::SmokeTests,
+    sources::ImageSource,
+};
+use crate::foundry::boot_command::BootCommand;
+use crate::foundry::dashboard::{Dashboard, DashboardHandle};
+use crate::foundry::fabricators::Fabricate;
 use crate::foundry::molds::CastImage;
+use crate::foundry::qemu::{FailedVmSlot, OnFailure};
+use crate::foundry::vnc::{VncCmd, DEFAULT_WAIT_TIMEOUT};
 use crate::{cli::progress::ProgressBar, library::ImageLibrary};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use byte_unit::Byte;
 use clap::{builder::PossibleValue, ValueEnum};
 use goldboot_image::{qcow::Qcow3, ImageArch, ImageHandle};
@@ -12,20 +24,30 @@ use serde::{Deserialize, Serialize};
 use std::{
     fmt::Display,
     path::{Path, PathBuf},
-    sync::OnceLock,
+    sync::{Arc, OnceLock},
     thread,
     time::SystemTime,
 };
 use strum::EnumIter;
-use tracing::info;
+use tracing::{error, info, warn};
 use validator::Validate;
 
+pub mod alloy;
+pub mod boot_command;
+pub mod cache;
+pub mod dashboard;
 pub mod fabricators;
 pub mod http;
+pub mod keyboard;
 pub mod molds;
 pub mod options;
 pub mod ovmf;
 pub mod qemu;
+pub mod qmp;
+pub mod recording;
+pub mod report;
+pub mod serial;
+pub mod smoke;
 pub mod sources;
 pub mod ssh;
 pub mod vnc;
@@ -33,6 +55,10 @@ pub mod vnc;
 ///
 #[derive(Clone, Serialize, Deserialize, Validate, Default, Debug)]
 pub struct ImageElement {
+    /// Changes to the mold's boot sequence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot: Option<BootCommand>,
+
     pub fabricators: Option<Vec<Fabricator>>,
     pub mold: ImageMold,
     pub pref_size: Option<String>,
@@ -40,15 +66,13 @@ pub struct ImageElement {
 }
 
 impl ImageElement {
-    /// Get the size
-    pub fn size(&self, image_size: String) -> u64 {
-        let image_size = Byte::parse_str(image_size, true).unwrap();
-
-        if let Some(_size) = &self.pref_size {
-            todo!()
-        } else {
-            image_size.as_u64()
-        }
+    /// Get the size of the element's disk given its share of the image size.
+    pub fn size(&self, share: u64) -> u64 {
+        self.pref_size
+            .as_ref()
+            .and_then(|size| Byte::parse_str(size, true).ok())
+            .map(|size| size.as_u64())
+            .unwrap_or(share)
     }
 }
 
@@ -64,6 +88,10 @@ pub struct Foundry {
     #[serde(flatten)]
     pub arch: ImageArch,
 
+    /// The local port of the web dashboard
+    #[serde(skip)]
+    pub dashboard: Option<u16>,
+
     /// When set, the run will pause before each step in the boot sequence
     pub debug: bool,
 
@@ -76,13 +104,33 @@ pub struct Foundry {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub memory: Option<String>,
 
+    /// Where checkpoints are kept when resuming without the build cache
+    #[serde(skip)]
+    pub checkpoints: Option<PathBuf>,
+
+    /// The directory that relative paths in the config are resolved against
+    #[serde(skip)]
+    pub dir: PathBuf,
+
     /// The image name
     #[validate(length(min = 1, max = 64))]
     pub name: String,
 
+    /// Whether the state of failed workers will be kept for inspection
+    #[serde(skip)]
+    pub keep_failed: bool,
+
+    /// Whether cached build layers will be ignored
+    #[serde(skip)]
+    pub no_cache: bool,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nvme: Option<bool>,
 
+    /// What to do with the VM when the build fails
+    #[serde(skip)]
+    pub on_failure: Option<OnFailure>,
+
     /// The path to an OVMF.fd file
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ovmf_path: Option<String>,
@@ -91,80 +139,247 @@ pub struct Foundry {
     #[serde(skip_serializing)]
     pub password: Option<String>,
 
-    /// Whether screenshots will be generated during the run for debugging
+    /// Whether the screen will be recorded during the run for debugging
     pub record: bool,
 
+    /// Where the build report is written
+    #[serde(skip)]
+    pub report: Option<PathBuf>,
+
+    /// Whether to continue from the checkpoints of a previous failed run
+    #[serde(skip)]
+    pub resume: bool,
+
     /// Whether the image is public
     pub public: bool,
 
+    /// Where screenshots are saved when a wait times out
+    #[serde(skip)]
+    pub screenshots: Option<PathBuf>,
+
     pub size: String,
+
+    /// Checks to run against the finished image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tests: Option<SmokeTests>,
+
+    /// The default amount of seconds that screen waits can take before the
+    /// cast fails
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait_timeout: Option<u64>,
 }
 
 /// Handles more sophisticated validation of a [`Foundry`].
-pub fn custom_foundry_validator(_f: &Foundry) -> Result<(), validator::ValidationError> {
-    // If there's more than one mold, they must all support alloy
-    // if f.alloy.len() > 1 {
-    //     for template in &self.config.templates {
-    //         //if !template.is_multiboot() {
-    //         //	bail!("Template does not support multiboot");
-    //         //}
-    //     }
-    // }
+pub fn custom_foundry_validator(f: &Foundry) -> Result<(), validator::ValidationError> {
+    if f.alloy.len() > 1 {
+        // If there's more than one mold, they must all support alloy
+        if let Some(element) = f.alloy.iter().find(|element| !element.mold.alloy()) {
+            let mut error = validator::ValidationError::new("alloy");
+            error.message =
+                Some(format!("{} cannot be combined with other molds", element.mold).into());
+            return Err(error);
+        }
+
+        // The preferred sizes have to leave room for the rest
+        let total = Byte::parse_str(&f.size, true)
+            .map_err(|_| validator::ValidationError::new("size"))?
+            .as_u64();
+        let mut preferred = 0;
+        for element in &f.alloy {
+            if let Some(size) = &element.pref_size {
+                preferred += Byte::parse_str(size, true)
+                    .map_err(|_| validator::ValidationError::new("pref_size"))?
+                    .as_u64();
+            }
+        }
+
+        if preferred >= total {
+            let mut error = validator::ValidationError::new("pref_size");
+            error.message = Some("The preferred sizes exceed the image size".into());
+            return Err(error);
+        }
+    }
 
     Ok(())
 }
 
 impl Foundry {
-    fn new_worker(&self, element: ImageElement) -> FoundryWorker {
+    /// Split the image size between the alloy elements. Elements without a
+    /// preferred size evenly share whatever is left.
+    pub fn element_sizes(&self) -> Result<Vec<u64>> {
+        let total = Byte::parse_str(&self.size, true)?.as_u64();
+        let preferred: u64 = self.alloy.iter().map(|element| element.size(0)).sum();
+        let flexible = self
+            .alloy
+            .iter()
+            .filter(|element| element.pref_size.is_none())
+            .count() as u64;
+
+        // Round down to whole MiB
+        let share = match flexible {
+            0 => 0,
+            flexible => (total.saturating_sub(preferred) / flexible) & !0xfffff,
+        };
+
+        Ok(self
+            .alloy
+            .iter()
+            .map(|element| element.size(share))
+            .collect())
+    }
+
+    /// Load anything the config refers to by a path relative to the given
+    /// directory.
+    pub fn resolve(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        self.dir = dir.as_ref().to_path_buf();
+        for element in &mut self.alloy {
+            if let ImageMold::Scripted(mold) = &mut element.mold {
+                mold.resolve(&self.dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the cache that workers store their layers in.
+    fn cache(&self, tmp: &Path) -> Result<BuildCache> {
+        if !self.no_cache {
+            return BuildCache::default();
+        }
+
+        // Checkpoints are only kept across casts when resuming. Otherwise they
+        // go away with the worker's directory (unless it's kept on failure).
+        if !self.resume {
+            return BuildCache::new(tmp.join("checkpoints"));
+        }
+
+        match &self.checkpoints {
+            Some(checkpoints) => BuildCache::new(checkpoints),
+            // Checkpoints in the worker's directory would be deleted with it
+            None => bail!("A checkpoint directory is required when caching is disabled"),
+        }
+    }
+
+    fn new_worker(
+        &self,
+        element: ImageElement,
+        size: u64,
+        dashboard: Option<&Arc<Dashboard>>,
+    ) -> Result<FoundryWorker> {
         // Obtain a temporary directory for the worker
         let tmp = tempfile::tempdir().unwrap();
 
         // Unpack included firmware if one isn't given
-        let ovmf_path = if let Some(path) = self.ovmf_path.clone() {
-            PathBuf::from(path)
-        } else if let Some(path) = crate::foundry::ovmf::find() {
-            path
-        } else if cfg!(feature = "include_ovmf") {
-            let path = tmp.path().join("OVMF.fd").to_string_lossy().to_string();
-
-            #[cfg(feature = "include_ovmf")]
-            crate::foundry::ovmf::write(self.arch, &path).unwrap();
-            PathBuf::from(path)
+        let ovmf_path = match self.ovmf_path.clone() {
+            Some(path) => PathBuf::from(path),
+            None => crate::foundry::ovmf::prepare(self.arch, tmp.path())?,
+        };
+
+        // Debug workers only share the fixed port when they run one at a time
+        let vnc_port = if self.debug && dashboard.is_none() {
+            5900
         } else {
-            panic!("No OVMF firmware found");
+            rand::thread_rng().gen_range(5900..5999)
         };
 
-        FoundryWorker {
+        Ok(FoundryWorker {
             arch: self.arch,
+            cache: self.cache(tmp.path())?,
+            dashboard: dashboard.map(|d| d.register(&element.mold.to_string(), vnc_port)),
             debug: self.debug,
+            dir: self.dir.clone(),
             record: self.record,
             end_time: None,
+            failed_vm: FailedVmSlot::default(),
             memory: self.memory.clone().unwrap_or(String::from("4G")),
+            on_failure: self.on_failure,
             ovmf_path,
             qcow_path: tmp.path().join("image.gb.qcow2"),
-            qcow_size: element.size(self.size.clone()),
+            qcow_size: size,
+            screenshots: self
+                .screenshots
+                .clone()
+                .unwrap_or_else(|| tmp.path().join("screenshots")),
             start_time: None,
             tmp,
-            vnc_port: if self.debug {
-                5900
-            } else {
-                rand::thread_rng().gen_range(5900..5999)
-            },
+            vnc_port,
+            wait_timeout: self.wait_timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT),
             element,
+        })
+    }
+
+    /// Hold the VM of a failed worker and keep its temporary directory so
+    /// they can be inspected.
+    fn fail(&self, worker: FoundryWorker, err: anyhow::Error) -> anyhow::Error {
+        if let Some(on_failure) = self.on_failure {
+            let vm = worker.failed_vm.lock().unwrap().take();
+            if let Some(mut vm) = vm {
+                error!(error = %err, "Build failed");
+                if let Err(err) = vm.hold(on_failure) {
+                    warn!(error = %err, "Failed to keep the VM for debugging");
+                }
+            }
         }
+        if self.keep_failed {
+            let path = worker.tmp.into_path();
+            error!(path = ?path, "Kept failed worker state");
+        }
+        err
     }
 
     /// Run the entire build process. If no output file is given, the image is
     /// moved into the image library.
     pub fn run(&mut self, output: Option<String>) -> Result<()> {
+        let mut report = BuildReport::new(&self.name, self.arch);
+        let start_time = SystemTime::now();
+
+        let result = self.cast(output, &mut report);
+
+        report.duration = start_time.elapsed()?.as_secs_f64();
+        report.success = result.is_ok();
+        report.error = result.as_ref().err().map(|err| err.to_string());
+        if let Some(path) = &self.report {
+            report.write(path)?;
+        }
+
+        result
+    }
+
+    fn cast(&mut self, output: Option<String>, report: &mut BuildReport) -> Result<()> {
         // Track the workers
         let mut workers = Vec::new();
 
-        // If we're in debug mode, run workers sequentially
-        if self.debug {
-            for element in self.alloy.clone().into_iter() {
-                let mut worker = self.new_worker(element);
-                worker.run()?;
+        let sizes = self.element_sizes()?;
+
+        // Cached casts always continue from the last unchanged step
+        if self.resume && !self.no_cache {
+            bail!("Resuming only applies to casts without the build cache (--no-cache)");
+        }
+
+        // Start over unless resuming a previous run
+        if let Some(checkpoints) = &self.checkpoints {
+            if self.no_cache && !self.resume && checkpoints.exists() {
+                std::fs::remove_dir_all(checkpoints)?;
+            }
+        }
+
+        if !self.no_cache {
+            BuildCache::default()?.evict()?;
+        }
+
+        let dashboard = self.dashboard.map(Dashboard::serve).transpose()?;
+
+        // If we're debugging in the terminal or might hold a failed VM, run
+        // workers sequentially
+        if (self.debug && dashboard.is_none()) || self.on_failure.is_some() {
+            for (element, size) in self.alloy.clone().into_iter().zip(sizes) {
+                let mut worker = self.new_worker(element, size, dashboard.as_ref())?;
+                let result = worker.run();
+                if let Some(handle) = &worker.dashboard {
+                    handle.finish(&result);
+                }
+                if let Err(err) = result {
+                    return Err(self.fail(worker, err));
+                }
                 workers.push(worker);
             }
         }
@@ -172,52 +387,118 @@ impl Foundry {
         else {
             let mut handles = Vec::new();
 
-            for element in self.alloy.clone().into_iter() {
-                let mut worker = self.new_worker(element);
+            for (element, size) in self.alloy.clone().into_iter().zip(sizes) {
+                let mut worker = self.new_worker(element, size, dashboard.as_ref())?;
                 handles.push(thread::spawn(move || {
-                    worker.run().unwrap();
-                    worker
+                    let result = worker.run();
+                    if let Some(handle) = &worker.dashboard {
+                        handle.finish(&result);
+                    }
+                    (worker, result)
                 }));
             }
 
             // Wait for each build to complete
+            let mut failure = None;
             for handle in handles {
-                workers.push(handle.join().unwrap());
+                match handle.join().unwrap() {
+                    (worker, Ok(())) => workers.push(worker),
+                    (worker, Err(err)) => failure = Some(self.fail(worker, err)),
+                }
+            }
+            if let Some(err) = failure {
+                return Err(err);
             }
         }
 
-        let final_qcow = if workers.len() > 1 {
-            // Allocate a temporary image if we need to merge
-            // TODO
-            Qcow3::open(&workers[0].qcow_path)?
+        for worker in &workers {
+            report.elements.push(ElementReport {
+                mold: worker.element.mold.to_string(),
+                duration: worker
+                    .end_time
+                    .and_then(|end| end.duration_since(worker.start_time?).ok())
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+            });
+        }
+
+        let final_path = if workers.len() > 1 {
+            info!("Merging {} alloy elements", workers.len());
+
+            let mut elements = Vec::new();
+            for worker in &workers {
+                let path = worker.tmp.path().join("image.raw");
+                qemu::convert(&worker.qcow_path, &path, "raw")?;
+                elements.push(alloy::AlloyElement {
+                    title: worker.element.mold.to_string(),
+                    path,
+                });
+            }
+
+            let raw_path = workers[0].tmp.path().join("alloy.raw");
+            let qcow_path = workers[0].tmp.path().join("alloy.gb.qcow2");
+            alloy::merge(
+                &elements,
+                Byte::parse_str(&self.size, true)?.as_u64(),
+                &raw_path,
+            )?;
+            qemu::convert(&raw_path, &qcow_path, "qcow2")?;
+
+            // The intermediate raw images aren't needed anymore
+            for element in elements {
+                std::fs::remove_file(element.path)?;
+            }
+            std::fs::remove_file(raw_path)?;
+
+            qcow_path
         } else {
-            Qcow3::open(&workers[0].qcow_path)?
+            workers[0].qcow_path.clone()
         };
 
         // Convert into final immutable image
-        if let Some(output) = output {
-            ImageHandle::convert(
-                &final_qcow,
-                self.name.clone(),
-                ron::ser::to_string_pretty(&self, PrettyConfig::new())?.into_bytes(),
-                self.password.clone(),
-                self.public,
-                output,
-                ProgressBar::Convert.new_empty(),
-            )?;
-        } else {
-            let tmp = ImageLibrary::open().temporary();
-            ImageHandle::convert(
-                &final_qcow,
-                self.name.clone(),
-                ron::ser::to_string_pretty(&self, PrettyConfig::new())?.into_bytes(),
-                self.password.clone(),
-                self.public,
-                &tmp,
-                ProgressBar::Convert.new_empty(),
-            )?;
+        let dest = match &output {
+            Some(output) => PathBuf::from(output),
+            None => ImageLibrary::open().temporary(),
+        };
+        let image = ImageHandle::convert(
+            &Qcow3::open(&final_path)?,
+            self.name.clone(),
+            ron::ser::to_string_pretty(&self, PrettyConfig::new())?.into_bytes(),
+            self.password.clone(),
+            self.public,
+            &dest,
+            ProgressBar::Convert.new_empty(),
+        )?;
+        report.image_id = Some(image.id);
+
+        // Make sure the image actually boots before keeping it
+        if let Some(tests) = &self.tests {
+            let passed = match tests.run(&workers[0], &final_path) {
+                Ok(results) => {
+                    report.tests = results;
+                    report.tests.iter().all(|result| result.passed)
+                }
+                Err(err) => {
+                    std::fs::remove_file(&dest)?;
+                    return Err(err);
+                }
+            };
+
+            if !passed {
+                std::fs::remove_file(&dest)?;
+                bail!("Smoke tests failed");
+            }
+        }
+
+        if output.is_none() {
+            ImageLibrary::open().add_move(dest)?;
+        }
 
-            ImageLibrary::open().add_move(tmp)?;
+        // Checkpoints aren't needed after a successful run
+        if let Some(checkpoints) = &self.checkpoints {
+            if self.no_cache && checkpoints.exists() {
+                std::fs::remove_dir_all(checkpoints)?;
+            }
         }
 
         Ok(())
@@ -229,8 +510,17 @@ impl Foundry {
 pub struct FoundryWorker {
     pub arch: ImageArch,
 
+    /// Where layers are stored after each step
+    pub cache: BuildCache,
+
+    /// Where progress is reported when the dashboard is running
+    pub dashboard: Option<DashboardHandle>,
+
     pub debug: bool,
 
+    /// The directory that relative paths in the element are resolved against
+    pub dir: PathBuf,
+
     pub record: bool,
 
     pub element: ImageElement,
@@ -238,14 +528,23 @@ pub struct FoundryWorker {
     /// The end time of the run
     pub end_time: Option<SystemTime>,
 
+    /// The VM of the build if it failed and should be kept
+    pub failed_vm: FailedVmSlot,
+
     pub memory: String,
 
+    /// What to do with the VM when the build fails
+    pub on_failure: Option<OnFailure>,
+
     /// The path to the intermediate image artifact
     pub qcow_path: PathBuf,
 
     /// The size of the intermediate image in bytes
     pub qcow_size: u64,
 
+    /// Where screenshots are saved when a wait times out
+    pub screenshots: PathBuf,
+
     /// The start time of the run
     pub start_time: Option<SystemTime>,
 
@@ -255,16 +554,32 @@ pub struct FoundryWorker {
     /// The VM port for VNC
     pub vnc_port: u16,
 
+    /// The default amount of seconds that screen waits can take
+    pub wait_timeout: u64,
+
     pub ovmf_path: PathBuf,
 }
 
 impl FoundryWorker {
+    /// Get the boot sequence to run after applying the element's changes to
+    /// the mold's default. The variables can be used in the changes.
+    pub fn boot_command(
+        &self,
+        sequence: Vec<Vec<VncCmd>>,
+        vars: &[(&str, &str)],
+    ) -> Result<Vec<Vec<VncCmd>>> {
+        match &self.element.boot {
+            Some(boot) => boot.apply(sequence, vars, &self.dir),
+            None => Ok(sequence),
+        }
+    }
+
     /// Run the image casting/building process.
     pub fn run(&mut self) -> Result<()> {
         self.start_time = Some(SystemTime::now());
-        Qcow3::create(&self.qcow_path, self.qcow_size)?;
 
-        self.element.mold.cast(&self)?;
+        self.run_steps()?;
+
         info!(
             "Build completed in: {:?}",
             self.start_time.unwrap().elapsed()?
@@ -273,6 +588,71 @@ impl FoundryWorker {
 
         Ok(())
     }
+
+    /// Run the build one step at a time, storing each step as a layer in the
+    /// cache. Steps with a cached layer are skipped.
+    fn run_steps(&self) -> Result<()> {
+        let cache = &self.cache;
+        let keys = BuildCache::keys(&self.element, self.arch, self.qcow_size)?;
+        let fabricators = self.element.fabricators.clone().unwrap_or_default();
+
+        // Layers are only usable if everything below them is too
+        let cached = keys.iter().take_while(|key| cache.contains(key)).count();
+        for key in &keys[..cached] {
+            cache.touch(key)?;
+        }
+
+        // Layers above a rebuilt one were made on top of a different disk
+        for key in &keys[cached..] {
+            cache.remove(key)?;
+        }
+
+        if cached == 0 {
+            self.install()?;
+            cache.store(&self.qcow_path, &keys[0])?;
+        } else {
+            info!(
+                steps = cached,
+                total = keys.len(),
+                "Resuming from cached build"
+            );
+        }
+
+        for step in cached.max(1)..keys.len() {
+            cache.overlay(&keys[step - 1], &self.qcow_path)?;
+            self.fabricate(&fabricators[step - 1])?;
+            cache.store(&self.qcow_path, &keys[step])?;
+        }
+
+        // Flatten the final layer into a standalone disk
+        qemu::convert(cache.layer(keys.last().unwrap()), &self.qcow_path, "qcow2")
+    }
+
+    /// Perform the base install of the element onto a new disk.
+    fn install(&self) -> Result<()> {
+        match &self.element.source {
+            // Layered builds start from the base image instead
+            ImageSource::Mold { base } => {
+                sources::mold::write_base(base, &self.qcow_path, self.qcow_size)
+            }
+            _ => {
+                Qcow3::create(&self.qcow_path, self.qcow_size)?;
+                self.element.mold.cast(self)
+            }
+        }
+    }
+
+    /// Boot the installed disk and run the given fabricator.
+    fn fabricate(&self, fabricator: &Fabricator) -> Result<()> {
+        let (mut qemu, mut ssh) = self.element.mold.boot(self)?;
+
+        info!("Running fabricator");
+        fabricator.run(&mut ssh)?;
+
+        ssh.shutdown("poweroff")?;
+        qemu.shutdown_wait()?;
+        Ok(())
+    }
 }
 
 /// Represents a foundry configuration file. This mainly helps sort out the various
@@ -335,14 +715,28 @@ impl FoundryConfigPath {
         }
     }
 
+    /// Get the path to the configuration file.
+    pub fn path(&self) -> &Path {
+        match self {
+            FoundryConfigPath::Json(path) => path,
+            FoundryConfigPath::Ron(path) => path,
+            FoundryConfigPath::Toml(path) => path,
+            FoundryConfigPath::Yaml(path) => path,
+        }
+    }
+
     /// Read the configuration file into a new [`Foundry`].
     pub fn load(&self) -> Result<Foundry> {
-        Ok(match &self {
+        let mut foundry: Foundry = match &self {
             Self::Json(path) => serde_json::from_slice(&std::fs::read(path)?)?,
             Self::Ron(path) => ron::de::from_bytes(&std::fs::read(path)?)?,
             Self::Toml(path) => toml::from_str(String::from_utf8(std::fs::read(path)?)?.as_str())?,
             Self::Yaml(path) => serde_yaml::from_slice(&std::fs::read(path)?)?,
-        })
+        };
+
+        // Paths in the config are relative to it rather than the working directory
+        foundry.resolve(self.path().parent().unwrap_or(Path::new(".")))?;
+        Ok(foundry)
     }
 
     /// Write a [`Foundry`] to a configuration file.
@@ -362,13 +756,6 @@ impl FoundryConfigPath {
 
 impl Display for FoundryConfigPath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let path = match self {
-            FoundryConfigPath::Json(path) => path,
-            FoundryConfigPath::Ron(path) => path,
-            FoundryConfigPath::Toml(path) => path,
-            FoundryConfigPath::Yaml(path) => path,
-        }
-        .to_string_lossy();
-        path.fmt(f)
+        self.path().to_string_lossy().fmt(f)
     }
 }
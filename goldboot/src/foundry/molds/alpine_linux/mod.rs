@@ -8,18 +8,22 @@ use validator::Validate;
 
 use crate::{
     cli::prompt::{Prompt, PromptNew},
-    enter,
+    enter, expect,
     foundry::{
         options::{hostname::Hostname, unix_account::RootPassword},
-        qemu::{OsCategory, QemuBuilder},
+        qemu::{OsCategory, QemuBuilder, QemuProcess},
         sources::ImageSource,
+        ssh::SshConnection,
         Foundry, FoundryWorker,
     },
-    wait, wait_screen_rect,
+    send_line, wait,
 };
 
 use super::{CastImage, DefaultSource};
 
+/// How many seconds `setup-alpine` can take to complete.
+const INSTALL_TIMEOUT: u64 = 3600;
+
 /// Produces [Alpine Linux](https://www.alpinelinux.org) images.
 #[derive(Clone, Serialize, Deserialize, Validate, Debug, Default)]
 pub struct AlpineLinux {
@@ -49,22 +53,25 @@ impl Prompt for AlpineLinux {
 
 impl CastImage for AlpineLinux {
     fn cast(&self, worker: &FoundryWorker) -> Result<()> {
+        let root_password = self.root_password.plaintext()?;
+
         let mut qemu = QemuBuilder::new(&worker, OsCategory::Linux)
             .source(&worker.element.source)?
+            .serial()
             .prepare_ssh()?
             .start()?;
 
-        // Send boot command
+        // Send boot command over the serial console of the live system
         #[rustfmt::skip]
-		qemu.vnc.run(vec![
-			// Initial wait
-			wait!(30),
+		qemu.vnc.run(worker.boot_command(vec![
 			// Root login
-			enter!("root"),
+			expect!(r"login: $"),
+			send_line!("root"),
+			expect!(r"# $"),
 			// Configure install
-			enter!("export KEYMAPOPTS='us us'"),
-			enter!(format!("export HOSTNAMEOPTS='-n {}'", self.hostname.hostname)),
-			enter!("export INTERFACESOPTS='
+			send_line!("export KEYMAPOPTS='us us'"),
+			send_line!(format!("export HOSTNAMEOPTS='-n {}'", self.hostname.hostname)),
+			send_line!("export INTERFACESOPTS='
 auto lo
 iface lo inet loopback
 
@@ -72,21 +79,21 @@ auto eth0
 iface eth0 inet dhcp
     hostname alpine-test'"
 			),
-			enter!("export DNSOPTS='1.1.1.1'"),
-			enter!("export TIMEZONEOPTS='-z UTC'"),
-			enter!("export PROXYOPTS='none'"),
-			enter!("export APKREPOSOPTS='-r'"),
-			enter!("export SSHDOPTS='-c openssh'"),
-			enter!("export NTPOPTS='-c openntpd'"),
-			enter!("export DISKOPTS='-m sys /dev/vda'"),
+			send_line!("export DNSOPTS='1.1.1.1'"),
+			send_line!("export TIMEZONEOPTS='-z UTC'"),
+			send_line!("export PROXYOPTS='none'"),
+			send_line!("export APKREPOSOPTS='-r'"),
+			send_line!("export SSHDOPTS='-c openssh'"),
+			send_line!("export NTPOPTS='-c openntpd'"),
+			send_line!("export DISKOPTS='-m sys /dev/vda'"),
 			// Start install
-			enter!("echo -e 'root\nroot\ny' | setup-alpine"),
-			wait_screen_rect!("6d7b9fc9229c4f4ae8bc84f0925d8479ccd3e7d2", 668, 0, 1024, 100),
+			send_line!(format!("echo -e '{root_password}\n{root_password}\ny' | setup-alpine")),
+			expect!("Installation is complete", INSTALL_TIMEOUT),
 			// Remount root partition
-			enter!("mount -t ext4 /dev/vda3 /mnt"),
+			send_line!("mount -t ext4 /dev/vda3 /mnt"),
 			// Reboot into installation
-			enter!("apk add efibootmgr; efibootmgr -n 0003; reboot"),
-		])?;
+			send_line!("apk add efibootmgr; efibootmgr -n 0003; reboot"),
+		], &[])?)?;
 
         // Wait for SSH
         let mut ssh = qemu.ssh("root")?;
@@ -99,6 +106,24 @@ iface eth0 inet dhcp
         qemu.shutdown_wait()?;
         Ok(())
     }
+
+    fn boot(&self, worker: &FoundryWorker) -> Result<(QemuProcess, SshConnection)> {
+        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+            .boot("c")
+            .prepare_ssh()?
+            .start()?;
+
+        #[rustfmt::skip]
+        qemu.vnc.run(vec![
+            // Wait for login prompt
+            wait!(30),
+            enter!("root"),
+            enter!(self.root_password.plaintext()?),
+        ])?;
+
+        let ssh = qemu.ssh("root")?;
+        Ok((qemu, ssh))
+    }
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, EnumIter, Display, Default)]
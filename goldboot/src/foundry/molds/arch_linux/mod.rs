@@ -1,15 +1,15 @@
 use super::{CastImage, DefaultSource};
 use crate::cli::prompt::Prompt;
 use crate::cli::prompt::PromptNew;
-use crate::foundry::fabricators::Fabricate;
 use crate::foundry::http::HttpServer;
 use crate::foundry::molds::arch_linux::archinstall::ArchinstallConfig;
 use crate::foundry::molds::arch_linux::archinstall::ArchinstallCredentials;
 use crate::foundry::options::hostname::Hostname;
 use crate::foundry::options::unix_account::RootPassword;
-use crate::foundry::qemu::{OsCategory, QemuBuilder};
+use crate::foundry::qemu::{OsCategory, QemuBuilder, QemuProcess};
+use crate::foundry::ssh::SshConnection;
 use crate::foundry::Foundry;
-use crate::wait;
+use crate::{enter, wait};
 use crate::{
     foundry::{sources::ImageSource, FoundryWorker},
     wait_screen_rect,
@@ -74,12 +74,12 @@ impl CastImage for ArchLinux {
 
         // Send boot command
         #[rustfmt::skip]
-		qemu.vnc.run(vec![
+		qemu.vnc.run(worker.boot_command(vec![
 			// Initial wait
 			wait!(30),
 			// Wait for login
 			wait_screen_rect!("5b3ca88689e9d671903b3040889c7fa1cb5f244a", 100, 0, 1024, 400),
-		])?;
+		], &[("http_address", &http.address), ("http_port", &http.port.to_string())])?)?;
 
         // Wait for SSH
         let mut ssh = qemu.ssh("root")?;
@@ -97,18 +97,29 @@ impl CastImage for ArchLinux {
             _ => bail!("Installation failed"),
         }
 
-        // Run remaining fabricators
-        if let Some(fabricators) = &worker.element.fabricators {
-            for fabricator in fabricators {
-                fabricator.run(&mut ssh)?;
-            }
-        }
-
         // Shutdown
         ssh.shutdown("poweroff")?;
         qemu.shutdown_wait()?;
         Ok(())
     }
+
+    fn boot(&self, worker: &FoundryWorker) -> Result<(QemuProcess, SshConnection)> {
+        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+            .boot("c")
+            .prepare_ssh()?
+            .start()?;
+
+        #[rustfmt::skip]
+        qemu.vnc.run(vec![
+            // Wait for login prompt
+            wait!(30),
+            enter!("root"),
+            enter!(self.root_password.plaintext()?),
+        ])?;
+
+        let ssh = qemu.ssh("root")?;
+        Ok((qemu, ssh))
+    }
 }
 
 /// This provisioner configures the Archlinux mirror list.
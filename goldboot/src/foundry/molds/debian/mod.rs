@@ -11,8 +11,9 @@ use crate::{
     foundry::{
         http::HttpServer,
         options::{hostname::Hostname, unix_account::RootPassword},
-        qemu::{OsCategory, QemuBuilder},
+        qemu::{OsCategory, QemuBuilder, QemuProcess},
         sources::ImageSource,
+        ssh::SshConnection,
         Foundry, FoundryWorker,
     },
     input, wait, wait_screen, wait_screen_rect,
@@ -20,6 +21,10 @@ use crate::{
 
 use super::{CastImage, DefaultSource};
 
+/// How many seconds the unattended install can take to reach the login prompt
+/// of the installed system.
+const INSTALL_TIMEOUT: u64 = 7200;
+
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub enum DebianEdition {
     Bullseye,
@@ -64,8 +69,21 @@ pub fn fetch_debian_iso(edition: DebianEdition, arch: ImageArch) -> Result<Image
     bail!("Failed to request latest ISO");
 }
 
+/// Only some editions have boot sequences so far.
+fn validate_edition(edition: &DebianEdition) -> Result<(), validator::ValidationError> {
+    match edition {
+        DebianEdition::Bookworm => Ok(()),
+        _ => {
+            let mut error = validator::ValidationError::new("edition");
+            error.message = Some(format!("{:?} is not supported yet", edition).into());
+            Err(error)
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Validate, Debug)]
 pub struct Debian {
+    #[validate(custom(function = "validate_edition"))]
     pub edition: DebianEdition,
 
     #[serde(flatten)]
@@ -96,52 +114,89 @@ impl DefaultSource for Debian {
     }
 }
 
-impl CastImage for Debian {
-    fn cast(&self, worker: &FoundryWorker) -> Result<()> {
+impl Debian {
+    /// Run the installer and return the VM with an SSH connection to the
+    /// installed system. Other molds can use this to build on top of Debian.
+    pub fn install(&self, worker: &FoundryWorker) -> Result<(QemuProcess, SshConnection)> {
+        self.validate()?;
+
         let mut qemu = QemuBuilder::new(&worker, OsCategory::Linux)
             .vga("cirrus")
             .source(&worker.element.source)?
             .prepare_ssh()?
             .start()?;
 
+        let root_password = self.root_password.plaintext()?;
+
         // Start HTTP
         let http = HttpServer::new()?
-            .file("preseed.cfg", include_bytes!("preseed.cfg"))?
+            .file(
+                "preseed.cfg",
+                include_str!("preseed.cfg").replace("{root_password}", &root_password),
+            )?
             .serve();
 
         // Send boot command
         #[rustfmt::skip]
-		qemu.vnc.run(vec![
+		qemu.vnc.run(worker.boot_command(vec![
             // Wait for boot
 			wait_screen_rect!("f6852e8b6e072d15270b2b215bbada3da30fd733", 100, 100, 400, 400),
             // Trigger unattended install
 			input!("aa"),
             // Wait for preseed URL to be prompted
             match self.edition {
-                DebianEdition::Bullseye => todo!(),
                 DebianEdition::Bookworm => wait_screen!("6ee7873098bceb5a2124db82dae6abdae214ce7e"),
-                DebianEdition::Trixie => todo!(),
-                DebianEdition::Sid => todo!(),
+                _ => bail!("{:?} is not supported yet", self.edition),
             },
 			enter!(format!("http://{}:{}/preseed.cfg", http.address, http.port)),
             // Wait for login prompt
             match self.edition {
-                DebianEdition::Bullseye => todo!(),
-                DebianEdition::Bookworm => wait_screen!("2eb1ef517849c86a322ba60bb05386decbf00ba5"),
-                DebianEdition::Trixie => todo!(),
-                DebianEdition::Sid => todo!(),
+                DebianEdition::Bookworm => wait_screen!("2eb1ef517849c86a322ba60bb05386decbf00ba5", INSTALL_TIMEOUT),
+                _ => bail!("{:?} is not supported yet", self.edition),
             },
             // Login as root
             enter!("root"),
-            enter!("r00tme"),
-		])?;
+            enter!(root_password),
+		], &[("http_address", &http.address), ("http_port", &http.port.to_string())])?)?;
 
         // Wait for SSH
-        let mut ssh = qemu.ssh("root")?;
+        let ssh = qemu.ssh("root")?;
+        Ok((qemu, ssh))
+    }
+}
+
+impl CastImage for Debian {
+    fn cast(&self, worker: &FoundryWorker) -> Result<()> {
+        let (mut qemu, ssh) = self.install(worker)?;
 
         // Shutdown
         ssh.shutdown("poweroff")?;
         qemu.shutdown_wait()?;
         Ok(())
     }
+
+    fn boot(&self, worker: &FoundryWorker) -> Result<(QemuProcess, SshConnection)> {
+        self.validate()?;
+
+        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+            .vga("cirrus")
+            .boot("c")
+            .prepare_ssh()?
+            .start()?;
+
+        #[rustfmt::skip]
+        qemu.vnc.run(vec![
+            // Wait for login prompt
+            match self.edition {
+                DebianEdition::Bookworm => wait_screen!("2eb1ef517849c86a322ba60bb05386decbf00ba5"),
+                _ => bail!("{:?} is not supported yet", self.edition),
+            },
+            // Login as root
+            enter!("root"),
+            enter!(self.root_password.plaintext()?),
+        ])?;
+
+        let ssh = qemu.ssh("root")?;
+        Ok((qemu, ssh))
+    }
 }
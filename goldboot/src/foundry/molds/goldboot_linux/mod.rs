@@ -0,0 +1,128 @@
+use anyhow::{bail, Result};
+use dialoguer::theme::Theme;
+use goldboot_image::ImageArch;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use validator::Validate;
+
+use crate::{
+    cli::prompt::Prompt,
+    enter,
+    foundry::{
+        options::unix_account::RootPassword,
+        qemu::{OsCategory, QemuBuilder, QemuProcess},
+        sources::ImageSource,
+        ssh::SshConnection,
+        Foundry, FoundryWorker,
+    },
+    gbl, keys, wait,
+};
+
+use super::{debian::Debian, CastImage, DefaultSource};
+
+/// Produces goldboot Linux (GBL) images: a minimal Debian system that boots
+/// directly into the goldboot GUI. These images are the base of GBL media (see
+/// `goldboot write --gbl`).
+#[derive(Clone, Serialize, Deserialize, Validate, Debug, Default)]
+pub struct GoldbootLinux {
+    /// The goldboot executable to embed (defaults to the running executable
+    /// if it has the GUI). It must be built with the "gui" feature.
+    pub executable: Option<String>,
+
+    /// The root password. If not given, the root account is locked since the
+    /// GUI doesn't need it.
+    pub root_password: Option<RootPassword>,
+}
+
+impl GoldbootLinux {
+    /// The Debian mold that installs the base system.
+    fn debian(&self) -> Result<Debian> {
+        Ok(Debian {
+            // Only needed during the build if the account will be locked
+            root_password: match &self.root_password {
+                Some(root_password) => root_password.clone(),
+                None => RootPassword::Plaintext(crate::random_password()),
+            },
+            ..Debian::default()
+        })
+    }
+}
+
+// TODO proc macro
+impl Prompt for GoldbootLinux {
+    fn prompt(&mut self, _foundry: &Foundry, _theme: Box<dyn Theme>) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl DefaultSource for GoldbootLinux {
+    fn default_source(&self, arch: ImageArch) -> Result<ImageSource> {
+        Debian::default().default_source(arch)
+    }
+}
+
+impl CastImage for GoldbootLinux {
+    fn cast(&self, worker: &FoundryWorker) -> Result<()> {
+        let executable = match &self.executable {
+            Some(path) => std::fs::read(path)?,
+            // Without the GUI, the kiosk service would fail on every boot
+            None if !cfg!(feature = "gui") => bail!(
+                "This goldboot wasn't built with the \"gui\" feature, so an executable that was must be given"
+            ),
+            None => std::fs::read(std::env::current_exe()?)?,
+        };
+
+        info!("Starting {} build", console::style("goldboot Linux").blue());
+        let (mut qemu, mut ssh) = self.debian()?.install(worker)?;
+
+        ssh.upload(&executable, "/usr/bin/goldboot")?;
+
+        if ssh.upload_exec(
+            include_bytes!("setup.sh"),
+            vec![
+                ("GOLDBOOT_DATA_LABEL", gbl::DATA_LABEL),
+                (
+                    "GOLDBOOT_LOCK_ROOT",
+                    if self.root_password.is_none() {
+                        "1"
+                    } else {
+                        "0"
+                    },
+                ),
+            ],
+        )? != 0
+        {
+            bail!("Failed to configure goldboot Linux");
+        }
+
+        // Shutdown
+        ssh.shutdown("poweroff")?;
+        qemu.shutdown_wait()?;
+        Ok(())
+    }
+
+    fn boot(&self, worker: &FoundryWorker) -> Result<(QemuProcess, SshConnection)> {
+        let Some(root_password) = &self.root_password else {
+            bail!("The root account is locked unless a root password is configured");
+        };
+
+        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+            .vga("cirrus")
+            .boot("c")
+            .prepare_ssh()?
+            .start()?;
+
+        // The GUI takes the first console, so log in on the second
+        #[rustfmt::skip]
+        qemu.vnc.run(vec![
+            wait!(60),
+            keys!("ctrl+alt+F2"),
+            wait!(5),
+            enter!("root"),
+            enter!(root_password.plaintext()?),
+        ])?;
+
+        let ssh = qemu.ssh("root")?;
+        Ok((qemu, ssh))
+    }
+}
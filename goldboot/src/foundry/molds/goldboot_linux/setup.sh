@@ -0,0 +1,48 @@
+#!/bin/sh
+## Configure a minimal Debian install to boot directly into the goldboot GUI.
+set -e
+
+export DEBIAN_FRONTEND=noninteractive
+
+# Install a kiosk compositor and the GUI runtime dependencies
+apt-get update
+apt-get install -y --no-install-recommends cage libgtk-4-1 e2fsprogs util-linux
+
+# Mount the data partition (if present) at the image library location
+mkdir -p /var/lib/goldboot/images
+echo "LABEL=${GOLDBOOT_DATA_LABEL} /var/lib/goldboot/images ext4 defaults,nofail,x-systemd.device-timeout=5 0 2" >>/etc/fstab
+
+# Run the GUI fullscreen on the first console instead of a login prompt
+cat <<EOF >/etc/systemd/system/goldboot.service
+[Unit]
+Description=goldboot
+After=systemd-user-sessions.service local-fs.target
+Conflicts=getty@tty1.service
+
+[Service]
+ExecStart=/usr/bin/cage -s -- /usr/bin/goldboot --fullscreen
+Restart=always
+TTYPath=/dev/tty1
+StandardInput=tty
+StandardOutput=journal
+PAMName=login
+Environment=XDG_RUNTIME_DIR=/run/goldboot
+
+RuntimeDirectory=goldboot
+
+[Install]
+WantedBy=graphical.target
+EOF
+
+systemctl disable getty@tty1.service
+systemctl enable goldboot.service
+systemctl set-default graphical.target
+
+# Nobody needs to log in without a configured password
+if [ "${GOLDBOOT_LOCK_ROOT}" = "1" ]; then
+	passwd --lock root
+fi
+
+# Don't keep anything around that isn't needed
+apt-get clean
+rm -rf /var/lib/apt/lists/*
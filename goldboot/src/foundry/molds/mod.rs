@@ -1,4 +1,6 @@
+use super::qemu::QemuProcess;
 use super::sources::ImageSource;
+use super::ssh::SshConnection;
 use crate::cli::prompt::Prompt;
 use crate::foundry::Foundry;
 use crate::foundry::FoundryWorker;
@@ -14,10 +16,14 @@ use strum::{EnumIter, IntoEnumIterator};
 use alpine_linux::AlpineLinux;
 use arch_linux::ArchLinux;
 use debian::Debian;
+use goldboot_linux::GoldbootLinux;
+use scripted::Scripted;
 
 pub mod alpine_linux;
 pub mod arch_linux;
 pub mod debian;
+pub mod goldboot_linux;
+pub mod scripted;
 
 /// "Casting" is the process of generating an immutable goldboot image from raw
 /// configuration data.
@@ -28,6 +34,10 @@ pub mod debian;
 pub trait CastImage {
     /// Cast an image from the mold.
     fn cast(&self, context: &FoundryWorker) -> Result<()>;
+
+    /// Boot the worker's disk after the mold was cast onto it and log in so
+    /// fabricators can run.
+    fn boot(&self, context: &FoundryWorker) -> Result<(QemuProcess, SshConnection)>;
 }
 
 #[enum_dispatch(ImageMold)]
@@ -39,6 +49,7 @@ pub trait DefaultSource {
 /// images.
 #[enum_dispatch]
 #[derive(Clone, Serialize, Deserialize, Debug, EnumIter)]
+#[allow(clippy::large_enum_variant)]
 pub enum ImageMold {
     AlpineLinux,
     ArchLinux,
@@ -50,7 +61,7 @@ pub enum ImageMold {
     // Fedora,
     // FreeBsd,
     // Gentoo,
-    // GoldbootLinux,
+    GoldbootLinux,
     // Haiku,
     // Kali,
     // LinuxMint,
@@ -66,6 +77,7 @@ pub enum ImageMold {
     // Qubes,
     // RedHat,
     // RockyLinux,
+    Scripted,
     // Slackware,
     // SteamDeck,
     // SteamOs,
@@ -86,12 +98,38 @@ impl ImageMold {
             ImageMold::AlpineLinux(_) => vec![ImageArch::Amd64, ImageArch::Arm64],
             ImageMold::ArchLinux(_) => vec![ImageArch::Amd64],
             ImageMold::Debian(_) => vec![ImageArch::Amd64, ImageArch::Arm64],
+            ImageMold::GoldbootLinux(_) => vec![ImageArch::Amd64],
+            ImageMold::Scripted(mold) => mold.architectures(),
         }
     }
 
-    /// Whether the template can be combined with others in the same image
+    /// Whether the template can be combined with others in the same image. The
+    /// mold must install a UEFI bootloader on a GPT disk.
     pub fn alloy(&self) -> bool {
-        false
+        match self {
+            ImageMold::AlpineLinux(_) => false,
+            ImageMold::ArchLinux(_) => true,
+            ImageMold::Debian(_) => true,
+            ImageMold::GoldbootLinux(_) => false,
+            ImageMold::Scripted(mold) => mold.definition.alloy,
+        }
+    }
+
+    /// The root password of images cast from the mold. Layered builds use it
+    /// to log in to their base image.
+    pub fn root_password(&self) -> Result<Option<String>> {
+        Ok(match self {
+            ImageMold::AlpineLinux(mold) => Some(mold.root_password.plaintext()?),
+            ImageMold::ArchLinux(mold) => Some(mold.root_password.plaintext()?),
+            ImageMold::Debian(mold) => Some(mold.root_password.plaintext()?),
+            // The root account is locked without a password
+            ImageMold::GoldbootLinux(mold) => mold
+                .root_password
+                .as_ref()
+                .map(|root_password| root_password.plaintext())
+                .transpose()?,
+            ImageMold::Scripted(mold) => mold.definition.root_password.clone(),
+        })
     }
 
     // pub fn default_source
@@ -106,6 +144,8 @@ impl Display for ImageMold {
                 ImageMold::AlpineLinux(_) => "AlpineLinux",
                 ImageMold::ArchLinux(_) => "ArchLinux",
                 ImageMold::Debian(_) => "Debian",
+                ImageMold::GoldbootLinux(_) => "GoldbootLinux",
+                ImageMold::Scripted(_) => "Scripted",
             }
         )
     }
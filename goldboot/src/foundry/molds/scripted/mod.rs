@@ -0,0 +1,351 @@
+use anyhow::{anyhow, bail, Result};
+use dialoguer::theme::Theme;
+use goldboot_image::ImageArch;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info};
+
+use crate::{
+    cli::prompt::Prompt,
+    foundry::{
+        boot_command,
+        http::HttpServer,
+        qemu::{OsCategory, QemuBuilder, QemuProcess},
+        sources::ImageSource,
+        ssh::SshConnection,
+        Foundry, FoundryWorker,
+    },
+};
+
+use super::{CastImage, DefaultSource};
+
+/// Produces images from a mold that's defined in a RON or YAML file rather
+/// than in goldboot itself.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[serde(try_from = "ScriptedPath", into = "ScriptedPath")]
+pub struct Scripted {
+    /// The path to the definition as given in the config
+    pub path: String,
+
+    /// Where the definition was loaded from
+    pub file: PathBuf,
+
+    pub definition: MoldDefinition,
+}
+
+/// How a scripted mold appears in a foundry config.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+struct ScriptedPath {
+    path: String,
+}
+
+impl TryFrom<ScriptedPath> for Scripted {
+    type Error = anyhow::Error;
+
+    /// The definition is loaded once the config's directory is known.
+    fn try_from(value: ScriptedPath) -> Result<Self> {
+        Ok(Scripted {
+            path: value.path,
+            ..Default::default()
+        })
+    }
+}
+
+impl From<Scripted> for ScriptedPath {
+    fn from(value: Scripted) -> Self {
+        ScriptedPath { path: value.path }
+    }
+}
+
+/// The contents of a scripted mold's definition file.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct MoldDefinition {
+    pub name: String,
+
+    /// Supported system architectures (like "amd64")
+    pub architectures: Vec<String>,
+
+    /// The source used when the config doesn't give one
+    pub source: ImageSource,
+
+    #[serde(default)]
+    pub qemu: QemuOptions,
+
+    /// The boot sequence in the boot command syntax. The address of the HTTP
+    /// server is available as `${http_address}` and `${http_port}`.
+    #[serde(default)]
+    pub boot: Vec<String>,
+
+    /// Files served to the VM over HTTP by their URL path
+    #[serde(default)]
+    pub http: BTreeMap<String, String>,
+
+    /// The user to log in as over SSH after the boot sequence
+    #[serde(default = "default_ssh_user")]
+    pub ssh_user: String,
+
+    /// The root password of the installed system
+    pub root_password: Option<String>,
+
+    /// The sequence that logs in on the console of the installed system in the
+    /// boot command syntax. Fabricators can only be used if it's given.
+    #[serde(default)]
+    pub login: Vec<String>,
+
+    /// A script that's run over SSH to install the system
+    pub install: Option<String>,
+
+    /// The command that shuts down the installed system
+    #[serde(default = "default_shutdown")]
+    pub shutdown: String,
+
+    /// Whether the mold installs a UEFI bootloader on a GPT disk so it can be
+    /// combined with others
+    #[serde(default)]
+    pub alloy: bool,
+}
+
+fn default_ssh_user() -> String {
+    String::from("root")
+}
+
+fn default_shutdown() -> String {
+    String::from("poweroff")
+}
+
+/// Adjustments to the VM that the installer runs in.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct QemuOptions {
+    /// Override -vga
+    pub vga: Option<String>,
+
+    /// Add an absolute pointing device for clicks
+    #[serde(default)]
+    pub tablet: bool,
+
+    /// Attach a serial console for `<expect:...>` and `<serial:...>` steps
+    #[serde(default)]
+    pub serial: bool,
+}
+
+impl Scripted {
+    /// Load a mold definition from a RON or YAML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .map_err(|err| anyhow!("Failed to read mold definition {:?}: {}", path, err))?;
+
+        let definition: MoldDefinition = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_slice(&data)?,
+            Some("ron") => ron::de::from_bytes(&data)?,
+            _ => bail!("Mold definitions must be RON or YAML files"),
+        };
+
+        // Catch bad architectures before anything is built
+        for arch in &definition.architectures {
+            ImageArch::try_from(arch.clone())?;
+        }
+
+        Ok(Self {
+            path: path.to_string_lossy().to_string(),
+            file: path.to_path_buf(),
+            definition,
+        })
+    }
+
+    /// Load the definition that the config refers to relative to the config's
+    /// directory.
+    pub fn resolve(&mut self, dir: &Path) -> Result<()> {
+        let loaded = Scripted::load(dir.join(&self.path))?;
+        self.file = loaded.file;
+        self.definition = loaded.definition;
+        Ok(())
+    }
+
+    /// The directory that paths in the definition are relative to.
+    fn dir(&self) -> &Path {
+        self.file.parent().unwrap_or(Path::new("."))
+    }
+
+    /// The files that the mold reads during a cast.
+    pub fn inputs(&self) -> Vec<PathBuf> {
+        self.definition
+            .install
+            .iter()
+            .chain(self.definition.http.values())
+            .map(|path| self.dir().join(path))
+            .collect()
+    }
+
+    /// Create a VM with the adjustments from the definition.
+    fn qemu(&self, worker: &FoundryWorker) -> QemuBuilder {
+        let mut builder = QemuBuilder::new(worker, OsCategory::Linux);
+        if let Some(vga) = &self.definition.qemu.vga {
+            builder = builder.vga(vga);
+        }
+        if self.definition.qemu.tablet {
+            builder = builder.tablet();
+        }
+        if self.definition.qemu.serial {
+            builder = builder.serial();
+        }
+        builder
+    }
+
+    pub fn architectures(&self) -> Vec<ImageArch> {
+        self.definition
+            .architectures
+            .iter()
+            .filter_map(|arch| ImageArch::try_from(arch.clone()).ok())
+            .collect()
+    }
+}
+
+impl DefaultSource for Scripted {
+    fn default_source(&self, _: ImageArch) -> Result<ImageSource> {
+        if self.file.as_os_str().is_empty() {
+            bail!("Scripted molds need a definition file");
+        }
+        Ok(self.definition.source.clone())
+    }
+}
+
+// TODO proc macro
+impl Prompt for Scripted {
+    fn prompt(&mut self, _foundry: &Foundry, _theme: Box<dyn Theme>) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl CastImage for Scripted {
+    fn cast(&self, worker: &FoundryWorker) -> Result<()> {
+        let definition = &self.definition;
+        info!(mold = %definition.name, "Casting scripted mold");
+
+        let mut qemu = self
+            .qemu(worker)
+            .source(&worker.element.source)?
+            .prepare_ssh()?
+            .start()?;
+
+        // Start HTTP
+        let mut http = HttpServer::new()?;
+        for (path, file) in &definition.http {
+            http = http.file(path, std::fs::read(self.dir().join(file))?)?;
+        }
+        let http = http.serve();
+        let http_port = http.port.to_string();
+        let vars = [
+            ("http_address", http.address.as_str()),
+            ("http_port", http_port.as_str()),
+        ];
+
+        // Send boot command
+        let sequence = definition
+            .boot
+            .iter()
+            .map(|step| boot_command::parse(step, &vars, self.dir()))
+            .collect::<Result<Vec<_>>>()?;
+        qemu.vnc.run(worker.boot_command(sequence, &vars)?)?;
+
+        // Wait for SSH
+        let mut ssh = qemu.ssh(&definition.ssh_user)?;
+
+        // Run install script
+        if let Some(install) = &definition.install {
+            info!("Running installation script");
+            match ssh.upload_exec(
+                &std::fs::read(self.dir().join(install))?,
+                vec![
+                    ("GB_HTTP_HOST", &http.address),
+                    ("GB_HTTP_PORT", &http_port),
+                ],
+            ) {
+                Ok(0) => debug!("Installation completed successfully"),
+                _ => bail!("Installation failed"),
+            }
+        }
+
+        // Shutdown
+        ssh.shutdown(&definition.shutdown)?;
+        qemu.shutdown_wait()?;
+        Ok(())
+    }
+
+    fn boot(&self, worker: &FoundryWorker) -> Result<(QemuProcess, SshConnection)> {
+        let definition = &self.definition;
+        if definition.login.is_empty() {
+            bail!("{} doesn't have a login sequence", definition.name);
+        }
+
+        let mut qemu = self.qemu(worker).boot("c").prepare_ssh()?.start()?;
+
+        let sequence = definition
+            .login
+            .iter()
+            .map(|step| boot_command::parse(step, &[], self.dir()))
+            .collect::<Result<Vec<_>>>()?;
+        qemu.vnc.run(sequence)?;
+
+        let ssh = qemu.ssh(&definition.ssh_user)?;
+        Ok((qemu, ssh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::foundry::molds::ImageMold;
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("internal.yaml");
+        std::fs::write(
+            &path,
+            r#"
+name: Internal Linux
+architectures: [amd64]
+source: !Iso
+  url: http://example.com/internal.iso
+  checksum: null
+qemu:
+  vga: cirrus
+boot:
+  - "<wait30>root<enter>"
+  - "curl http://${http_address}:${http_port}/install.sh | sh<enter>"
+http:
+  install.sh: install.sh
+install: setup.sh
+"#,
+        )?;
+
+        // Configs only refer to the definition, relative to the config
+        let mut mold: ImageMold = ron::from_str("Scripted((path: \"internal.yaml\"))")?;
+        if let ImageMold::Scripted(scripted) = &mut mold {
+            scripted.resolve(tmp.path())?;
+        }
+        assert_eq!(mold.architectures(), vec![ImageArch::Amd64]);
+        assert!(!mold.alloy());
+        assert_eq!(ron::to_string(&mold)?, "Scripted((path:\"internal.yaml\"))");
+
+        if let ImageMold::Scripted(scripted) = mold {
+            assert_eq!(scripted.definition.ssh_user, "root");
+            assert_eq!(scripted.definition.shutdown, "poweroff");
+            assert_eq!(
+                scripted.inputs(),
+                vec![tmp.path().join("setup.sh"), tmp.path().join("install.sh")]
+            );
+        }
+
+        std::fs::write(
+            &path,
+            "name: Bad\narchitectures: [vax]\nsource: Buildroot\n",
+        )?;
+        assert!(Scripted::load(&path).is_err());
+        Ok(())
+    }
+}
@@ -4,7 +4,7 @@ use crate::{
     cli::prompt::{Prompt, PromptNew},
     foundry::Foundry,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use dialoguer::{theme::Theme, Password};
 use serde::{Deserialize, Serialize};
 use validator::Validate;
@@ -66,6 +66,17 @@ impl Default for RootPassword {
     }
 }
 
+impl RootPassword {
+    /// Get the password in plaintext.
+    pub fn plaintext(&self) -> Result<String> {
+        match &self {
+            RootPassword::Plaintext(password) => Ok(password.clone()),
+            RootPassword::PlaintextEnv(name) => std::env::var(name)
+                .map_err(|_| anyhow!("Root password environment variable not set: {}", name)),
+        }
+    }
+}
+
 impl PromptNew for RootPassword {
     fn prompt_new(_: &Foundry, theme: Box<dyn Theme>) -> Result<Self> {
         Ok(RootPassword::Plaintext(
@@ -83,10 +94,7 @@ impl Display for RootPassword {
             "{}",
             match &self {
                 RootPassword::Plaintext(password) => format!("plain:{password}"),
-                RootPassword::PlaintextEnv(name) => format!(
-                    "plain:{}",
-                    std::env::var(name).expect("environment variable not found")
-                ),
+                RootPassword::PlaintextEnv(name) => format!("env:{name}"),
             }
         )
     }
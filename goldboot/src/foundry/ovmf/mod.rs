@@ -38,3 +38,21 @@ pub fn find() -> Option<PathBuf> {
     // TODO
     None
 }
+
+/// Find firmware for the given architecture, unpacking the included firmware
+/// into the given directory if there's none on the system.
+pub fn prepare(arch: ImageArch, directory: &Path) -> Result<PathBuf> {
+    if let Some(path) = find() {
+        return Ok(path);
+    }
+
+    if cfg!(feature = "include_ovmf") {
+        let path = directory.join("OVMF.fd");
+
+        #[cfg(feature = "include_ovmf")]
+        write(arch, &path)?;
+        return Ok(path);
+    }
+
+    bail!("No OVMF firmware found for {:?}", arch)
+}
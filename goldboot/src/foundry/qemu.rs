@@ -1,5 +1,12 @@
 use crate::enter;
-use crate::foundry::{ssh::SshConnection, vnc::VncConnection, FoundryWorker};
+use crate::foundry::{
+    dashboard::DashboardHandle,
+    qmp::{self, QmpConnection, SharedQmpConnection},
+    serial::SerialConnection,
+    ssh::SshConnection,
+    vnc::{VncConnection, DEFAULT_WAIT_TIMEOUT},
+    FoundryWorker,
+};
 use anyhow::bail;
 use anyhow::Result;
 use goldboot_image::ImageArch;
@@ -7,13 +14,14 @@ use rand::Rng;
 use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{
-    process::{Child, Command},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex, MutexGuard},
     time::Duration,
 };
 use strum::Display;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
 use super::sources::ImageSource;
 use super::sources::SourceCache;
@@ -25,6 +33,17 @@ pub enum OsCategory {
     Windows,
 }
 
+/// What to do with the VM when a build fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnFailure {
+    /// Open an interactive SSH session in the VM
+    Shell,
+    /// Wait for the user before killing the VM
+    Pause,
+    /// Leave the VM running until it's shut down or interrupted
+    Keep,
+}
+
 /// Detect the best acceleration type for the current hardware.
 pub fn detect_accel() -> String {
     if std::env::var("CI").is_ok() {
@@ -61,23 +80,154 @@ pub fn detect_accel() -> String {
 
 pub fn mimic_hardware() {}
 
+/// Convert a disk image with qemu-img.
+pub fn convert(source: impl AsRef<Path>, dest: impl AsRef<Path>, format: &str) -> Result<()> {
+    let status = Command::new("qemu-img")
+        .arg("convert")
+        .args(["-O", format])
+        .arg(source.as_ref())
+        .arg(dest.as_ref())
+        .stdout(Stdio::null())
+        .status()?;
+
+    if !status.success() {
+        bail!("Failed to convert image with qemu-img");
+    }
+    Ok(())
+}
+
+/// Create a qcow2 disk that records its changes on top of another.
+pub fn overlay(backing: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<()> {
+    let status = Command::new("qemu-img")
+        .arg("create")
+        .args(["-f", "qcow2", "-F", "qcow2", "-b"])
+        .arg(backing.as_ref())
+        .arg(dest.as_ref())
+        .stdout(Stdio::null())
+        .status()?;
+
+    if !status.success() {
+        bail!("Failed to create overlay with qemu-img");
+    }
+    Ok(())
+}
+
+/// The drive ID of the installation media.
+pub const CDROM: &str = "cdrom0";
+
+/// How long to wait for the VM to power off on its own before pressing the
+/// power button.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How long to wait after pressing the power button before killing the VM.
+const POWERDOWN_TIMEOUT: Duration = Duration::from_secs(60);
+
 /// Wraps a qemu process and provides easy access to VNC and SSH.
 pub struct QemuProcess {
     pub arch: ImageArch,
-    pub process: Child,
+
+    /// The qemu process, until it's handed over as a failed VM
+    process: Option<Child>,
     pub ssh_port: u16,
     pub private_key: PathBuf,
     pub host_key: PathBuf,
     pub vnc: VncConnection,
+    pub vnc_port: u16,
+
+    /// The QMP session if the socket could be connected
+    pub qmp: Option<SharedQmpConnection>,
     pub os_category: OsCategory,
+    pub temp: PathBuf,
+
+    /// Where the VM is handed over if the build fails
+    pub failed: Option<FailedVmSlot>,
+
+    /// The user of the SSH server if it was started
+    pub ssh_username: Option<String>,
+
+    /// Whether the VM was shut down normally
+    pub finished: bool,
 }
 
 impl Drop for QemuProcess {
+    fn drop(&mut self) {
+        let Some(mut process) = self.process.take() else {
+            return;
+        };
+
+        // The build failed if the VM is dropped before shutting down
+        if !self.finished {
+            if let Some(failed) = &self.failed {
+                *failed.lock().unwrap() = Some(FailedVm {
+                    process,
+                    ssh_port: self.ssh_port,
+                    vnc_port: self.vnc_port,
+                    private_key: self.private_key.clone(),
+                    ssh_username: self.ssh_username.clone(),
+                });
+                return;
+            }
+        }
+        process.kill().unwrap_or_default();
+    }
+}
+
+/// Where a worker's VM is kept when its build fails.
+pub type FailedVmSlot = Arc<Mutex<Option<FailedVm>>>;
+
+/// A VM whose build failed. It's killed when dropped.
+pub struct FailedVm {
+    process: Child,
+    ssh_port: u16,
+    vnc_port: u16,
+    private_key: PathBuf,
+    ssh_username: Option<String>,
+}
+
+impl Drop for FailedVm {
     fn drop(&mut self) {
         self.process.kill().unwrap_or_default();
     }
 }
 
+impl FailedVm {
+    /// Keep the VM alive for debugging.
+    pub fn hold(&mut self, on_failure: OnFailure) -> Result<()> {
+        match (on_failure, &self.ssh_username) {
+            (OnFailure::Shell, Some(username)) => {
+                warn!(
+                    ssh_port = self.ssh_port,
+                    "Build failed; opening a shell in the VM (exit to continue)"
+                );
+                SshConnection::new(username, &self.private_key, self.ssh_port)?.shell()?;
+            }
+            (OnFailure::Pause, _) => {
+                warn!(
+                    vnc_port = self.vnc_port,
+                    "Build failed; press enter to kill the VM"
+                );
+                std::io::stdin().read_line(&mut String::new())?;
+            }
+            (on_failure, username) => {
+                if on_failure == OnFailure::Shell {
+                    warn!("The SSH server wasn't started yet, so no shell can be opened");
+                }
+                warn!(
+                    vnc_port = self.vnc_port,
+                    ssh_port = self.ssh_port,
+                    ssh_username = ?username,
+                    private_key = ?self.private_key,
+                    "Build failed; keeping the VM running until it's shut down or interrupted"
+                );
+                while self.process.try_wait()?.is_none() {
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl QemuProcess {
     pub fn ssh(&mut self, username: &str) -> Result<SshConnection> {
         #[rustfmt::skip]
@@ -90,21 +240,83 @@ impl QemuProcess {
             enter!(format!("/tmp/goldboot/sshdog {} /tmp/goldboot/host_key /tmp/goldboot/public_key", self.ssh_port)),
         ])?;
 
-        Ok(SshConnection::new(
-            username,
-            &self.private_key,
-            self.ssh_port,
-        )?)
+        self.ssh_username = Some(username.to_string());
+        let mut ssh = SshConnection::new(username, &self.private_key, self.ssh_port)?;
+        ssh.dashboard = self.vnc.dashboard.clone();
+        Ok(ssh)
     }
 
+    /// Wait for the VM to power off. If it doesn't in a reasonable amount of
+    /// time, the power button is pressed.
     pub fn shutdown_wait(&mut self) -> Result<()> {
         info!("Waiting for shutdown");
 
-        // Wait for QEMU to exit
-        self.process.wait()?;
+        if !self.wait_timeout(SHUTDOWN_TIMEOUT)? {
+            warn!("VM didn't power off in time");
+            self.powerdown()?;
+        }
         debug!("Shutdown complete");
         Ok(())
     }
+
+    /// Gracefully power off the VM through ACPI and kill it if that doesn't
+    /// work.
+    pub fn powerdown(&mut self) -> Result<()> {
+        info!("Sending powerdown request");
+        self.qmp()?.system_powerdown()?;
+
+        if !self.wait_timeout(POWERDOWN_TIMEOUT)? {
+            self.kill()?;
+            bail!("VM didn't respond to the powerdown request");
+        }
+        Ok(())
+    }
+
+    /// Kill the VM immediately.
+    pub fn kill(&mut self) -> Result<()> {
+        Ok(self.process().kill()?)
+    }
+
+    fn process(&mut self) -> &mut Child {
+        self.process
+            .as_mut()
+            .expect("the process is only taken when dropped")
+    }
+
+    /// Wait for the VM to exit, however long it takes.
+    pub fn wait(&mut self) -> Result<()> {
+        while !self.wait_timeout(Duration::from_secs(3600))? {}
+        Ok(())
+    }
+
+    /// Wait for the VM to exit while watching its run state. Returns whether
+    /// the VM exited in time.
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        let start = std::time::Instant::now();
+
+        while start.elapsed() < timeout {
+            if self.process().try_wait()?.is_some() {
+                self.finished = true;
+                return Ok(true);
+            }
+            qmp::check_state(self.qmp.as_ref())?;
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        Ok(false)
+    }
+
+    /// Get the QMP session.
+    fn qmp(&self) -> Result<MutexGuard<'_, QmpConnection>> {
+        match &self.qmp {
+            Some(qmp) => Ok(qmp.lock().unwrap()),
+            None => bail!("QMP is not available"),
+        }
+    }
+
+    /// Get the serial console session.
+    pub fn serial(&mut self) -> Result<&mut SerialConnection> {
+        self.vnc.serial()
+    }
 }
 
 #[derive(Debug)]
@@ -120,9 +332,14 @@ pub struct QemuArgs {
     pub memory: String,
     pub name: String,
     pub netdev: Vec<String>,
+
+    /// Exit instead of restarting when the guest reboots
+    pub no_reboot: bool,
     pub smbios: Option<String>,
     pub smp: String,
     pub usbdevice: Vec<String>,
+    pub qmp: Option<String>,
+    pub serial: Vec<String>,
     pub vga: String,
     pub vnc: Vec<String>,
 }
@@ -183,6 +400,16 @@ impl Into<Vec<String>> for QemuArgs {
             cmdline.push(vnc.to_string());
         }
 
+        for serial in &self.serial {
+            cmdline.push(String::from("-serial"));
+            cmdline.push(serial.clone());
+        }
+
+        if let Some(qmp) = &self.qmp {
+            cmdline.push(String::from("-qmp"));
+            cmdline.push(qmp.clone());
+        }
+
         for device in &self.device {
             cmdline.push(String::from("-device"));
             cmdline.push(device.to_string());
@@ -191,11 +418,37 @@ impl Into<Vec<String>> for QemuArgs {
         cmdline.push(String::from("-vga"));
         cmdline.push(self.vga.to_string());
 
+        if self.no_reboot {
+            cmdline.push(String::from("-no-reboot"));
+        }
+
         trace!("QEMU cmdline: {:?}", &cmdline);
         cmdline
     }
 }
 
+/// The devices that every VM gets.
+fn devices(arch: ImageArch) -> Vec<String> {
+    let mut devices = vec![String::from("virtio-net,netdev=user.0")];
+
+    // Lets QMP report guest panics
+    if arch == ImageArch::Amd64 {
+        devices.push(String::from("pvpanic"));
+    }
+    devices
+}
+
+fn qmp_path(temp: &Path, ssh_port: u16) -> PathBuf {
+    temp.join(format!("qmp-{ssh_port}.sock"))
+}
+
+fn qmp_arg(temp: &Path, ssh_port: u16) -> String {
+    format!(
+        "unix:{},server=on,wait=off",
+        qmp_path(temp, ssh_port).display()
+    )
+}
+
 pub struct QemuBuilder {
     arch: ImageArch,
     args: QemuArgs,
@@ -204,9 +457,20 @@ pub struct QemuBuilder {
     ssh_port: u16,
     ssh_private_key: PathBuf,
     ssh_host_key: PathBuf,
+    qmp_path: PathBuf,
+    serial_path: Option<PathBuf>,
     vnc_port: u16,
     temp: PathBuf,
     os_category: OsCategory,
+    failed: Option<FailedVmSlot>,
+
+    /// Identifies the VM in errors
+    name: String,
+    screenshots: PathBuf,
+    wait_timeout: u64,
+
+    /// Where the VM's progress is reported
+    dashboard: Option<DashboardHandle>,
 }
 
 impl QemuBuilder {
@@ -220,7 +484,7 @@ impl QemuBuilder {
                 bios: worker.ovmf_path.display().to_string(),
                 boot: String::from("once=d"),
                 cpu: None,
-                device: vec![String::from("virtio-net,netdev=user.0")],
+                device: devices(worker.arch),
 
                 // Bring up a graphical console in debug mode (linux only)
                 display: if worker.debug && cfg!(target_os = "linux") {
@@ -244,34 +508,100 @@ impl QemuBuilder {
                 memory: worker.memory.clone(),
                 name: String::from("goldboot"),
                 netdev: vec![],
+                no_reboot: false,
                 smbios: None,
                 smp: String::from("4,sockets=1,cores=4,threads=1"),
                 usbdevice: vec![],
+                serial: vec![],
+                qmp: Some(qmp_arg(worker.tmp.path(), ssh_port)),
                 vnc: vec![format!("127.0.0.1:{}", worker.vnc_port % 5900)],
                 vga: String::from("std"),
             },
             arch: worker.arch,
             debug: worker.debug,
             os_category,
+            failed: worker.on_failure.map(|_| worker.failed_vm.clone()),
             record: worker.record,
             ssh_port,
             ssh_private_key,
             ssh_host_key,
+            serial_path: None,
+            qmp_path: qmp_path(worker.tmp.path(), ssh_port),
             temp: worker.tmp.path().to_path_buf(),
             vnc_port: worker.vnc_port,
+            name: worker.element.mold.to_string(),
+            screenshots: worker.screenshots.clone(),
+            wait_timeout: worker.wait_timeout,
+            dashboard: worker.dashboard.clone(),
         }
     }
 
+    /// Create a builder for a VM that boots an existing disk outside of a
+    /// cast.
+    pub fn standalone(
+        arch: ImageArch,
+        ovmf_path: &Path,
+        temp: &Path,
+        memory: &str,
+    ) -> Result<Self> {
+        let ssh_port = rand::thread_rng().gen_range(10000..11000);
+        let vnc_port = rand::thread_rng().gen_range(5900..5999);
+
+        Ok(Self {
+            args: QemuArgs {
+                bios: ovmf_path.display().to_string(),
+                boot: String::from("c"),
+                cpu: None,
+                device: devices(arch),
+                display: String::from("none"),
+                drive: vec![],
+                global: vec![String::from("driver=cfi.pflash01,property=secure,value=on")],
+                machine: format!("type=pc,accel={}", detect_accel()),
+                memory: memory.to_string(),
+                name: String::from("goldboot"),
+                netdev: vec![],
+                no_reboot: false,
+                smbios: None,
+                smp: String::from("4,sockets=1,cores=4,threads=1"),
+                usbdevice: vec![],
+                serial: vec![],
+                qmp: Some(qmp_arg(temp, ssh_port)),
+                vnc: vec![format!("127.0.0.1:{}", vnc_port % 5900)],
+                vga: String::from("std"),
+            },
+            arch,
+            debug: false,
+            os_category: OsCategory::Linux,
+            failed: None,
+            record: false,
+            ssh_port,
+            ssh_private_key: crate::foundry::ssh::generate_key(temp)?,
+            ssh_host_key: crate::foundry::ssh::generate_key(temp)?,
+            serial_path: None,
+            qmp_path: qmp_path(temp, ssh_port),
+            temp: temp.to_path_buf(),
+            vnc_port,
+            name: String::from("goldboot"),
+            screenshots: temp.join("screenshots"),
+            wait_timeout: DEFAULT_WAIT_TIMEOUT,
+            dashboard: None,
+        })
+    }
+
     /// Set the image source.
     pub fn source(mut self, source: &ImageSource) -> Result<Self> {
         match source {
             ImageSource::Iso { url, checksum } => {
                 self.args.drive.push(format!(
-                    "file={},media=cdrom",
+                    "file={},media=cdrom,id={CDROM}",
                     SourceCache::default()?.get(url.clone(), checksum.clone())?
                 ));
             }
-            _ => todo!(),
+            ImageSource::Mold { .. } => {
+                // The worker already wrote the base image to the disk
+                self.args.boot = String::from("c");
+            }
+            ImageSource::Buildroot => bail!("Buildroot sources are not supported yet"),
         }
 
         Ok(self)
@@ -285,6 +615,76 @@ impl QemuBuilder {
         self
     }
 
+    /// Update -boot
+    pub fn boot(mut self, arg: &str) -> Self {
+        self.args.boot = arg.to_string();
+        self
+    }
+
+    /// Replace the disk that the VM boots from.
+    pub fn disk(mut self, path: &Path, format: &str) -> Self {
+        let drive = format!(
+            "file={},if=virtio,cache=writeback,discard=ignore,format={format}",
+            path.display()
+        );
+
+        if self.args.drive.is_empty() {
+            self.args.drive.push(drive);
+        } else {
+            self.args.drive[0] = drive;
+        }
+        self
+    }
+
+    /// Attach the first serial port to a unix socket so the console can be
+    /// automated with `Expect` and `SendLine` steps in the boot sequence.
+    pub fn serial(mut self) -> Self {
+        let path = self.temp.join(format!("serial-{}.sock", self.ssh_port));
+        self.args
+            .serial
+            .push(format!("unix:{},server=on,wait=off", path.display()));
+        self.serial_path = Some(path);
+        self
+    }
+
+    /// Add an absolute pointing device so VNC clicks land where they're sent.
+    pub fn tablet(mut self) -> Self {
+        self.args.device.push(String::from("qemu-xhci"));
+        self.args.device.push(String::from("usb-tablet"));
+        self
+    }
+
+    /// Update -display
+    pub fn display(mut self, arg: &str) -> Self {
+        self.args.display = arg.to_string();
+        self
+    }
+
+    /// Forward the given local port to the guest's own SSH server. Only local
+    /// connections are accepted.
+    pub fn forward_ssh(mut self, port: u16) -> Self {
+        self.args
+            .netdev
+            .push(format!("user,id=user.0,hostfwd=tcp:127.0.0.1:{port}-:22"));
+        self
+    }
+
+    /// Boot from the network with the given iPXE script.
+    pub fn netboot(mut self, script: &str) -> Self {
+        self.args
+            .netdev
+            .push(format!("user,id=user.0,bootfile={script}"));
+        self.args.boot = String::from("n");
+        self
+    }
+
+    /// Make the VM exit when the guest reboots so the disk can be inspected
+    /// afterwards.
+    pub fn no_reboot(mut self) -> Self {
+        self.args.no_reboot = true;
+        self
+    }
+
     /// Update -vga
     pub fn vga(mut self, arg: &str) -> Self {
         self.args.vga = arg.to_string();
@@ -348,7 +748,7 @@ impl QemuBuilder {
         let public_key = std::fs::read(self.ssh_private_key.with_extension("pub"))?;
 
         self.args.netdev.push(format!(
-            "user,id=user.0,hostfwd=tcp::{}-:{}",
+            "user,id=user.0,hostfwd=tcp:127.0.0.1:{}-:{}",
             self.ssh_port, self.ssh_port
         ));
 
@@ -373,7 +773,7 @@ impl QemuBuilder {
         .spawn()?;
 
         // Connect to VNC immediately
-        let vnc = loop {
+        let mut vnc = loop {
             match VncConnection::new("localhost", self.vnc_port, self.record, self.debug) {
                 Ok(vnc) => break Ok(vnc),
                 Err(_) => {
@@ -391,14 +791,43 @@ impl QemuBuilder {
                 }
             }
         }?;
+        vnc.name = self.name;
+        vnc.screenshots = self.screenshots;
+        vnc.timeout = Duration::from_secs(self.wait_timeout);
+        vnc.dashboard = self.dashboard;
+
+        let qmp = match QmpConnection::connect(&self.qmp_path) {
+            Ok(qmp) => Some(Arc::new(Mutex::new(qmp))),
+            Err(err) => {
+                warn!(error = %err, "Failed to connect to QMP");
+                None
+            }
+        };
+
+        // Waits on the consoles fail as soon as the VM stops running normally
+        vnc.qmp = qmp.clone();
+
+        // Serial steps run alongside the VNC ones
+        if let Some(path) = &self.serial_path {
+            let mut serial = SerialConnection::connect(path)?;
+            serial.qmp = qmp.clone();
+            vnc.serial = Some(serial);
+        }
+
         Ok(QemuProcess {
             arch: self.arch,
             os_category: self.os_category,
+            failed: self.failed,
+            ssh_username: None,
+            finished: false,
             private_key: self.ssh_private_key,
             host_key: self.ssh_host_key,
-            process,
+            process: Some(process),
             ssh_port: self.ssh_port,
             vnc,
+            vnc_port: self.vnc_port,
+            qmp,
+            temp: self.temp,
         })
     }
 }
@@ -0,0 +1,317 @@
+//! A client for the QEMU Machine Protocol which allows the VM to be controlled
+//! more precisely than through VNC.
+
+use super::vnc::{rgb332, VncScreenshot};
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, trace};
+
+/// A QMP session that's shared between the VM and its consoles so that waits
+/// can watch the run state.
+pub type SharedQmpConnection = Arc<Mutex<QmpConnection>>;
+
+/// Installers reboot on their own, but a VM that resets this many times within
+/// [`RESET_WINDOW`] is stuck.
+const MAX_RESETS: usize = 5;
+const RESET_WINDOW: Duration = Duration::from_secs(60);
+
+/// Fail if the VM stopped running normally. Nothing is checked without a QMP
+/// session.
+pub fn check_state(qmp: Option<&SharedQmpConnection>) -> Result<()> {
+    match qmp {
+        Some(qmp) => qmp.lock().unwrap().check_state(),
+        None => Ok(()),
+    }
+}
+
+/// Represents a QMP session to a running VM.
+pub struct QmpConnection {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+
+    /// Events that arrived while waiting for command responses
+    events: Vec<Value>,
+
+    /// When the VM was recently reset
+    resets: Vec<Instant>,
+
+    /// Whether the connection was lost (usually because QEMU exited)
+    closed: bool,
+}
+
+impl QmpConnection {
+    /// Connect to the QMP server on the given unix socket.
+    #[cfg(unix)]
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        Self::new(Box::new(stream.try_clone()?), Box::new(stream))
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(_path: impl AsRef<Path>) -> Result<Self> {
+        bail!("QMP is only supported over unix sockets");
+    }
+
+    /// Negotiate a new session over the given streams.
+    pub fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> Result<Self> {
+        let mut qmp = Self {
+            reader: BufReader::new(reader),
+            writer,
+            events: Vec::new(),
+            resets: Vec::new(),
+            closed: false,
+        };
+
+        let greeting = qmp.read()?;
+        if greeting.get("QMP").is_none() {
+            bail!("Unexpected QMP greeting: {}", greeting);
+        }
+        debug!(version = %greeting["QMP"]["version"]["qemu"], "Connected to QMP");
+
+        qmp.execute("qmp_capabilities", None)?;
+        Ok(qmp)
+    }
+
+    fn read(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            bail!("QMP connection closed");
+        }
+        trace!(message = %line.trim_end(), "Received QMP message");
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Run a command and return its result.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        writeln!(self.writer, "{}", request)?;
+        self.writer.flush()?;
+
+        loop {
+            let mut response = self.read()?;
+            if response.get("event").is_some() {
+                self.events.push(response);
+            } else if let Some(result) = response.get_mut("return") {
+                return Ok(result.take());
+            } else if let Some(error) = response.get("error") {
+                bail!("QMP command {} failed: {}", command, error["desc"]);
+            }
+        }
+    }
+
+    /// Take the events that have been received so far. Events are only read
+    /// while waiting for command responses.
+    pub fn events(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Get the current run state of the VM (like "running" or
+    /// "guest-panicked").
+    pub fn status(&mut self) -> Result<String> {
+        self.execute("query-status", None)?["status"]
+            .as_str()
+            .map(|status| status.to_string())
+            .ok_or_else(|| anyhow!("Invalid query-status response"))
+    }
+
+    /// Check the run state of the VM and fail if the guest panicked or keeps
+    /// resetting. Nothing is checked after the connection is lost.
+    pub fn check_state(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        let status = match self.status() {
+            Ok(status) => status,
+            Err(err) => {
+                debug!(error = %err, "Lost QMP connection");
+                self.closed = true;
+                return Ok(());
+            }
+        };
+
+        for event in self.events() {
+            match event["event"].as_str() {
+                Some("RESET") => {
+                    info!("VM was reset");
+                    self.resets.push(Instant::now());
+                }
+                Some("GUEST_PANICKED") => {
+                    bail!("The guest operating system panicked: {}", event["data"])
+                }
+                Some(event) => debug!(event, "Received QMP event"),
+                None => {}
+            }
+        }
+
+        self.resets.retain(|reset| reset.elapsed() < RESET_WINDOW);
+        if self.resets.len() >= MAX_RESETS {
+            bail!(
+                "The VM was reset {} times in {} seconds",
+                self.resets.len(),
+                RESET_WINDOW.as_secs()
+            );
+        }
+
+        if status == "guest-panicked" {
+            bail!("The guest operating system panicked");
+        }
+        Ok(())
+    }
+
+    /// Press the virtual power button.
+    pub fn system_powerdown(&mut self) -> Result<()> {
+        self.execute("system_powerdown", None)?;
+        Ok(())
+    }
+
+    /// Capture the screen into a PPM file.
+    pub fn screendump(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.execute(
+            "screendump",
+            Some(json!({ "filename": path.as_ref().to_string_lossy() })),
+        )?;
+        Ok(())
+    }
+
+    /// Capture the screen without going through VNC.
+    pub fn screenshot(&mut self) -> Result<VncScreenshot> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("screendump.ppm");
+        self.screendump(&path)?;
+        parse_ppm(&std::fs::read(&path)?)
+    }
+}
+
+/// Convert a binary PPM screendump into a screenshot with the same pixel format
+/// as VNC screenshots.
+pub fn parse_ppm(data: &[u8]) -> Result<VncScreenshot> {
+    // The header has four whitespace separated fields: magic, width, height,
+    // and max value
+    let mut fields = Vec::new();
+    let mut position = 0;
+    while fields.len() < 4 {
+        while data
+            .get(position)
+            .ok_or_else(|| anyhow!("Truncated PPM header"))?
+            .is_ascii_whitespace()
+        {
+            position += 1;
+        }
+        let start = position;
+        while position < data.len() && !data[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        fields.push(std::str::from_utf8(&data[start..position])?);
+    }
+
+    if fields[0] != "P6" || fields[3] != "255" {
+        bail!("Unsupported PPM format");
+    }
+    let width: u16 = fields[1].parse()?;
+    let height: u16 = fields[2].parse()?;
+
+    // A single whitespace byte separates the header from the pixels
+    let pixels = data
+        .get(position + 1..position + 1 + width as usize * height as usize * 3)
+        .ok_or_else(|| anyhow!("Truncated PPM data"))?;
+
+    Ok(VncScreenshot {
+        width,
+        height,
+        data: pixels
+            .chunks_exact(3)
+            .map(|rgb| rgb332(rgb[0], rgb[1], rgb[2]))
+            .collect(),
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_qmp() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("qmp.sock");
+        let listener = UnixListener::bind(&path)?;
+
+        // Pretend to be QEMU
+        let server = std::thread::spawn(move || -> Result<Vec<String>> {
+            let (stream, _) = listener.accept()?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut writer = stream;
+            let mut commands = Vec::new();
+
+            writeln!(
+                writer,
+                r#"{{"QMP": {{"version": {{"qemu": {{"major": 8}}}}, "capabilities": []}}}}"#
+            )?;
+
+            let mut line = String::new();
+            while reader.read_line(&mut line)? > 0 {
+                let request: Value = serde_json::from_str(&line)?;
+                commands.push(request["execute"].as_str().unwrap().to_string());
+                line.clear();
+
+                match request["execute"].as_str() {
+                    Some("query-status") => {
+                        writeln!(writer, r#"{{"event": "GUEST_PANICKED", "data": {{}}}}"#)?;
+                        writeln!(
+                            writer,
+                            r#"{{"return": {{"status": "guest-panicked", "running": false}}}}"#
+                        )?;
+                    }
+                    Some("screendump") => writeln!(
+                        writer,
+                        r#"{{"error": {{"class": "GenericError", "desc": "no surface"}}}}"#
+                    )?,
+                    _ => writeln!(writer, r#"{{"return": {{}}}}"#)?,
+                }
+            }
+            Ok(commands)
+        });
+
+        let mut qmp = QmpConnection::connect(&path)?;
+        assert_eq!(qmp.status()?, "guest-panicked");
+        assert_eq!(qmp.events()[0]["event"], "GUEST_PANICKED");
+        assert!(qmp.events().is_empty());
+        assert!(qmp.check_state().is_err());
+        assert!(qmp.screenshot().is_err());
+        qmp.system_powerdown()?;
+        drop(qmp);
+
+        assert_eq!(
+            server.join().unwrap()?,
+            vec![
+                "qmp_capabilities",
+                "query-status",
+                "query-status",
+                "screendump",
+                "system_powerdown"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ppm() -> Result<()> {
+        let mut data = b"P6\n2 1\n255\n".to_vec();
+        data.extend([0xff, 0xff, 0xff, 0xe0, 0x20, 0x40]);
+
+        let screenshot = parse_ppm(&data)?;
+        assert_eq!((screenshot.width, screenshot.height), (2, 1));
+        assert_eq!(screenshot.data, vec![0xff, 0xe5]);
+        Ok(())
+    }
+}
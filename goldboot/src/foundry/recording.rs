@@ -0,0 +1,190 @@
+//! Records the screen during a cast so it can be reviewed afterwards. Frames
+//! are saved as they're captured along with an HTML timeline that shows which
+//! command was running at the time.
+
+use super::vnc::VncScreenshot;
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tracing::{debug, warn};
+
+#[derive(Serialize, Debug)]
+pub struct Frame {
+    /// The frame's file name relative to the recording
+    pub file: String,
+
+    /// Milliseconds since the recording started
+    pub time: u128,
+
+    /// The step that was running when the frame was captured
+    pub step: usize,
+    pub command: String,
+}
+
+/// A sequence of timestamped screenshots.
+pub struct Recording {
+    pub directory: PathBuf,
+    pub frames: Vec<Frame>,
+    name: String,
+    start: Instant,
+
+    /// Hash of the last frame so unchanged screens aren't saved repeatedly
+    last_hash: String,
+}
+
+impl Recording {
+    /// Start a recording in a new directory under the given one.
+    pub fn new(parent: &Path, name: &str) -> Result<Self> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        let mut directory = parent.join(format!("{name}-{timestamp}"));
+        let mut attempt = 1;
+        while directory.exists() {
+            attempt += 1;
+            directory = parent.join(format!("{name}-{timestamp}-{attempt}"));
+        }
+        std::fs::create_dir_all(&directory)?;
+        debug!(directory = ?directory, "Recording screen");
+
+        Ok(Self {
+            directory,
+            frames: Vec::new(),
+            name: name.to_string(),
+            start: Instant::now(),
+            last_hash: String::new(),
+        })
+    }
+
+    /// Add a frame unless the screen hasn't changed since the last one.
+    pub fn capture(
+        &mut self,
+        screenshot: &VncScreenshot,
+        step: usize,
+        command: &str,
+    ) -> Result<()> {
+        let hash = screenshot.hash();
+        if hash == self.last_hash {
+            return Ok(());
+        }
+
+        let file = format!("{:06}.png", self.frames.len());
+        screenshot.write_png(&self.directory.join(&file))?;
+        self.last_hash = hash;
+        self.frames.push(Frame {
+            file,
+            time: self.start.elapsed().as_millis(),
+            step,
+            command: command.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Write the HTML timeline for the frames captured so far.
+    pub fn write_timeline(&self) -> Result<()> {
+        let title = self
+            .name
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+
+        std::fs::write(
+            self.directory.join("index.html"),
+            format!(
+                r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; background: #222; color: #eee; }}
+input {{ width: 100%; }}
+img {{ max-width: 100%; image-rendering: pixelated; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<input id="slider" type="range" min="0" value="0" oninput="show(this.value)">
+<p id="caption"></p>
+<img id="frame">
+<script>
+const frames = {frames};
+const slider = document.getElementById("slider");
+function show(index) {{
+  const frame = frames[index];
+  if (!frame) return;
+  document.getElementById("frame").src = frame.file;
+  document.getElementById("caption").textContent =
+    `${{(frame.time / 1000).toFixed(1)}}s, step ${{frame.step}}: ${{frame.command}}`;
+}}
+document.addEventListener("keydown", (event) => {{
+  if (event.key === "ArrowLeft") slider.value--;
+  if (event.key === "ArrowRight") slider.value++;
+  show(slider.value);
+}});
+slider.max = Math.max(frames.length - 1, 0);
+slider.value = slider.max;
+show(slider.value);
+</script>
+</body>
+</html>
+"#,
+                // Typed text could otherwise end the script early
+                frames = serde_json::to_string(&self.frames)?.replace("</", "<\\/"),
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        // Also covers casts that fail partway through
+        match self.write_timeline() {
+            Ok(()) => debug!(directory = ?self.directory, "Saved recording"),
+            Err(err) => warn!(error = %err, "Failed to save recording timeline"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let screen = VncScreenshot {
+            data: vec![0xe0; 4],
+            width: 2,
+            height: 2,
+        };
+
+        let mut recording = Recording::new(tmp.path(), "Debian")?;
+        recording.capture(&screen, 1, "WaitScreen(\"abc\")")?;
+        recording.capture(&screen, 2, "Enter")?;
+        recording.capture(
+            &VncScreenshot {
+                data: vec![0x03; 4],
+                ..screen
+            },
+            2,
+            "Enter",
+        )?;
+
+        // Unchanged screens are skipped
+        assert_eq!(recording.frames.len(), 2);
+        assert_eq!(recording.frames[1].step, 2);
+
+        let directory = recording.directory.clone();
+        drop(recording);
+        let timeline = std::fs::read_to_string(directory.join("index.html"))?;
+        assert!(timeline.contains(r#""file":"000001.png""#));
+        assert!(directory.join("000000.png").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use goldboot_image::ImageArch;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A record of a cast which is written next to the foundry config.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct BuildReport {
+    /// The image name
+    pub name: String,
+
+    pub arch: ImageArch,
+
+    /// When the cast started in seconds since the epoch
+    pub started: u64,
+
+    /// How long the cast took in seconds
+    pub duration: f64,
+
+    /// Whether the cast produced an image
+    pub success: bool,
+
+    /// Why the cast failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// The ID of the resulting image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_id: Option<String>,
+
+    pub elements: Vec<ElementReport>,
+
+    /// The results of booting the finished image
+    pub tests: Vec<TestResult>,
+}
+
+/// A record of one alloy element's build.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ElementReport {
+    pub mold: String,
+
+    /// How long the element took to build in seconds
+    pub duration: f64,
+}
+
+/// The result of a single smoke test assertion.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct TestResult {
+    pub command: String,
+    pub exit_code: i32,
+    pub passed: bool,
+}
+
+impl BuildReport {
+    pub fn new(name: &str, arch: ImageArch) -> Self {
+        Self {
+            name: name.to_string(),
+            arch,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            duration: 0.0,
+            success: false,
+            error: None,
+            image_id: None,
+            elements: Vec::new(),
+            tests: Vec::new(),
+        }
+    }
+
+    /// Write the report as JSON.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
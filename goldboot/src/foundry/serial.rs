@@ -0,0 +1,183 @@
+//! Contains an expect-style interface for automating installers over the VM's
+//! serial console. Unlike screen hashes, text matches survive changes to fonts
+//! and resolutions.
+//!
+//! Boot sequences use the console through the `Expect` and `SendLine` commands
+//! (or `<expect:...>` and `<serial:...>` in the boot command syntax).
+
+use super::qmp::{self, SharedQmpConnection};
+use anyhow::{bail, Result};
+use regex::Regex;
+use std::{
+    io::{Read, Write},
+    path::Path,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+use tracing::trace;
+
+/// How much of the console output is kept for matching.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Represents a session to a VM's serial console.
+pub struct SerialConnection {
+    output: Receiver<Vec<u8>>,
+    writer: Box<dyn Write + Send>,
+
+    /// Console output that hasn't been matched yet
+    buffer: String,
+
+    /// Used to watch the run state of the VM during waits
+    pub qmp: Option<SharedQmpConnection>,
+}
+
+impl SerialConnection {
+    /// Connect to the serial console on the given unix socket.
+    #[cfg(unix)]
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        Ok(Self::new(Box::new(stream.try_clone()?), Box::new(stream)))
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(_path: impl AsRef<Path>) -> Result<Self> {
+        bail!("Serial consoles are only supported over unix sockets");
+    }
+
+    pub fn new(mut reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> Self {
+        // Read on another thread so waits can time out
+        let (sender, output) = channel();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            while let Ok(size @ 1..) = reader.read(&mut buffer) {
+                if sender.send(buffer[..size].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            output,
+            writer,
+            buffer: String::new(),
+            qmp: None,
+        }
+    }
+
+    /// Wait for the console output to match the given regex. Returns the
+    /// matched text.
+    pub fn expect(&mut self, pattern: &str, timeout: Duration) -> Result<String> {
+        let regex = Regex::new(pattern)?;
+        let start = Instant::now();
+
+        loop {
+            if let Some(found) = regex.find(&self.buffer) {
+                let matched = found.as_str().to_string();
+                self.buffer.drain(..found.end());
+                return Ok(matched);
+            }
+
+            // Wake up regularly to watch the VM
+            let remaining = timeout.saturating_sub(start.elapsed());
+            match self
+                .output
+                .recv_timeout(remaining.min(Duration::from_secs(1)))
+            {
+                Ok(data) => {
+                    let text = String::from_utf8_lossy(&data);
+                    for line in text.lines() {
+                        trace!("(serial) {}", line);
+                    }
+                    self.buffer.push_str(&text);
+
+                    // Don't let unmatched output grow forever
+                    if self.buffer.len() > BUFFER_SIZE {
+                        let mut cut = self.buffer.len() - BUFFER_SIZE;
+                        while !self.buffer.is_char_boundary(cut) {
+                            cut += 1;
+                        }
+                        self.buffer.drain(..cut);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) if remaining > Duration::from_secs(1) => {
+                    qmp::check_state(self.qmp.as_ref())?
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    bail!("Timed out waiting for serial output to match: {}", pattern)
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    bail!("Serial console closed while waiting for: {}", pattern)
+                }
+            }
+        }
+    }
+
+    /// Send text to the console.
+    pub fn send(&mut self, text: &str) -> Result<()> {
+        self.writer.write_all(text.as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub mod macros {
+
+    #[macro_export]
+    macro_rules! expect {
+        ($pattern:expr) => {
+            vec![$crate::foundry::vnc::VncCmd::Expect($pattern.to_string())]
+        };
+        ($pattern:expr, $timeout:expr) => {
+            vec![$crate::foundry::vnc::VncCmd::WithTimeout(
+                $timeout,
+                Box::new($crate::foundry::vnc::VncCmd::Expect($pattern.to_string())),
+            )]
+        };
+    }
+
+    #[macro_export]
+    macro_rules! send_line {
+        ($text:expr) => {
+            vec![$crate::foundry::vnc::VncCmd::SendLine($text.to_string())]
+        };
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{io::BufRead, io::BufReader, os::unix::net::UnixListener};
+
+    #[test]
+    fn test_serial() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("serial.sock");
+        let listener = UnixListener::bind(&path)?;
+
+        // Pretend to be a login prompt
+        let console = std::thread::spawn(move || -> Result<String> {
+            let (stream, _) = listener.accept()?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut writer = stream;
+
+            writer.write_all(b"\r\nArch Linux 6.7.0 (tty)\r\n\r\narchiso login: ")?;
+            let mut username = String::new();
+            reader.read_line(&mut username)?;
+            writer.write_all(b"\r\n[root@archiso ~]# ")?;
+            Ok(username)
+        });
+
+        let mut serial = SerialConnection::connect(&path)?;
+        assert_eq!(
+            serial.expect(r"\w+ login: $", Duration::from_secs(5))?,
+            "archiso login: "
+        );
+        serial.send("root\n")?;
+        serial.expect(r"\[root@\w+ ~\]# ", Duration::from_secs(5))?;
+        assert_eq!(console.join().unwrap()?, "root\n");
+
+        // Nothing else is coming
+        assert!(serial.expect("never", Duration::from_secs(1)).is_err());
+        Ok(())
+    }
+}
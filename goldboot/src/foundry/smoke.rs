@@ -0,0 +1,109 @@
+use super::{
+    qemu::{self, OsCategory, QemuBuilder},
+    report::TestResult,
+    FoundryWorker,
+};
+use crate::{enter, wait, wait_screen};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::{info, warn};
+use validator::Validate;
+
+/// Checks that are run by booting the finished image.
+#[derive(Clone, Serialize, Deserialize, Validate, Default, Debug)]
+pub struct SmokeTests {
+    /// Wait for the screen to match this hash after booting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait_screen: Option<String>,
+
+    /// Commands to run over SSH
+    #[serde(default)]
+    pub assertions: Vec<Assertion>,
+}
+
+/// A command that has to exit with the expected code.
+#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+pub struct Assertion {
+    pub command: String,
+
+    #[serde(default)]
+    pub exit_code: i32,
+}
+
+impl SmokeTests {
+    /// Boot an overlay of the given disk and run the assertions against it.
+    pub fn run(&self, worker: &FoundryWorker, disk: &Path) -> Result<Vec<TestResult>> {
+        info!("Running smoke tests");
+
+        // Keep the finished disk untouched
+        let overlay = worker.tmp.path().join("smoke.gb.qcow2");
+        qemu::overlay(disk, &overlay)?;
+
+        let mut qemu = QemuBuilder::new(worker, OsCategory::Linux)
+            .disk(&overlay, "qcow2")
+            .boot("c")
+            .prepare_ssh()?
+            .start()?;
+
+        if let Some(hash) = &self.wait_screen {
+            qemu.vnc.run(vec![wait_screen!(hash)])?;
+        }
+
+        let mut results = Vec::new();
+        if !self.assertions.is_empty() {
+            let password =
+                worker.element.mold.root_password()?.ok_or_else(|| {
+                    anyhow!("{} doesn't support smoke tests", worker.element.mold)
+                })?;
+
+            // Log in on the console so the SSH server can be started
+            #[rustfmt::skip]
+            qemu.vnc.run(vec![
+                wait!(if self.wait_screen.is_some() { 0 } else { 60 }),
+                enter!("root"),
+                enter!(password),
+            ])?;
+
+            let mut ssh = qemu.ssh("root")?;
+            for assertion in &self.assertions {
+                let exit_code = ssh.exec(&assertion.command)?;
+                let passed = exit_code == assertion.exit_code;
+                if !passed {
+                    warn!(command = %assertion.command, exit_code, "Assertion failed");
+                }
+
+                results.push(TestResult {
+                    command: assertion.command.clone(),
+                    exit_code,
+                    passed,
+                });
+            }
+            ssh.shutdown("poweroff")?;
+        } else {
+            qemu.kill()?;
+        }
+
+        qemu.shutdown_wait()?;
+        std::fs::remove_file(overlay)?;
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_smoke_tests() -> Result<()> {
+        let tests: SmokeTests = ron::from_str(
+            r#"(assertions: [(command: "systemctl is-system-running"), (command: "false", exit_code: 1)])"#,
+        )?;
+
+        assert!(tests.wait_screen.is_none());
+        assert_eq!(tests.assertions.len(), 2);
+        assert_eq!(tests.assertions[0].exit_code, 0);
+        assert_eq!(tests.assertions[1].exit_code, 1);
+        Ok(())
+    }
+}
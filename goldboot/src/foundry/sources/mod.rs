@@ -13,6 +13,7 @@ use std::{
 use tracing::{debug, info};
 
 pub mod iso;
+pub mod mold;
 
 pub trait LoadSource {}
 
@@ -24,9 +25,9 @@ pub enum ImageSource {
         url: String,
         checksum: Option<String>,
     },
-    Mold {
-        base: String,
-    },
+    /// Start from an existing goldboot image (by ID, name, path, or registry
+    /// reference). Only fabricators are run on top of it.
+    Mold { base: String },
     #[default]
     Buildroot,
 }
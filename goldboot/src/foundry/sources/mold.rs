@@ -0,0 +1,85 @@
+use crate::{cli::progress::ProgressBar, library::ImageLibrary, registry::remote};
+use anyhow::{anyhow, bail, Result};
+use goldboot_image::ImageHandle;
+use std::{path::Path, process::Command};
+use tracing::info;
+
+/// Find a base image by path, registry reference, ID, or name. Remote images
+/// are downloaded into the image library first.
+pub fn find_base(base: &str) -> Result<ImageHandle> {
+    if Path::new(base).is_file() {
+        return ImageHandle::open(base);
+    }
+
+    if let Some(url) = remote::resolve_url(base) {
+        return ImageLibrary::open().download(url);
+    }
+
+    if let Ok(image) = ImageLibrary::find_by_id(base) {
+        return Ok(image);
+    }
+
+    // Use the newest image with the given name
+    ImageLibrary::find_by_name(base)?
+        .into_iter()
+        .max_by_key(|image| image.primary_header.timestamp)
+        .ok_or_else(|| anyhow!("Base image not found: {}", base))
+}
+
+/// Get the ID of a base image. Unlike [`find_base`], remote images aren't
+/// downloaded. If the server doesn't know a remote image's ID, what it says
+/// about the file stands in for it.
+pub fn base_id(base: &str) -> Result<String> {
+    if !Path::new(base).is_file() {
+        if let Some(url) = remote::resolve_url(base) {
+            let reader = remote::HttpRangeReader::new(&url)?;
+            return Ok(remote::image_id(&reader).unwrap_or_else(|| {
+                format!(
+                    "{} {} {}",
+                    reader.url,
+                    reader.length,
+                    reader.etag.as_deref().unwrap_or_default()
+                )
+            }));
+        }
+    }
+
+    Ok(find_base(base)?.id)
+}
+
+/// Replace the given qcow2 disk with the contents of a base image. The disk is
+/// grown to the given size if the base image is smaller.
+pub fn write_base(base: &str, disk: impl AsRef<Path>, size: u64) -> Result<()> {
+    let disk = disk.as_ref();
+
+    let mut image = find_base(base)?;
+    if image.primary_header.size > size {
+        bail!(
+            "The base image is larger than the requested size ({} > {})",
+            image.primary_header.size,
+            size
+        );
+    }
+
+    info!(base = %image.primary_header.name(), id = %image.id, "Writing base image");
+    image.load(None)?;
+
+    let raw = disk.with_extension("raw");
+    image.write(&raw, ProgressBar::Write.new_empty())?;
+    crate::foundry::qemu::convert(&raw, disk, "qcow2")?;
+    std::fs::remove_file(&raw)?;
+
+    if image.primary_header.size < size {
+        let status = Command::new("qemu-img")
+            .arg("resize")
+            .arg(disk)
+            .arg(format!("{size}"))
+            .status()?;
+
+        if !status.success() {
+            bail!("Failed to resize image with qemu-img");
+        }
+    }
+
+    Ok(())
+}
@@ -6,8 +6,11 @@ use ssh2::Session;
 use ssh_key::Algorithm;
 use ssh_key::LineEnding;
 use ssh_key::PrivateKey;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
 use std::thread::sleep;
 use std::{
     io::{BufRead, BufReader, Cursor},
@@ -17,7 +20,7 @@ use std::{
 };
 use tracing::{debug, info};
 
-use super::qemu::OsCategory;
+use super::{dashboard::DashboardHandle, qemu::OsCategory};
 
 /// Generate a new random SSH keypair
 pub fn generate_key(directory: &Path) -> Result<PathBuf> {
@@ -73,6 +76,9 @@ pub struct SshConnection {
     pub private_key: PathBuf,
     pub port: u16,
     pub session: ssh2::Session,
+
+    /// Where command output is reported when the dashboard is running
+    pub dashboard: Option<DashboardHandle>,
 }
 
 impl SshConnection {
@@ -89,6 +95,7 @@ impl SshConnection {
                         private_key: private_key.clone(),
                         port,
                         session,
+                        dashboard: None,
                     }
                 }
                 Err(error) => debug!("{}", error),
@@ -171,12 +178,16 @@ impl SshConnection {
             match stdout.read_line(&mut line) {
                 Ok(0) => break,
                 // TODO part of some span like goldboot::foundry::fabricator::exe
-                Ok(_) => debug!(
-                    "(fabricator) {}",
-                    line.strip_suffix("\r\n")
+                Ok(_) => {
+                    let line = line
+                        .strip_suffix("\r\n")
                         .or(line.strip_suffix("\n"))
-                        .unwrap_or(&line)
-                ),
+                        .unwrap_or(&line);
+                    debug!("(fabricator) {}", line);
+                    if let Some(dashboard) = &self.dashboard {
+                        dashboard.log(line);
+                    }
+                }
                 Err(_) => {
                     // The VM is probably rebooting, wait for SSH to come back up
                     info!("SSH disconnected; waiting for it to come back");
@@ -205,4 +216,154 @@ impl SshConnection {
     pub fn exec(&mut self, cmdline: &str) -> Result<i32> {
         self.exec_env(cmdline, Vec::new())
     }
+
+    /// Attach the terminal to an interactive shell on the VM until it exits.
+    pub fn shell(&self) -> Result<()> {
+        let mut channel = self.session.channel_session()?;
+        let (width, height) = console::Term::stdout().size();
+        channel.request_pty(
+            &std::env::var("TERM").unwrap_or(String::from("xterm")),
+            None,
+            Some((width.into(), height.into(), 0, 0)),
+        )?;
+        channel.shell()?;
+
+        // Read stdin on another thread until the shell exits
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut buffer = [0u8; 1024];
+                while !stop.load(Ordering::Relaxed) {
+                    if !stdin_ready(Duration::from_millis(100)) {
+                        continue;
+                    }
+                    match std::io::stdin().read(&mut buffer) {
+                        Ok(size @ 1..) => {
+                            if sender.send(buffer[..size].to_vec()).is_err() {
+                                break;
+                            }
+                        }
+                        _ => break,
+                    }
+                }
+            }
+        });
+
+        let raw = RawTerminal::enable();
+        self.session.set_blocking(false);
+        let result = Self::forward(&mut channel, &receiver);
+        self.session.set_blocking(true);
+        drop(raw);
+
+        stop.store(true, Ordering::Relaxed);
+        #[cfg(unix)]
+        reader.join().unwrap_or_default();
+        // The reader can't be interrupted here, so it exits on the next input
+        #[cfg(not(unix))]
+        drop(reader);
+
+        result
+    }
+
+    /// Copy the shell's output to stdout and the input to the shell until the
+    /// channel closes.
+    fn forward(channel: &mut ssh2::Channel, receiver: &Receiver<Vec<u8>>) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        let mut buffer = [0u8; 4096];
+        while !channel.eof() {
+            let mut idle = true;
+
+            match channel.read(&mut buffer) {
+                Ok(0) => {}
+                Ok(size) => {
+                    stdout.write_all(&buffer[..size])?;
+                    stdout.flush()?;
+                    idle = false;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+
+            while let Ok(input) = receiver.try_recv() {
+                let mut input = input.as_slice();
+                while !input.is_empty() {
+                    match channel.write(input) {
+                        Ok(size) => input = &input[size..],
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                            sleep(Duration::from_millis(10))
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                idle = false;
+            }
+
+            if idle {
+                sleep(Duration::from_millis(10));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Wait until stdin can be read without blocking or the timeout passes.
+fn stdin_ready(timeout: Duration) -> bool {
+    #[cfg(unix)]
+    unsafe {
+        let mut fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) > 0
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = timeout;
+        true
+    }
+}
+
+/// Puts the terminal in raw mode so keystrokes go straight to the remote
+/// shell. The previous mode is restored when dropped.
+struct RawTerminal {
+    #[cfg(unix)]
+    original: Option<libc::termios>,
+}
+
+impl RawTerminal {
+    fn enable() -> Self {
+        #[cfg(unix)]
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Self { original: None };
+            }
+
+            let original = termios;
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+            Self {
+                original: Some(original),
+            }
+        }
+
+        #[cfg(not(unix))]
+        Self {}
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(original) = &self.original {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
 }
@@ -2,14 +2,36 @@
 //! we compare the state of the screen with specifications from templates in order
 //! to act on timing events.
 
+use super::{
+    dashboard::DashboardHandle,
+    keyboard::{self, KeyboardLayout},
+    qmp::{self, SharedQmpConnection},
+    recording::Recording,
+    serial::SerialConnection,
+};
+use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Result;
 use rand::Rng;
 use sha1::{Digest, Sha1};
-use std::{fs::File, io::BufWriter, net::TcpStream, path::Path, time::Duration};
-use tracing::{debug, info, trace};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, trace, warn};
 use vnc::client::Event;
 
+/// The default amount of seconds a wait command can take before failing. Waits
+/// that span an entire install should give their own timeout.
+pub const DEFAULT_WAIT_TIMEOUT: u64 = 1800;
+
+/// Waits with the default timeout fail early if the screen doesn't change for
+/// this many seconds.
+pub const STALL_TIMEOUT: u64 = 600;
+
 /// A rectangular snapshot of the entire screen or an arbitrary subsection.
 pub struct VncScreenshot {
     pub data: Vec<u8>,
@@ -23,16 +45,21 @@ impl VncScreenshot {
         hex::encode(Sha1::new().chain_update(&self.data).finalize())
     }
 
+    /// Produce a hash of the screenshot as if it was captured with one bit per
+    /// color channel, which is how older screen hashes were captured.
+    pub fn legacy_hash(&self) -> String {
+        let data: Vec<u8> = self
+            .data
+            .iter()
+            .map(|&pixel| ((pixel >> 7) << 5) | (((pixel >> 4) & 1) << 2) | ((pixel >> 1) & 1))
+            .collect();
+        hex::encode(Sha1::new().chain_update(&data).finalize())
+    }
+
     /// Write the screenshot to a png file (probably for debugging).
     pub fn write_png(&self, output_path: &Path) -> Result<()> {
         std::fs::create_dir_all(output_path.parent().unwrap())?;
-        let ref mut w = BufWriter::new(File::create(output_path)?);
-
-        let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
-        encoder.set_color(png::ColorType::Grayscale);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&self.data).unwrap();
+        self.encode_png(BufWriter::new(File::create(output_path)?))?;
 
         debug!(
             "Saved screenshot to: {:?}",
@@ -41,9 +68,162 @@ impl VncScreenshot {
         Ok(())
     }
 
-    /// Compute a percentage of how similar the given screenshot is to this one.
-    pub fn similarity(&self, _other: &VncScreenshot) -> f32 {
-        todo!();
+    /// Encode the screenshot as a color PNG.
+    pub fn encode_png(&self, output: impl Write) -> Result<()> {
+        let mut encoder = png::Encoder::new(output, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(
+            &self
+                .data
+                .iter()
+                .flat_map(|&pixel| rgb888(pixel))
+                .collect::<Vec<u8>>(),
+        )?;
+        Ok(())
+    }
+
+    /// Read a screenshot from a PNG file like the ones written by
+    /// [`VncScreenshot::write_png`].
+    pub fn from_png(data: &[u8]) -> Result<VncScreenshot> {
+        let mut reader = png::Decoder::new(data).read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+
+        if info.bit_depth != png::BitDepth::Eight {
+            bail!("Screenshots must be 8-bit PNGs");
+        }
+        buffer.truncate(info.buffer_size());
+
+        let data = match info.color_type {
+            png::ColorType::Rgb => buffer
+                .chunks_exact(3)
+                .map(|rgb| rgb332(rgb[0], rgb[1], rgb[2]))
+                .collect(),
+            png::ColorType::Rgba => buffer
+                .chunks_exact(4)
+                .map(|rgba| rgb332(rgba[0], rgba[1], rgba[2]))
+                .collect(),
+            // Older screenshots stored the raw pixels as grayscale
+            png::ColorType::Grayscale => buffer,
+            _ => bail!("Unsupported PNG color type: {:?}", info.color_type),
+        };
+
+        Ok(VncScreenshot {
+            data,
+            width: info.width.try_into()?,
+            height: info.height.try_into()?,
+        })
+    }
+
+    /// Compute how similar the given screenshot is to this one from 0 (nothing
+    /// in common) to 1 (identical). Each pixel contributes the largest
+    /// difference between its color channels, so small changes like a blinking
+    /// cursor barely affect the result.
+    pub fn similarity(&self, other: &VncScreenshot) -> f32 {
+        if self.width != other.width || self.height != other.height || self.data.is_empty() {
+            return 0.0;
+        }
+
+        let difference: f32 = self
+            .data
+            .iter()
+            .zip(&other.data)
+            .filter(|(a, b)| a != b)
+            .map(|(&a, &b)| pixel_difference(a, b))
+            .sum();
+
+        1.0 - difference / self.data.len() as f32
+    }
+
+    /// Produce a grayscale image that is brighter where the given screenshot
+    /// differs from this one.
+    pub fn diff(&self, other: &VncScreenshot) -> Result<VncScreenshot> {
+        if self.width != other.width || self.height != other.height {
+            bail!("Can't compare screenshots of different sizes");
+        }
+
+        Ok(VncScreenshot {
+            data: self
+                .data
+                .iter()
+                .zip(&other.data)
+                .map(|(&a, &b)| {
+                    let level = (pixel_difference(a, b) * 255.0) as u8;
+                    rgb332(level, level, level)
+                })
+                .collect(),
+            width: self.width,
+            height: self.height,
+        })
+    }
+
+    /// Get the brightness of each pixel from 0 to 1.
+    fn luminance(&self) -> Vec<f32> {
+        self.data
+            .iter()
+            .map(|&pixel| {
+                0.299 * (pixel >> 5) as f32 / 7.0
+                    + 0.587 * ((pixel >> 2) & 0x7) as f32 / 7.0
+                    + 0.114 * (pixel & 0x3) as f32 / 3.0
+            })
+            .collect()
+    }
+
+    /// Find where the given template appears in the screenshot using
+    /// normalized cross-correlation. The search runs on a downscaled copy
+    /// first and the best candidates are refined at full resolution.
+    pub fn find(&self, template: &VncScreenshot) -> Option<ImageMatch> {
+        if template.width > self.width || template.height > self.height || template.data.is_empty()
+        {
+            return None;
+        }
+
+        let screen = Plane::new(self.luminance(), self.width as usize, self.height as usize);
+        let patch = Plane::new(
+            template.luminance(),
+            template.width as usize,
+            template.height as usize,
+        );
+
+        // Keep at least 8 pixels of the template in each dimension
+        let factor = [4, 2, 1]
+            .into_iter()
+            .find(|factor| patch.width / factor >= 8 && patch.height / factor >= 8)
+            .unwrap_or(1);
+
+        let small_screen = screen.downscale(factor);
+        let small_patch = patch.downscale(factor);
+
+        let mut candidates = Vec::new();
+        for y in 0..=small_screen.height - small_patch.height {
+            for x in 0..=small_screen.width - small_patch.width {
+                candidates.push((small_screen.correlate(&small_patch, x, y), x, y));
+            }
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        // Search the area around the best coarse matches
+        let mut best: Option<ImageMatch> = None;
+        for &(_, x, y) in candidates.iter().take(5) {
+            let (x, y) = (x * factor, y * factor);
+            for y in y.saturating_sub(factor)..=(y + factor).min(screen.height - patch.height) {
+                for x in x.saturating_sub(factor)..=(x + factor).min(screen.width - patch.width) {
+                    let score = screen.correlate(&patch, x, y);
+                    if best.as_ref().map_or(true, |best| score > best.score) {
+                        best = Some(ImageMatch {
+                            left: x as u16,
+                            top: y as u16,
+                            width: template.width,
+                            height: template.height,
+                            score,
+                        });
+                    }
+                }
+            }
+        }
+        best
     }
 
     /// Create a trimmed screenshot according to the given dimensions
@@ -84,6 +264,152 @@ impl VncScreenshot {
     }
 }
 
+/// Pack 8-bit color channels into an RGB332 pixel.
+pub fn rgb332(r: u8, g: u8, b: u8) -> u8 {
+    (r & 0xe0) | ((g & 0xe0) >> 3) | (b >> 6)
+}
+
+/// Unpack an RGB332 pixel into 8-bit color channels.
+pub fn rgb888(pixel: u8) -> [u8; 3] {
+    [
+        ((pixel >> 5) as u16 * 255 / 7) as u8,
+        (((pixel >> 2) & 0x7) as u16 * 255 / 7) as u8,
+        ((pixel & 0x3) as u16 * 255 / 3) as u8,
+    ]
+}
+
+/// Get the largest difference between the color channels of two RGB332 pixels
+/// from 0 to 1.
+fn pixel_difference(a: u8, b: u8) -> f32 {
+    // Split an RGB332 pixel into normalized channels
+    fn channels(pixel: u8) -> [f32; 3] {
+        [
+            (pixel >> 5) as f32 / 7.0,
+            ((pixel >> 2) & 0x7) as f32 / 7.0,
+            (pixel & 0x3) as f32 / 3.0,
+        ]
+    }
+
+    let (a, b) = (channels(a), channels(b));
+    (0..3).map(|i| (a[i] - b[i]).abs()).fold(0.0, f32::max)
+}
+
+/// Where a template was found on the screen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageMatch {
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+
+    /// The normalized cross-correlation from -1 to 1
+    pub score: f32,
+}
+
+impl ImageMatch {
+    /// The center of the match.
+    pub fn center(&self) -> (u16, u16) {
+        (self.left + self.width / 2, self.top + self.height / 2)
+    }
+}
+
+/// A grid of brightness values.
+struct Plane {
+    data: Vec<f32>,
+    width: usize,
+    height: usize,
+}
+
+impl Plane {
+    fn new(data: Vec<f32>, width: usize, height: usize) -> Self {
+        Self {
+            data,
+            width,
+            height,
+        }
+    }
+
+    /// Shrink by averaging blocks of the given size.
+    fn downscale(&self, factor: usize) -> Plane {
+        if factor == 1 {
+            return Plane::new(self.data.clone(), self.width, self.height);
+        }
+
+        let (width, height) = (self.width / factor, self.height / factor);
+        let mut data = vec![0.0; width * height];
+        for y in 0..height * factor {
+            for x in 0..width * factor {
+                data[(y / factor) * width + x / factor] += self.data[y * self.width + x];
+            }
+        }
+        let area = (factor * factor) as f32;
+        data.iter_mut().for_each(|value| *value /= area);
+        Plane::new(data, width, height)
+    }
+
+    /// Compute the normalized cross-correlation of the patch placed at the
+    /// given position.
+    fn correlate(&self, patch: &Plane, x: usize, y: usize) -> f32 {
+        let count = (patch.width * patch.height) as f32;
+        let mut sum = 0.0;
+        for row in 0..patch.height {
+            let start = (y + row) * self.width + x;
+            sum += self.data[start..start + patch.width].iter().sum::<f32>();
+        }
+        let mean = sum / count;
+        let patch_mean = patch.data.iter().sum::<f32>() / count;
+
+        let (mut product, mut variance, mut patch_variance) = (0.0, 0.0, 0.0);
+        for row in 0..patch.height {
+            let start = (y + row) * self.width + x;
+            for (value, patch_value) in self.data[start..start + patch.width]
+                .iter()
+                .zip(&patch.data[row * patch.width..(row + 1) * patch.width])
+            {
+                let (a, b) = (value - mean, patch_value - patch_mean);
+                product += a * b;
+                variance += a * a;
+                patch_variance += b * b;
+            }
+        }
+
+        if variance == 0.0 || patch_variance == 0.0 {
+            // Flat areas only match flat templates
+            return if variance == patch_variance { 1.0 } else { 0.0 };
+        }
+        product / (variance * patch_variance).sqrt()
+    }
+}
+
+/// A reference screenshot that the screen is compared against.
+#[derive(Clone)]
+pub struct ReferenceScreen {
+    pub name: String,
+
+    /// The PNG contents
+    pub data: Vec<u8>,
+}
+
+impl ReferenceScreen {
+    /// Load a reference screenshot from a PNG file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            name: path.as_ref().to_string_lossy().to_string(),
+            data: std::fs::read(path)?,
+        })
+    }
+
+    pub fn screenshot(&self) -> Result<VncScreenshot> {
+        VncScreenshot::from_png(&self.data)
+    }
+}
+
+impl std::fmt::Debug for ReferenceScreen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ReferenceScreen({})", self.name)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum VncCmd {
     /// Input the enter key.
@@ -112,6 +438,109 @@ pub enum VncCmd {
 
     /// Wait for a subsection of the screen to match the given hash.
     WaitScreenRect(String, u16, u16, u16, u16),
+
+    /// Wait for the screen to be at least as similar to the reference as the
+    /// given threshold.
+    WaitScreenSimilar(ReferenceScreen, f32),
+
+    /// Wait for the subsection of the screen at the given top and left offsets
+    /// to be similar to the reference. The reference determines the size of the
+    /// subsection.
+    WaitScreenRectSimilar(ReferenceScreen, f32, u16, u16),
+
+    /// Wait for the reference to appear anywhere on the screen with at least
+    /// the given match score.
+    WaitImage(ReferenceScreen, f32),
+
+    /// Wait for the reference to appear anywhere on the screen and click its
+    /// center. The VM needs an absolute pointing device (see
+    /// `QemuBuilder::tablet`).
+    ClickImage(ReferenceScreen, f32),
+
+    /// Press a chord of X key names like "ctrl+alt+Delete" or "F2".
+    Chord(String),
+
+    /// Move the pointer to the given position.
+    MouseMove(u16, u16),
+
+    /// Click the given button at the given position.
+    Click(MouseButton, u16, u16),
+
+    /// Scroll the wheel by the given amount of steps at the pointer's position.
+    /// Positive amounts scroll down.
+    Scroll(i16),
+
+    /// Run the given wait command with its own timeout in seconds instead of
+    /// the default.
+    WithTimeout(u64, Box<VncCmd>),
+
+    /// Switch the keyboard layout that `Type` assumes the guest is using.
+    Layout(KeyboardLayout),
+
+    /// Wait for the output of the serial console to match the given regex.
+    /// Only output since the last match is considered.
+    Expect(String),
+
+    /// Send the given text followed by a newline to the serial console.
+    SendLine(String),
+}
+
+impl VncCmd {
+    /// Describe the command for recordings, logs, and the dashboard. Typed text
+    /// is left out since it often contains passwords.
+    pub fn describe(&self) -> String {
+        match self {
+            VncCmd::Type(_) => String::from("Type(<redacted>)"),
+            VncCmd::SendLine(_) => String::from("SendLine(<redacted>)"),
+            VncCmd::WithTimeout(seconds, step) => {
+                format!("WithTimeout({}, {})", seconds, step.describe())
+            }
+            _ => format!("{:?}", self),
+        }
+    }
+}
+
+/// Tracks how long the screen has stayed the same during a wait.
+struct StallDetector {
+    hash: String,
+    since: Instant,
+}
+
+impl StallDetector {
+    fn new() -> Self {
+        Self {
+            hash: String::new(),
+            since: Instant::now(),
+        }
+    }
+
+    /// Check the latest screen and return whether it hasn't changed for too
+    /// long.
+    fn stalled(&mut self, screen: &VncScreenshot) -> bool {
+        let hash = screen.hash();
+        if hash != self.hash {
+            self.hash = hash;
+            self.since = Instant::now();
+        }
+        self.since.elapsed() > Duration::from_secs(STALL_TIMEOUT)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+impl MouseButton {
+    fn mask(&self) -> u8 {
+        match self {
+            MouseButton::Left => 1,
+            MouseButton::Middle => 2,
+            MouseButton::Right => 4,
+        }
+    }
 }
 
 /// Represents a VNC session to a running VM.
@@ -121,6 +550,37 @@ pub struct VncConnection {
     pub vnc: vnc::Client,
     pub record: bool,
     pub debug: bool,
+
+    /// The guest's keyboard layout
+    pub layout: KeyboardLayout,
+
+    /// The last position the pointer was moved to
+    pointer: (u16, u16),
+
+    /// How long waits can take before failing
+    pub timeout: Duration,
+
+    /// Where screenshots are saved when a wait times out
+    pub screenshots: PathBuf,
+
+    /// Identifies the sequence of commands in errors (usually the mold)
+    pub name: String,
+
+    /// The number of the current step and its description
+    step: usize,
+    command: String,
+
+    /// Frames captured during the session when recording
+    pub recording: Option<Recording>,
+
+    /// Where progress is reported when the dashboard is running
+    pub dashboard: Option<DashboardHandle>,
+
+    /// Used to watch the run state of the VM during waits
+    pub qmp: Option<SharedQmpConnection>,
+
+    /// The serial console session if the VM has one
+    pub serial: Option<SerialConnection>,
 }
 
 impl VncConnection {
@@ -139,9 +599,9 @@ impl VncConnection {
             depth: 8,
             big_endian: false,
             true_colour: true,
-            red_max: 8,
-            green_max: 8,
-            blue_max: 4,
+            red_max: 7,
+            green_max: 7,
+            blue_max: 3,
             red_shift: 5,
             green_shift: 2,
             blue_shift: 0,
@@ -156,10 +616,50 @@ impl VncConnection {
             vnc,
             record,
             debug,
+            layout: KeyboardLayout::Us,
+            pointer: (0, 0),
+            timeout: Duration::from_secs(DEFAULT_WAIT_TIMEOUT),
+            screenshots: PathBuf::from("screenshots"),
+            name: String::from("vnc"),
+            step: 0,
+            command: String::new(),
+            recording: None,
+            dashboard: None,
+            qmp: None,
+            serial: None,
         })
     }
 
+    /// Get the serial console session.
+    pub fn serial(&mut self) -> Result<&mut SerialConnection> {
+        match self.serial.as_mut() {
+            Some(serial) => Ok(serial),
+            None => bail!("The VM has no serial console"),
+        }
+    }
+
+    /// Capture the screen, adding it to the recording if there is one.
     pub fn screenshot(&mut self) -> Result<VncScreenshot> {
+        let screenshot = match (self.read_screen(), &self.qmp) {
+            (Ok(screenshot), _) => screenshot,
+            (Err(err), Some(qmp)) => {
+                warn!(error = %err, "Failed to read the screen over VNC; using a screendump");
+                qmp.lock().unwrap().screenshot()?
+            }
+            (Err(err), None) => return Err(err),
+        };
+        if let Some(recording) = self.recording.as_mut() {
+            recording.capture(&screenshot, self.step, &self.command)?;
+        }
+        if let Some(dashboard) = &self.dashboard {
+            let mut png = Vec::new();
+            screenshot.encode_png(&mut png)?;
+            dashboard.screen(png);
+        }
+        Ok(screenshot)
+    }
+
+    fn read_screen(&mut self) -> Result<VncScreenshot> {
         // Attempt to clear the framebuffer, but don't discard any resize events
         for event in self.vnc.poll_iter() {
             match event {
@@ -206,13 +706,18 @@ impl VncConnection {
 
     fn handle_breakpoint(&mut self, cmd: &VncCmd) -> Result<()> {
         loop {
-            info!(
-                "(breakpoint)['c' to continue, 's' to screenshot, 'q' to quit debugging] Next command: {:?}",
-                cmd
-            );
-
             let mut line = String::new();
-            std::io::stdin().read_line(&mut line).unwrap();
+            match &self.dashboard {
+                // Parallel workers can't share the terminal
+                Some(dashboard) => line.push_str(dashboard.breakpoint(&cmd.describe()).input()),
+                None => {
+                    info!(
+                        "(breakpoint)['c' to continue, 's' to screenshot, 'q' to quit debugging] Next command: {}",
+                        cmd.describe()
+                    );
+                    std::io::stdin().read_line(&mut line).unwrap();
+                }
+            }
             let mut words = line.split_whitespace();
 
             match words.next() {
@@ -248,13 +753,276 @@ impl VncConnection {
         }
     }
 
+    /// Click the left mouse button at the given position.
+    pub fn click(&mut self, x: u16, y: u16) -> Result<()> {
+        self.click_button(MouseButton::Left, x, y)
+    }
+
+    /// Click the given mouse button at the given position.
+    pub fn click_button(&mut self, button: MouseButton, x: u16, y: u16) -> Result<()> {
+        self.move_pointer(x, y)?;
+        self.vnc.send_pointer_event(button.mask(), x, y)?;
+        std::thread::sleep(Duration::from_millis(100));
+        self.vnc.send_pointer_event(0, x, y)?;
+        Ok(())
+    }
+
+    /// Move the pointer without pressing any buttons.
+    pub fn move_pointer(&mut self, x: u16, y: u16) -> Result<()> {
+        self.vnc.send_pointer_event(0, x, y)?;
+        self.pointer = (x, y);
+        Ok(())
+    }
+
+    /// Turn the scroll wheel at the pointer's position.
+    pub fn scroll(&mut self, steps: i16) -> Result<()> {
+        let (x, y) = self.pointer;
+
+        // The wheel is buttons 4 (up) and 5 (down)
+        let mask = if steps < 0 { 8 } else { 16 };
+        for _ in 0..steps.unsigned_abs() {
+            self.vnc.send_pointer_event(mask, x, y)?;
+            self.vnc.send_pointer_event(0, x, y)?;
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    /// Press all of the given keys in order and release them in reverse.
+    pub fn chord(&mut self, keysyms: &[u32]) -> Result<()> {
+        for keysym in keysyms {
+            self.vnc.send_key_event(true, *keysym)?;
+        }
+        for keysym in keysyms.iter().rev() {
+            self.vnc.send_key_event(false, *keysym)?;
+        }
+        Ok(())
+    }
+
+    /// Type text according to the guest's keyboard layout.
+    pub fn type_text(&mut self, text: &str) -> Result<()> {
+        for ch in text.chars() {
+            match ch {
+                '\n' => self.chord(&[keyboard::RETURN])?,
+                '\t' => self.chord(&[keyboard::TAB])?,
+                _ => {
+                    let stroke = self.layout.keystroke(ch)?;
+                    let mut keysyms = Vec::new();
+                    if stroke.shift {
+                        keysyms.push(keyboard::SHIFT);
+                    }
+                    if stroke.alt_gr {
+                        keysyms.push(keyboard::ALT_GR);
+                    }
+                    keysyms.push(stroke.keysym);
+                    self.chord(&keysyms)?;
+
+                    if stroke.dead {
+                        self.chord(&[keyboard::SPACE])?;
+                    }
+                }
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    /// Check whether a wait should give up. Returns the reason if it should.
+    /// Without an explicit timeout, the default applies and a screen that stops
+    /// changing also ends the wait.
+    fn expired(
+        &self,
+        start: Instant,
+        timeout: Option<Duration>,
+        stall: &mut StallDetector,
+        screen: &VncScreenshot,
+    ) -> Option<String> {
+        let limit = timeout.unwrap_or(self.timeout);
+        if start.elapsed() > limit {
+            return Some(format!("timed out after {} seconds", limit.as_secs()));
+        }
+
+        // Explicit timeouts are trusted even if the screen stays the same
+        if timeout.is_none() && stall.stalled(screen) {
+            return Some(format!(
+                "stalled with no change on the screen for {} seconds",
+                STALL_TIMEOUT
+            ));
+        }
+        None
+    }
+
+    /// Fail a wait that took too long, saving the current screen and what it
+    /// was expected to look like.
+    fn timed_out(
+        &self,
+        reason: &str,
+        actual: &VncScreenshot,
+        expected: Option<&VncScreenshot>,
+    ) -> anyhow::Error {
+        let path = |kind: &str| {
+            self.screenshots
+                .join(format!("{}-{}-{kind}.png", self.name, self.step))
+        };
+
+        let saved = actual
+            .write_png(&path("actual"))
+            .and_then(|_| match expected {
+                Some(expected) => {
+                    expected.write_png(&path("expected"))?;
+                    actual.diff(expected)?.write_png(&path("diff"))
+                }
+                None => Ok(()),
+            });
+        if let Err(err) = saved {
+            warn!(error = %err, "Failed to save timeout screenshots");
+        }
+
+        anyhow!(
+            "{} step {} {}: {}; screenshots saved in {}",
+            self.name,
+            self.step,
+            reason,
+            self.command,
+            self.screenshots.display()
+        )
+    }
+
+    /// Wait for the screen (or the given subsection of it) to match the hash.
+    fn wait_hash(
+        &mut self,
+        hash: &str,
+        rect: Option<vnc::Rect>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        debug!("Waiting for screen hash to equal: {}", hash);
+        let start = Instant::now();
+        let mut stall = StallDetector::new();
+
+        loop {
+            std::thread::sleep(Duration::from_millis(
+                rand::thread_rng().gen_range(500..1000),
+            ));
+
+            qmp::check_state(self.qmp.as_ref())?;
+            let screen = self.screenshot()?;
+
+            // If the trim fails, the screen may not be the right size yet
+            let trimmed = rect.and_then(|rect| screen.trim(rect).ok());
+            let screenshot = trimmed.as_ref().unwrap_or(&screen);
+
+            if screenshot.hash() == hash || screenshot.legacy_hash() == hash {
+                // Don't continue immediately
+                std::thread::sleep(Duration::from_secs(1));
+                return Ok(());
+            } else if let Some(reason) = self.expired(start, timeout, &mut stall, &screen) {
+                // Only the hash of the expected screen is known
+                return Err(self.timed_out(&reason, screenshot, None));
+            }
+        }
+    }
+
+    /// Wait for the reference to appear anywhere on the screen.
+    fn wait_image(
+        &mut self,
+        reference: &ReferenceScreen,
+        threshold: f32,
+        timeout: Option<Duration>,
+    ) -> Result<ImageMatch> {
+        debug!(reference = ?reference, threshold, "Waiting for image to appear");
+        let template = reference.screenshot()?;
+        let start = Instant::now();
+        let mut stall = StallDetector::new();
+
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+
+            qmp::check_state(self.qmp.as_ref())?;
+            let screen = self.screenshot()?;
+            let found = screen.find(&template);
+            if let Some(found) = &found {
+                trace!(found = ?found, "Searched screen for image");
+                if found.score >= threshold {
+                    return Ok(found.clone());
+                }
+            }
+
+            if let Some(reason) = self.expired(start, timeout, &mut stall, &screen) {
+                // Compare against the closest match if there was one
+                let closest = found.and_then(|found| {
+                    screen
+                        .trim(vnc::Rect {
+                            top: found.top,
+                            left: found.left,
+                            width: found.width,
+                            height: found.height,
+                        })
+                        .ok()
+                });
+                return Err(self.timed_out(
+                    &reason,
+                    closest.as_ref().unwrap_or(&screen),
+                    Some(&template),
+                ));
+            }
+        }
+    }
+
+    /// Wait for the screen (or the subsection at the given offsets) to be
+    /// similar enough to the reference.
+    fn wait_similar(
+        &mut self,
+        reference: &ReferenceScreen,
+        threshold: f32,
+        offset: Option<(u16, u16)>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        debug!(reference = ?reference, threshold, "Waiting for screen to be similar");
+        let expected = reference.screenshot()?;
+        let start = Instant::now();
+        let mut stall = StallDetector::new();
+
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+
+            qmp::check_state(self.qmp.as_ref())?;
+            let screen = self.screenshot()?;
+
+            // If the trim fails, the screen may not be the right size yet
+            let trimmed = offset.and_then(|(top, left)| {
+                screen
+                    .trim(vnc::Rect {
+                        top,
+                        left,
+                        width: expected.width,
+                        height: expected.height,
+                    })
+                    .ok()
+            });
+            let screenshot = trimmed.as_ref().unwrap_or(&screen);
+
+            let similarity = screenshot.similarity(&expected);
+            trace!(similarity, "Compared screen to reference");
+            if similarity >= threshold {
+                // Wait a few before continuing
+                std::thread::sleep(Duration::from_secs(1));
+                return Ok(());
+            } else if let Some(reason) = self.expired(start, timeout, &mut stall, &screen) {
+                return Err(self.timed_out(&reason, screenshot, Some(&expected)));
+            }
+        }
+    }
+
     /// Run the given sequence of VNC commands.
     pub fn run(&mut self, commands: Vec<Vec<VncCmd>>) -> Result<()> {
         info!("Running VNC commands");
 
-        let mut cmd_number = 0;
-        for cmd in commands {
-            cmd_number += 1;
+        if self.record && self.recording.is_none() {
+            self.recording = Some(Recording::new(&self.screenshots, &self.name)?);
+        }
+
+        for (index, cmd) in commands.into_iter().enumerate() {
+            self.step = index + 1;
             for step in cmd {
                 if self.debug {
                     match &step {
@@ -262,127 +1030,104 @@ impl VncConnection {
                         _ => self.handle_breakpoint(&step)?,
                     }
                 }
-                match step {
-                    VncCmd::Type(ref text) => {
-                        for ch in text.chars() {
-                            if ch == '\n' {
-                                self.vnc.send_key_event(true, 0xff0d)?;
-                                self.vnc.send_key_event(false, 0xff0d)?;
-                            } else if ch.is_uppercase() {
-                                self.vnc.send_key_event(true, 0xffe1)?;
-                                self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-                                self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-                                self.vnc.send_key_event(false, 0xffe1)?;
-                            } else {
-                                match ch {
-                                    '~' | '!' | '#' | '$' | '%' | '^' | '&' | '*' | '(' | ')'
-                                    | '_' | '+' | '{' | '}' | '|' | ':' | '"' | '<' | '>' | '?' => {
-                                        self.vnc.send_key_event(true, 0xffe1)?;
-                                        self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-                                        self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-                                        self.vnc.send_key_event(false, 0xffe1)?;
-                                    }
-                                    _ => {
-                                        self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-                                        self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-                                    }
-                                }
-                            }
-                            std::thread::sleep(Duration::from_millis(50));
-                        }
-                    }
-                    VncCmd::Wait(duration) => {
-                        debug!("Waiting {} seconds", &duration);
-                        std::thread::sleep(Duration::from_secs(duration));
-                    }
-                    VncCmd::WaitScreen(hash) => {
-                        debug!("Waiting for screen hash to equal: {}", &hash);
-
-                        // Track the screen hash over time because we should
-                        // exit if nothing happens for a really long time.
-                        let mut running_hash = String::new();
-                        let mut running_count = 0;
-                        let mut total_count = 0;
-
-                        loop {
-                            total_count += 1;
-                            running_count += 1;
-
-                            std::thread::sleep(Duration::from_millis(
-                                rand::thread_rng().gen_range(500..1000),
-                            ));
-
-                            let screenshot = self.screenshot()?;
-                            if screenshot.hash() == hash {
-                                debug!(total_count, "Finished screen wait");
-
-                                // Don't continue immediately
-                                std::thread::sleep(Duration::from_secs(1));
-                                break;
-                            } else if screenshot.hash() == running_hash {
-                                // TODO configurable
-                                if running_count > 600 {
-                                    // Write out the screen that's frozen
-                                    screenshot.write_png(&Path::new("screenshots/frozen.png"))?;
-                                    bail!("Screen has not changed in 600 sec");
-                                }
-                            } else {
-                                running_hash = screenshot.hash();
-                                running_count = 0;
-                            }
-                        }
-                    }
-                    VncCmd::WaitScreenRect(hash, top, left, width, height) => {
-                        debug!("Waiting for screen hash to equal: {}", &hash);
-                        loop {
-                            std::thread::sleep(Duration::from_secs(1));
-                            match self.screenshot()?.trim(vnc::Rect {
-                                top,
-                                left,
-                                width,
-                                height,
-                            }) {
-                                Ok(screenshot) => {
-                                    if screenshot.hash() == hash {
-                                        // Wait a few before continuing
-                                        std::thread::sleep(Duration::from_secs(1));
-                                        break;
-                                    }
-                                }
-                                // If the trim failed, the screen may not be the right size yet
-                                Err(_) => continue,
-                            }
-                        }
-                    }
-                    VncCmd::Enter => {
-                        self.vnc.send_key_event(true, 0xff0d)?;
-                        self.vnc.send_key_event(false, 0xff0d)?;
-                    }
-                    VncCmd::Tab => {
-                        self.vnc.send_key_event(true, 0xff09)?;
-                        self.vnc.send_key_event(false, 0xff09)?;
-                    }
-                    VncCmd::Spacebar => {
-                        self.vnc.send_key_event(true, 0x0020)?;
-                        self.vnc.send_key_event(false, 0x0020)?;
-                    }
-                    VncCmd::LeftSuper => {
-                        self.vnc.send_key_event(true, 0xffeb)?;
-                        self.vnc.send_key_event(false, 0xffeb)?;
-                    }
-                    VncCmd::Escape => {
-                        self.vnc.send_key_event(true, 0xff1b)?;
-                        self.vnc.send_key_event(false, 0xff1b)?;
-                    }
+                self.command = step.describe();
+                if let Some(dashboard) = &self.dashboard {
+                    dashboard.step(self.step, &self.command);
                 }
-                if self.record {
-                    self.screenshot()?
-                        .write_png(&Path::new(&format!("screenshots/{cmd_number}.png")))?;
+                self.execute(step, None)?;
+
+                if self.recording.is_some() {
+                    self.screenshot()?;
                 }
             }
         }
         Ok(())
     }
+
+    /// Run a single command, failing any waits that exceed the timeout (or the
+    /// default timeout if none is given).
+    fn execute(&mut self, step: VncCmd, timeout: Option<Duration>) -> Result<()> {
+        match step {
+            VncCmd::Type(ref text) => self.type_text(text)?,
+            VncCmd::Wait(duration) => {
+                debug!("Waiting {} seconds", &duration);
+                for _ in 0..duration {
+                    std::thread::sleep(Duration::from_secs(1));
+                    qmp::check_state(self.qmp.as_ref())?;
+
+                    // Keep recording while nothing else is happening
+                    if self.recording.is_some() {
+                        self.screenshot()?;
+                    }
+                }
+            }
+            VncCmd::WaitScreen(hash) => self.wait_hash(&hash, None, timeout)?,
+            VncCmd::WaitScreenRect(hash, top, left, width, height) => {
+                let rect = vnc::Rect {
+                    top,
+                    left,
+                    width,
+                    height,
+                };
+                self.wait_hash(&hash, Some(rect), timeout)?;
+            }
+            VncCmd::WaitScreenSimilar(reference, threshold) => {
+                self.wait_similar(&reference, threshold, None, timeout)?;
+            }
+            VncCmd::WaitScreenRectSimilar(reference, threshold, top, left) => {
+                self.wait_similar(&reference, threshold, Some((top, left)), timeout)?;
+            }
+            VncCmd::WaitImage(reference, threshold) => {
+                self.wait_image(&reference, threshold, timeout)?;
+            }
+            VncCmd::ClickImage(reference, threshold) => {
+                let (x, y) = self.wait_image(&reference, threshold, timeout)?.center();
+                debug!(x, y, "Clicking image");
+                self.click(x, y)?;
+            }
+            VncCmd::WithTimeout(seconds, step) => {
+                self.execute(*step, Some(Duration::from_secs(seconds)))?;
+            }
+            VncCmd::Chord(ref chord) => {
+                debug!(chord, "Pressing keys");
+                self.chord(&keyboard::chord(chord)?)?;
+            }
+            VncCmd::MouseMove(x, y) => self.move_pointer(x, y)?,
+            VncCmd::Click(button, x, y) => self.click_button(button, x, y)?,
+            VncCmd::Scroll(steps) => self.scroll(steps)?,
+            VncCmd::Layout(layout) => {
+                debug!(?layout, "Switching keyboard layout");
+                self.layout = layout;
+            }
+            VncCmd::Expect(ref pattern) => {
+                debug!(pattern, "Waiting for serial output");
+                let timeout = timeout.unwrap_or(self.timeout);
+                self.serial()?.expect(pattern, timeout)?;
+            }
+            VncCmd::SendLine(ref text) => self.serial()?.send(&format!("{text}\n"))?,
+            VncCmd::Enter => {
+                self.vnc.send_key_event(true, 0xff0d)?;
+                self.vnc.send_key_event(false, 0xff0d)?;
+            }
+            VncCmd::Tab => {
+                self.vnc.send_key_event(true, 0xff09)?;
+                self.vnc.send_key_event(false, 0xff09)?;
+            }
+            VncCmd::Spacebar => {
+                self.vnc.send_key_event(true, 0x0020)?;
+                self.vnc.send_key_event(false, 0x0020)?;
+            }
+            VncCmd::LeftSuper => {
+                self.vnc.send_key_event(true, 0xffeb)?;
+                self.vnc.send_key_event(false, 0xffeb)?;
+            }
+            VncCmd::Escape => {
+                self.vnc.send_key_event(true, 0xff1b)?;
+                self.vnc.send_key_event(false, 0xff1b)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub mod macros {
@@ -466,7 +1211,83 @@ pub mod macros {
     #[macro_export]
     macro_rules! wait_screen {
         ($hash:expr) => {
-            vec![crate::foundry::vnc::VncCmd::WaitScreen($hash.to_string())]
+            vec![$crate::foundry::vnc::VncCmd::WaitScreen($hash.to_string())]
+        };
+        ($hash:expr, $timeout:expr) => {
+            vec![$crate::foundry::vnc::VncCmd::WithTimeout(
+                $timeout,
+                Box::new($crate::foundry::vnc::VncCmd::WaitScreen($hash.to_string())),
+            )]
+        };
+    }
+
+    /// Wait for the screen to be similar to a PNG next to the calling file.
+    /// References can be captured with the screenshot command in debug mode.
+    #[macro_export]
+    macro_rules! wait_screen_similar {
+        ($path:expr, $threshold:expr) => {
+            vec![$crate::foundry::vnc::VncCmd::WaitScreenSimilar(
+                $crate::foundry::vnc::ReferenceScreen {
+                    name: $path.to_string(),
+                    data: include_bytes!($path).to_vec(),
+                },
+                $threshold,
+            )]
+        };
+        ($path:expr, $threshold:expr, $top:expr, $left:expr) => {
+            vec![$crate::foundry::vnc::VncCmd::WaitScreenRectSimilar(
+                $crate::foundry::vnc::ReferenceScreen {
+                    name: $path.to_string(),
+                    data: include_bytes!($path).to_vec(),
+                },
+                $threshold,
+                $top,
+                $left,
+            )]
+        };
+    }
+
+    /// Wait for a PNG next to the calling file to appear anywhere on the
+    /// screen.
+    #[macro_export]
+    macro_rules! wait_image {
+        ($path:expr, $threshold:expr) => {
+            vec![$crate::foundry::vnc::VncCmd::WaitImage(
+                $crate::foundry::vnc::ReferenceScreen {
+                    name: $path.to_string(),
+                    data: include_bytes!($path).to_vec(),
+                },
+                $threshold,
+            )]
+        };
+        ($path:expr, $threshold:expr, $timeout:expr) => {
+            vec![$crate::foundry::vnc::VncCmd::WithTimeout(
+                $timeout,
+                Box::new($crate::foundry::vnc::VncCmd::WaitImage(
+                    $crate::foundry::vnc::ReferenceScreen {
+                        name: $path.to_string(),
+                        data: include_bytes!($path).to_vec(),
+                    },
+                    $threshold,
+                )),
+            )]
+        };
+    }
+
+    /// Click on a PNG next to the calling file once it appears on the screen.
+    #[macro_export]
+    macro_rules! click_image {
+        ($path:expr, $threshold:expr) => {
+            vec![
+                $crate::foundry::vnc::VncCmd::ClickImage(
+                    $crate::foundry::vnc::ReferenceScreen {
+                        name: $path.to_string(),
+                        data: include_bytes!($path).to_vec(),
+                    },
+                    $threshold,
+                ),
+                $crate::foundry::vnc::VncCmd::Wait(2),
+            ]
         };
     }
 
@@ -493,6 +1314,60 @@ pub mod macros {
         };
     }
 
+    #[macro_export]
+    macro_rules! keys {
+        ($chord:expr) => {
+            vec![
+                $crate::foundry::vnc::VncCmd::Chord($chord.to_string()),
+                $crate::foundry::vnc::VncCmd::Wait(2),
+            ]
+        };
+    }
+
+    #[macro_export]
+    macro_rules! click {
+        ($x:expr, $y:expr) => {
+            vec![
+                $crate::foundry::vnc::VncCmd::Click(
+                    $crate::foundry::vnc::MouseButton::Left,
+                    $x,
+                    $y,
+                ),
+                $crate::foundry::vnc::VncCmd::Wait(2),
+            ]
+        };
+        ($button:ident, $x:expr, $y:expr) => {
+            vec![
+                $crate::foundry::vnc::VncCmd::Click(
+                    $crate::foundry::vnc::MouseButton::$button,
+                    $x,
+                    $y,
+                ),
+                $crate::foundry::vnc::VncCmd::Wait(2),
+            ]
+        };
+    }
+
+    #[macro_export]
+    macro_rules! scroll {
+        ($x:expr, $y:expr, $steps:expr) => {
+            vec![
+                $crate::foundry::vnc::VncCmd::MouseMove($x, $y),
+                $crate::foundry::vnc::VncCmd::Scroll($steps),
+                $crate::foundry::vnc::VncCmd::Wait(1),
+            ]
+        };
+    }
+
+    #[macro_export]
+    macro_rules! layout {
+        ($layout:ident) => {
+            vec![$crate::foundry::vnc::VncCmd::Layout(
+                $crate::foundry::keyboard::KeyboardLayout::$layout,
+            )]
+        };
+    }
+
     #[macro_export]
     macro_rules! leftSuper {
         () => {
@@ -503,3 +1378,131 @@ pub mod macros {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity() -> Result<()> {
+        let screen = VncScreenshot {
+            data: (0..10000).map(|i| (i % 256) as u8).collect(),
+            width: 100,
+            height: 100,
+        };
+        assert_eq!(screen.similarity(&screen), 1.0);
+
+        // A small change like a cursor barely matters
+        let mut cursor = VncScreenshot {
+            data: screen.data.clone(),
+            ..screen
+        };
+        cursor.data[5000..5010].fill(0xff);
+        let similarity = screen.similarity(&cursor);
+        assert!(similarity < 1.0 && similarity > 0.99);
+
+        // The diff only lights up where the cursor is
+        let diff = screen.diff(&cursor)?;
+        assert!(diff.data[..5000].iter().all(|&pixel| pixel == 0));
+        assert!(diff.data[5000..5010].iter().any(|&pixel| pixel > 0));
+        assert!(screen
+            .diff(&screen.trim(vnc::Rect {
+                left: 0,
+                top: 0,
+                width: 10,
+                height: 10
+            })?)
+            .is_err());
+
+        // An inverted screen has nothing in common
+        let inverted = VncScreenshot {
+            data: screen.data.iter().map(|pixel| !pixel).collect(),
+            ..screen
+        };
+        assert!(screen.similarity(&inverted) < 0.5);
+
+        // Older hashes only kept the top bit of each channel
+        let legacy = VncScreenshot {
+            data: vec![rgb332(0xff, 0xff, 0xff), rgb332(0x80, 0x7f, 0xc0)],
+            width: 2,
+            height: 1,
+        };
+        assert_eq!(
+            legacy.legacy_hash(),
+            hex::encode(Sha1::new().chain_update([0x25, 0x21]).finalize())
+        );
+        assert_eq!(
+            screen.similarity(&screen.trim(vnc::Rect {
+                left: 0,
+                top: 0,
+                width: 10,
+                height: 10
+            })?),
+            0.0
+        );
+
+        // References are stored as PNGs
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("reference.png");
+        screen.write_png(&path)?;
+        assert_eq!(
+            ReferenceScreen::open(&path)?
+                .screenshot()?
+                .similarity(&screen),
+            1.0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            VncCmd::Type(String::from("hunter2")).describe(),
+            "Type(<redacted>)"
+        );
+        assert_eq!(
+            VncCmd::WithTimeout(60, Box::new(VncCmd::SendLine(String::from("hunter2")))).describe(),
+            "WithTimeout(60, SendLine(<redacted>))"
+        );
+        assert_eq!(VncCmd::Wait(5).describe(), "Wait(5)");
+    }
+
+    #[test]
+    fn test_find() {
+        // A noisy background with a distinct patch in it
+        let mut seed: u32 = 1;
+        let mut screen = VncScreenshot {
+            data: (0..640 * 480)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as u8 & 0x49
+                })
+                .collect(),
+            width: 640,
+            height: 480,
+        };
+        for y in 0..40 {
+            for x in 0..120 {
+                screen.data[(300 + y) * 640 + 213 + x] = if (x / 10 + y / 10) % 2 == 0 {
+                    0xff
+                } else {
+                    0x00
+                };
+            }
+        }
+
+        let template = screen
+            .trim(vnc::Rect {
+                left: 213,
+                top: 300,
+                width: 120,
+                height: 40,
+            })
+            .unwrap();
+
+        let found = screen.find(&template).unwrap();
+        assert_eq!((found.left, found.top), (213, 300));
+        assert!(found.score > 0.99);
+        assert_eq!(found.center(), (273, 320));
+    }
+}
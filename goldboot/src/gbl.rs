@@ -1,4 +1,140 @@
+//! goldboot Linux (GBL) media is a bootable USB stick that runs the goldboot
+//! GUI in fullscreen. Images are carried on a separate data partition which GBL
+//! mounts at the image library location, so target machines can be flashed
+//! without a network connection.
 
-pub fn merge(qcow_images: Vec<Path>) {
-    
-}
\ No newline at end of file
+use crate::{
+    cli::progress::ProgressBar,
+    device::{reread_partitions, BlockDevice, LoopDevice, Mount, PartitionTable},
+    library::ImageLibrary,
+};
+use anyhow::{anyhow, bail, Result};
+use goldboot_image::ImageHandle;
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+use tracing::{debug, info};
+
+/// The filesystem label of the data partition.
+pub const DATA_LABEL: &str = "goldboot-data";
+
+/// The name of GBL images in the image library.
+pub const GBL_NAME: &str = "goldboot-linux";
+
+/// Space reserved on the data partition for filesystem overhead.
+const DATA_OVERHEAD: u64 = 64 * 1024 * 1024;
+
+/// Find the newest GBL image in the image library.
+pub fn find_base() -> Result<ImageHandle> {
+    ImageLibrary::find_by_name(GBL_NAME)?
+        .into_iter()
+        .max_by_key(|image| image.primary_header.timestamp)
+        .ok_or_else(|| anyhow!("No {} image found in the library", GBL_NAME))
+}
+
+/// The total number of bytes needed for GBL media containing the given images.
+pub fn required_size(base: &ImageHandle, images: &[ImageHandle]) -> u64 {
+    base.primary_header.size
+        + images.iter().map(|image| image.file_size).sum::<u64>()
+        + DATA_OVERHEAD
+}
+
+/// Run a command and fail if it doesn't succeed, optionally passing it some
+/// input.
+fn run(command: &mut Command, input: Option<&str>) -> Result<()> {
+    debug!(command = ?command, "Running command");
+    let mut child = command.stdin(Stdio::piped()).spawn()?;
+
+    if let Some(input) = input {
+        child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("Failed to open stdin"))?
+            .write_all(input.as_bytes())?;
+    }
+    drop(child.stdin.take());
+
+    if !child.wait()?.success() {
+        bail!("Command failed: {:?}", command);
+    }
+    Ok(())
+}
+
+/// Write GBL media to the given device or file. The base image must already be
+/// loaded.
+pub fn write(base: &ImageHandle, images: &[ImageHandle], dest: impl AsRef<Path>) -> Result<()> {
+    let dest = dest.as_ref();
+    let required = required_size(base, images);
+
+    // Regular files are simply made large enough
+    if !dest.exists() || dest.is_file() {
+        File::create(dest)?.set_len(required)?;
+    } else if BlockDevice::open(dest)?.size()? < required {
+        bail!(
+            "The device is too small (at least {} bytes are required)",
+            required
+        );
+    }
+
+    base.write(dest, ProgressBar::Write.new_empty())?;
+
+    // The backup GPT header is at the end of the GBL image rather than the
+    // end of the device, so it has to be moved before the partition table can
+    // grow
+    let mut sectors = [0u8; 1024];
+    File::open(dest)?.read_exact(&mut sectors)?;
+    if PartitionTable::detect(&sectors) == PartitionTable::Gpt {
+        run(
+            Command::new("sfdisk")
+                .args(["--relocate", "gpt-bak-std"])
+                .arg(dest),
+            None,
+        )?;
+    }
+
+    // Append a Linux partition that fills the remaining space
+    run(
+        Command::new("sfdisk").arg("--append").arg(dest),
+        Some(",,L\n"),
+    )?;
+
+    let loop_device = if dest.is_file() {
+        Some(LoopDevice::attach(dest)?)
+    } else {
+        reread_partitions(dest);
+        None
+    };
+
+    let device = match &loop_device {
+        Some(loop_device) => BlockDevice::open(&loop_device.path)?,
+        None => BlockDevice::open(dest)?,
+    };
+
+    let partition = device
+        .partitions()?
+        .pop()
+        .ok_or_else(|| anyhow!("Failed to find the data partition"))?;
+
+    info!(partition = ?partition.path, "Formatting data partition");
+    run(
+        Command::new("mkfs.ext4")
+            .args(["-q", "-F", "-L", DATA_LABEL])
+            .arg(&partition.path),
+        None,
+    )?;
+
+    let mount = Mount::new(&partition.path)?;
+    for image in images {
+        info!(image = image.primary_header.name(), "Copying image");
+
+        // Use the same naming scheme as the image library
+        let mut file = File::create(mount.path().join(format!("{}.gb", image.id)))?;
+        ProgressBar::Write.copy(&mut File::open(&image.path)?, &mut file, image.file_size)?;
+        file.sync_all()?;
+    }
+
+    Ok(())
+}
@@ -3,10 +3,17 @@ use rand::Rng;
 use std::net::TcpListener;
 
 pub mod cli;
+pub mod deploy;
+pub mod device;
 pub mod foundry;
+pub mod gbl;
 #[cfg(feature = "gui")]
 pub mod gui;
 pub mod library;
+#[cfg(unix)]
+pub mod personalize;
+#[cfg(target_os = "linux")]
+pub mod pivot_root;
 pub mod registry;
 
 /// Build info
@@ -36,8 +36,13 @@ pub fn main() -> ExitCode {
             Some(Commands::Cast {
                 record: _,
                 debug,
+                dashboard: _,
                 read_password: _,
                 no_accel: _,
+                no_cache: _,
+                resume: _,
+                keep_failed: _,
+                on_failure: _,
                 output: _,
                 path: _,
             }) => {
@@ -68,6 +73,12 @@ pub fn main() -> ExitCode {
         Some(Commands::Write { .. }) => {
             goldboot::cli::cmd::write::run(command_line.command.unwrap())
         }
+        Some(Commands::Run { .. }) => goldboot::cli::cmd::run::run(command_line.command.unwrap()),
+        Some(Commands::ServeDeploy { .. })
+        | Some(Commands::DeployClient { .. })
+        | Some(Commands::Multicast { .. }) => {
+            goldboot::cli::cmd::deploy::run(command_line.command.unwrap())
+        }
         #[cfg(feature = "gui")]
         None => goldboot::gui::load_gui(command_line.fullscreen),
         #[cfg(not(feature = "gui"))]
@@ -0,0 +1,447 @@
+//! Personalization gives each deployed disk a unique identity after the image
+//! has been written. Since every disk written from the same image is identical,
+//! things like the hostname, machine ID, and SSH host keys need to be changed
+//! before the machine boots for the first time.
+
+use crate::device::{reread_partitions, BlockDevice, LoopDevice, Mount};
+use anyhow::Result;
+use anyhow::{anyhow, bail};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+use tracing::{debug, info, warn};
+
+/// A per-device manifest describing how to personalize a written disk.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Personalization {
+    /// The hostname template. The following variables are substituted:
+    ///     - {serial}: the target device's serial number
+    ///     - {random}: six random alphanumeric characters
+    pub hostname: Option<String>,
+
+    /// Files to inject into the root filesystem
+    #[serde(default)]
+    pub files: Vec<InjectedFile>,
+
+    /// Identifiers and keys that should be regenerated
+    #[serde(default)]
+    pub regenerate: Vec<Regenerate>,
+
+    /// The partition number of the root filesystem. If not given, the first
+    /// partition containing "/etc/os-release" is used.
+    pub root_partition: Option<u32>,
+}
+
+/// A file that will be written into the root filesystem.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct InjectedFile {
+    /// The absolute destination path in the root filesystem
+    pub path: String,
+
+    /// Inline file content
+    pub content: Option<String>,
+
+    /// A local file to copy instead of inline content
+    pub source: Option<String>,
+
+    /// The file permissions
+    pub mode: Option<u32>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum Regenerate {
+    /// Write a new random /etc/machine-id
+    MachineId,
+
+    /// Replace the SSH host keys in /etc/ssh
+    SshHostKeys,
+}
+
+impl Personalization {
+    /// Load a manifest from a JSON, RON, TOML, or YAML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)?;
+
+        let personalization: Self = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_slice(&data)?,
+            Some("ron") => ron::de::from_bytes(&data)?,
+            Some("toml") => toml::from_str(&String::from_utf8(data)?)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_slice(&data)?,
+            _ => bail!("Unknown manifest format: {}", path.display()),
+        };
+        personalization.validate()?;
+        Ok(personalization)
+    }
+
+    /// Check the manifest for values that can't be applied safely.
+    pub fn validate(&self) -> Result<()> {
+        if self.root_partition == Some(0) {
+            bail!("Partition numbers start at 1");
+        }
+
+        for file in &self.files {
+            // Files must stay inside the root filesystem
+            let path = Path::new(file.path.trim_start_matches('/'));
+            if path.as_os_str().is_empty()
+                || !path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            {
+                bail!("Invalid file path: {}", file.path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Personalize the root filesystem on the given device or disk image file.
+    pub fn apply(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        self.validate()?;
+        info!(dest = ?dest, "Personalizing written image");
+
+        // Regular files need a loop device before the partitions can be mounted
+        let loop_device = if dest.is_file() {
+            Some(LoopDevice::attach(dest)?)
+        } else {
+            reread_partitions(dest);
+            None
+        };
+
+        let device = match &loop_device {
+            Some(loop_device) => BlockDevice::open(&loop_device.path)?,
+            None => BlockDevice::open(dest)?,
+        };
+
+        let vars = TemplateVars::new(device.serial());
+
+        let partitions = match self.root_partition {
+            Some(number) => match device
+                .partitions()?
+                .into_iter()
+                .find(|partition| partition.partition_number() == Some(number))
+            {
+                Some(partition) => vec![partition],
+                None => bail!("Partition {} not found", number),
+            },
+            None => device.partitions()?,
+        };
+
+        for partition in partitions {
+            let mount = match Mount::new(&partition.path) {
+                Ok(mount) => mount,
+                Err(error) => {
+                    debug!(partition = ?partition.path, error = %error, "Skipping partition");
+                    continue;
+                }
+            };
+
+            if self.root_partition.is_none() && !mount.path().join("etc/os-release").exists() {
+                continue;
+            }
+
+            info!(partition = ?partition.path, "Found root filesystem");
+            return self.apply_root(mount.path(), &vars);
+        }
+
+        bail!("Failed to find the root filesystem");
+    }
+
+    /// Personalize an already mounted (or extracted) root filesystem.
+    pub fn apply_root(&self, root: &Path, vars: &TemplateVars) -> Result<()> {
+        self.validate()?;
+
+        if let Some(template) = &self.hostname {
+            let hostname = vars.render(template);
+            info!(hostname, "Setting hostname");
+
+            // Also replace the old hostname in the hosts file
+            if let Ok(old_hostname) = std::fs::read_to_string(root_path(root, "etc/hostname")?) {
+                if let Ok(hosts) = std::fs::read_to_string(root_path(root, "etc/hosts")?) {
+                    write_file(
+                        root,
+                        "etc/hosts",
+                        replace_hostname(&hosts, old_hostname.trim(), &hostname),
+                    )?;
+                }
+            }
+
+            write_file(root, "etc/hostname", format!("{hostname}\n"))?;
+        }
+
+        for regenerate in &self.regenerate {
+            match regenerate {
+                Regenerate::MachineId => {
+                    info!("Generating new machine ID");
+                    let machine_id = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+                    write_file(root, "etc/machine-id", format!("{machine_id}\n"))?;
+                }
+                Regenerate::SshHostKeys => {
+                    let ssh_dir = root_path(root, "etc/ssh")?;
+                    if !ssh_dir.is_dir() {
+                        warn!("No SSH configuration found; skipping host keys");
+                        continue;
+                    }
+                    info!("Generating new SSH host keys");
+
+                    for entry in ssh_dir.read_dir()? {
+                        let path = entry?.path();
+                        if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                            if name.starts_with("ssh_host_") {
+                                std::fs::remove_file(&path)?;
+                            }
+                        }
+                    }
+
+                    // Generate every default key type under the root
+                    let output = Command::new("ssh-keygen")
+                        .arg("-A")
+                        .arg("-f")
+                        .arg(root)
+                        .output()
+                        .map_err(|err| anyhow!("Failed to run ssh-keygen: {}", err))?;
+                    if !output.status.success() {
+                        bail!(
+                            "Failed to generate SSH host keys: {}",
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        );
+                    }
+                }
+            }
+        }
+
+        for file in &self.files {
+            debug!(path = file.path, "Injecting file");
+
+            let content = match (&file.content, &file.source) {
+                (Some(content), None) => vars.render(content).into_bytes(),
+                (None, Some(source)) => std::fs::read(source)?,
+                _ => bail!(
+                    "Exactly one of 'content' or 'source' is required: {}",
+                    file.path
+                ),
+            };
+
+            let path = write_file(root, &file.path, content)?;
+            if let Some(mode) = file.mode {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the path of a file in the root filesystem. Absolute symlinks in the image
+/// would be followed on the host, so symlinked components are refused.
+fn root_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let mut resolved = root.to_path_buf();
+
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        let Component::Normal(name) = component else {
+            bail!("Invalid file path: {}", path);
+        };
+        resolved.push(name);
+
+        if resolved
+            .symlink_metadata()
+            .is_ok_and(|metadata| metadata.file_type().is_symlink())
+        {
+            bail!("Refusing to follow symlink in the image: {}", path);
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Write a file into the root filesystem without following symlinks and return
+/// its full path.
+fn write_file(root: &Path, path: &str, content: impl AsRef<[u8]>) -> Result<PathBuf> {
+    let path = root_path(root, path)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)?
+        .write_all(content.as_ref())?;
+    Ok(path)
+}
+
+/// Values that can be substituted into manifest templates.
+#[derive(Debug, Default)]
+pub struct TemplateVars {
+    pub serial: Option<String>,
+
+    /// Chosen once so every template gets the same value
+    pub random: String,
+}
+
+impl TemplateVars {
+    pub fn new(serial: Option<String>) -> Self {
+        let random: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(6)
+            .map(char::from)
+            .collect();
+
+        Self {
+            serial,
+            random: random.to_lowercase(),
+        }
+    }
+
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{serial}", self.serial.as_deref().unwrap_or("unknown"))
+            .replace("{random}", &self.random)
+    }
+}
+
+/// Replace a hostname in the given hosts file content, leaving unrelated lines
+/// untouched.
+fn replace_hostname(hosts: &str, old_hostname: &str, hostname: &str) -> String {
+    hosts
+        .lines()
+        .map(|line| {
+            if !old_hostname.is_empty()
+                && !line.trim_start().starts_with('#')
+                && line.split_whitespace().any(|word| word == old_hostname)
+            {
+                line.split_whitespace()
+                    .map(|word| if word == old_hostname { hostname } else { word })
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            } else {
+                line.to_string()
+            }
+        })
+        .map(|line| line + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_root() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("etc/ssh"))?;
+        std::fs::write(root.path().join("etc/hostname"), "goldboot\n")?;
+        std::fs::write(root.path().join("etc/hosts"), "127.0.1.1 goldboot\n")?;
+        std::fs::write(root.path().join("etc/ssh/ssh_host_rsa_key"), "old")?;
+
+        let mut personalization = Personalization {
+            hostname: Some(String::from("lab-{serial}-{random}")),
+            files: vec![InjectedFile {
+                path: String::from("/etc/motd"),
+                content: Some(String::from("Welcome to {serial}-{random}")),
+                source: None,
+                mode: Some(0o644),
+            }],
+            regenerate: vec![Regenerate::MachineId],
+            root_partition: None,
+        };
+
+        // Skip host keys if ssh-keygen isn't installed
+        let ssh_keygen = Command::new("ssh-keygen").arg("-?").output().is_ok();
+        if ssh_keygen {
+            personalization.regenerate.push(Regenerate::SshHostKeys);
+        }
+        personalization.apply_root(
+            root.path(),
+            &TemplateVars {
+                serial: Some(String::from("S123")),
+                random: String::from("abc123"),
+            },
+        )?;
+
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("etc/hostname"))?,
+            "lab-S123-abc123\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("etc/hosts"))?,
+            "127.0.1.1 lab-S123-abc123\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("etc/motd"))?,
+            "Welcome to S123-abc123"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("etc/machine-id"))?.len(),
+            33
+        );
+        if ssh_keygen {
+            assert_ne!(
+                std::fs::read_to_string(root.path().join("etc/ssh/ssh_host_rsa_key"))?,
+                "old"
+            );
+            assert!(root
+                .path()
+                .join("etc/ssh/ssh_host_ed25519_key.pub")
+                .exists());
+        }
+
+        // Files can't be written outside the root
+        personalization.files[0].path = String::from("/etc/../../escape");
+        assert!(personalization.validate().is_err());
+
+        personalization.files.clear();
+        personalization.root_partition = Some(0);
+        assert!(personalization.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_root_symlink() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let host = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("etc"))?;
+        std::fs::write(host.path().join("hostname"), "host\n")?;
+        std::os::unix::fs::symlink(
+            host.path().join("hostname"),
+            root.path().join("etc/hostname"),
+        )?;
+        std::os::unix::fs::symlink(host.path(), root.path().join("opt"))?;
+
+        let personalization = Personalization {
+            hostname: Some(String::from("lab")),
+            ..Default::default()
+        };
+        assert!(personalization
+            .apply_root(root.path(), &TemplateVars::default())
+            .is_err());
+
+        let personalization = Personalization {
+            files: vec![InjectedFile {
+                path: String::from("/opt/hostname"),
+                content: Some(String::from("lab")),
+                source: None,
+                mode: None,
+            }],
+            ..Default::default()
+        };
+        assert!(personalization
+            .apply_root(root.path(), &TemplateVars::default())
+            .is_err());
+
+        // Nothing on the host was touched
+        assert_eq!(
+            std::fs::read_to_string(host.path().join("hostname"))?,
+            "host\n"
+        );
+        Ok(())
+    }
+}
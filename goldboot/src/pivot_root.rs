@@ -1,56 +1,487 @@
-use std::process::Command;
+//! Live reimaging replaces the disk that the current system is running from.
+//! Since the root filesystem can't be overwritten while it's in use, everything
+//! needed to finish the write (the goldboot binary, its shared libraries, and
+//! the image itself) is staged into a tmpfs which then becomes the new root.
+//!
+//! The process happens in two stages. The first stage checks prerequisites,
+//! stages the tmpfs, stops services, and pivots. It then re-executes the staged
+//! binary which releases the old root filesystem, writes the image, and reboots.
+//!
+//! The init process isn't staged, so it keeps the old root busy until the
+//! reboot. The old root is made read-only before anything is written so that
+//! nothing from it can be flushed onto the new image.
+//!
+//! The whole process is tested in a VM by `tests/live_write.rs`, which is
+//! ignored by default since it needs a guest disk.
 
-/// Check that the current system is a candidate for pivoting.
-#[cfg(target_os = "linux")]
-pub fn check_pivot_root() -> bool {
-	false
+use anyhow::bail;
+use anyhow::Result;
+use std::{
+    ffi::CString,
+    io::Read,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
+
+/// Where the temporary root is staged before the pivot.
+pub const STAGING_ROOT: &str = "/tmp/tmproot";
+
+/// Where the original root filesystem is moved to after the pivot.
+pub const OLD_ROOT: &str = "/oldroot";
+
+/// The path of the staged goldboot binary in the new root.
+pub const STAGED_BINARY: &str = "/goldboot";
+
+/// The path of the staged image in the new root.
+pub const STAGED_IMAGE: &str = "/image.gb";
+
+/// Extra memory required beyond the image and binary for the tmpfs and the
+/// write itself.
+const MEMORY_MARGIN: u64 = 256 * 1024 * 1024;
+
+/// How long an error stays on the console before the machine is rebooted.
+const EMERGENCY_DELAY: Duration = Duration::from_secs(60);
+
+/// Services that are left running until the old root is released.
+const KEEP_SERVICES: [&str; 6] = [
+    "dbus.service",
+    "dbus-broker.service",
+    "sshd.service",
+    "ssh.service",
+    "systemd-journald.service",
+    "systemd-udevd.service",
+];
+
+/// Convert the return value of a libc call into a [`Result`].
+fn check(ret: libc::c_int, operation: &str) -> Result<()> {
+    if ret < 0 {
+        bail!(
+            "Failed to {}: {}",
+            operation,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+fn c_path(path: impl AsRef<Path>) -> Result<CString> {
+    Ok(CString::new(path.as_ref().as_os_str().as_bytes())?)
+}
+
+fn mount(
+    source: Option<&str>,
+    target: impl AsRef<Path>,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> Result<()> {
+    let source = source.map(CString::new).transpose()?;
+    let target = c_path(target)?;
+    let fstype = fstype.map(CString::new).transpose()?;
+    let data = data.map(CString::new).transpose()?;
+
+    check(
+        unsafe {
+            libc::mount(
+                source.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                target.as_ptr(),
+                fstype.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+                flags,
+                data.as_ref()
+                    .map_or(std::ptr::null(), |s| s.as_ptr() as *const libc::c_void),
+            )
+        },
+        &format!("mount {}", target.to_string_lossy()),
+    )
+}
+
+/// Check that the current system is a candidate for pivoting and has enough
+/// memory to hold an image of the given size.
+pub fn check_prerequisites(image_size: u64) -> Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        bail!("Live reimaging must be run as root");
+    }
+
+    if !Path::new("/run/systemd/system").exists() {
+        bail!("Live reimaging is only supported on systems running systemd");
+    }
+
+    if Path::new(STAGING_ROOT).exists() {
+        bail!("Staging directory already exists: {}", STAGING_ROOT);
+    }
+
+    let binary_size = std::fs::metadata(std::env::current_exe()?)?.len();
+    let required = image_size + binary_size + MEMORY_MARGIN;
+
+    match parse_mem_available(&std::fs::read_to_string("/proc/meminfo")?) {
+        Some(available) if available >= required => {
+            debug!(available, required, "Sufficient memory available");
+        }
+        Some(available) => bail!(
+            "Not enough memory to stage the image (available: {}, required: {})",
+            available,
+            required
+        ),
+        None => bail!("Failed to determine available memory"),
+    }
+
+    for tool in ["ldd", "systemctl"] {
+        if Command::new("which").arg(tool).output()?.status.success() {
+            continue;
+        }
+        bail!("Required tool not found: {}", tool);
+    }
+
+    Ok(())
 }
 
-fn exec_silent(exe: &str, args: &[&str]) {
-	Command::new(exe)
-        .args(args)
-        .status()
-        .expect("Failed to exec");
+/// Mount a tmpfs at [`STAGING_ROOT`] and copy the goldboot binary, its shared
+/// libraries, and the image into it.
+pub fn stage(image: &mut impl Read, image_size: u64) -> Result<PathBuf> {
+    let root = PathBuf::from(STAGING_ROOT);
+    let binary = std::env::current_exe()?;
+    info!(root = ?root, "Staging temporary root");
+
+    std::fs::create_dir_all(&root)?;
+    let size = image_size + std::fs::metadata(&binary)?.len() + MEMORY_MARGIN;
+    let staged = mount(
+        Some("goldboot"),
+        &root,
+        Some("tmpfs"),
+        0,
+        Some(&format!("size={size},mode=0755")),
+    )
+    .and_then(|_| populate(&root, &binary, image, image_size));
+
+    // Clean up so the staging can be retried
+    if let Err(err) = staged {
+        let target = c_path(&root)?;
+        unsafe {
+            libc::umount2(target.as_ptr(), libc::MNT_DETACH);
+        }
+        if let Err(error) = std::fs::remove_dir(&root) {
+            warn!(error = %error, "Failed to remove staging directory");
+        }
+        return Err(err);
+    }
+
+    Ok(root)
 }
 
-/// Pivot the root mountpoint to a temporary directory so we can reimage the root
+/// Copy everything needed to finish the write into the mounted tmpfs.
+fn populate(root: &Path, binary: &Path, image: &mut impl Read, image_size: u64) -> Result<()> {
+    for dir in ["dev", "proc", "run", "sys", "tmp", "oldroot"] {
+        std::fs::create_dir(root.join(dir))?;
+    }
+
+    // The staged binary still needs the dynamic loader and libraries
+    let ldd = Command::new("ldd").arg(binary).output()?;
+    for library in parse_ldd(&String::from_utf8_lossy(&ldd.stdout)) {
+        let dest = root.join(library.strip_prefix("/")?);
+        debug!(library = ?library, "Staging library");
+
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&library, &dest)?;
+    }
+
+    // The loader falls back to its default search path without the cache
+    if Path::new("/etc/ld.so.cache").exists() {
+        std::fs::create_dir(root.join("etc"))?;
+        std::fs::copy("/etc/ld.so.cache", root.join("etc/ld.so.cache"))?;
+    }
+
+    std::fs::copy(binary, root.join(STAGED_BINARY.trim_start_matches('/')))?;
+
+    let mut staged_image = std::fs::File::create(root.join(STAGED_IMAGE.trim_start_matches('/')))?;
+    if std::io::copy(image, &mut staged_image)? != image_size {
+        bail!("Failed to stage the complete image");
+    }
+
+    Ok(())
+}
+
+/// Stop all running services except the few that are needed until the very
+/// end. Failures are not fatal since anything left will be killed later.
+pub fn stop_services() -> Result<()> {
+    let output = Command::new("systemctl")
+        .args([
+            "list-units",
+            "--type=service",
+            "--state=running",
+            "--no-legend",
+            "--plain",
+        ])
+        .output()?;
+
+    if !output.status.success() {
+        bail!("Failed to list running services");
+    }
+
+    for service in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|service| !KEEP_SERVICES.contains(service))
+    {
+        debug!(service, "Stopping service");
+        match Command::new("systemctl")
+            .args(["stop", "--no-block", service])
+            .status()
+        {
+            Ok(status) if status.success() => {}
+            _ => warn!(service, "Failed to stop service"),
+        }
+    }
+
+    // Give the services a chance to exit cleanly
+    std::thread::sleep(Duration::from_secs(5));
+    Ok(())
+}
+
+/// Pivot the root mountpoint to the staged root so we can reimage the root
 /// partition on-the-fly. We have no intention of ever pivoting back, so it's OK
-/// to break stuff. Taken from: https://unix.stackexchange.com/a/227318.
-#[cfg(target_os = "linux")]
-pub fn pivot_root() {
-
-	// Unmount everything we can
-	exec_silent("umount", &["-a"]);
-
-    // Create a temporary root
-    exec_silent("mkdir", &["/tmp/tmproot"]);
-    exec_silent("mount", &["-t", "tmpfs", "none", "/tmp/tmproot"]);
-    exec_silent("mkdir", &["/tmp/tmproot/proc"]);
-    exec_silent("mkdir", &["/tmp/tmproot/sys"]);
-    exec_silent("mkdir", &["/tmp/tmproot/dev"]);
-    exec_silent("mkdir", &["/tmp/tmproot/run"]);
-    exec_silent("mkdir", &["/tmp/tmproot/usr"]);
-    exec_silent("mkdir", &["/tmp/tmproot/var"]);
-    exec_silent("mkdir", &["/tmp/tmproot/tmp"]);
-    exec_silent("mkdir", &["/tmp/tmproot/oldroot"]);
-    exec_silent("cp", &["-ax", "/bin", "/tmp/tmproot/"]);
-    exec_silent("cp", &["-ax", "/etc", "/tmp/tmproot/"]);
-    exec_silent("cp", &["-ax", "/sbin", "/tmp/tmproot/"]);
-    exec_silent("cp", &["-ax", "/lib", "/tmp/tmproot/"]);
-    exec_silent("cp", &["-ax", "/lib64", "/tmp/tmproot/"]);
-
-    // Run pivot root
-    exec_silent("mount", &["--make-rprivate", "/"]);
-    exec_silent("pivot_root", &["/tmp/tmproot", "/tmp/tmproot/oldroot"]);
-    exec_silent("mount", &["--move", "/oldroot/dev", "/dev"]);
-    exec_silent("mount", &["--move", "/oldroot/proc", "/proc"]);
-    exec_silent("mount", &["--move", "/oldroot/sys", "/sys"]);
-    exec_silent("mount", &["--move", "/oldroot/run", "/run"]);
-
-    // Clean up processes holding onto files in the old root
-    exec_silent("systemctl", &["daemon-reexec"]);
-    exec_silent("fuser", &["-mk", "/oldroot"]);
-
-    // Lastly unmount the original root filesystem
-    exec_silent("umount", &["/oldroot"]);
-}
\ No newline at end of file
+/// to break stuff.
+pub fn pivot(root: &Path) -> Result<()> {
+    info!(root = ?root, "Pivoting to temporary root");
+
+    // pivot_root doesn't work with shared mounts
+    mount(None, "/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+
+    let new_root = c_path(root)?;
+    let put_old = c_path(root.join(OLD_ROOT.trim_start_matches('/')))?;
+    check(
+        unsafe { libc::syscall(libc::SYS_pivot_root, new_root.as_ptr(), put_old.as_ptr()) as i32 },
+        "pivot root",
+    )?;
+    std::env::set_current_dir("/")?;
+
+    for dir in ["dev", "proc", "sys", "run"] {
+        mount(
+            Some(&format!("{OLD_ROOT}/{dir}")),
+            format!("/{dir}"),
+            None,
+            libc::MS_MOVE,
+            None,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Kill every process that still refers to the old root and unmount it. This
+/// must be run from the staged binary since the current process would
+/// otherwise hold the old root itself. Filesystems that stay busy must at least
+/// become read-only, otherwise the write is aborted.
+pub fn release_old_root() -> Result<()> {
+    let own_pid = std::process::id();
+
+    for entry in Path::new("/proc").read_dir()? {
+        let entry = entry?;
+        let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+
+        // The init process can't be killed and holds onto the old root until
+        // the reboot
+        if pid == 1 || pid == own_pid || !uses_old_root(&entry.path()) {
+            continue;
+        }
+
+        debug!(pid, "Killing process using the old root");
+        unsafe {
+            libc::kill(pid as i32, libc::SIGKILL);
+        }
+    }
+
+    // Wait for the processes to be reaped
+    std::thread::sleep(Duration::from_secs(2));
+
+    // Unmount nested filesystems first
+    let mut mountpoints: Vec<PathBuf> =
+        crate::device::parse_mounts(&std::fs::read_to_string("/proc/mounts")?)
+            .into_iter()
+            .map(|(_, mountpoint)| mountpoint)
+            .chain(std::iter::once(PathBuf::from(OLD_ROOT)))
+            .filter(|mountpoint| mountpoint.starts_with(OLD_ROOT))
+            .collect();
+    mountpoints.sort_by_key(|mountpoint| std::cmp::Reverse(mountpoint.components().count()));
+    mountpoints.dedup();
+
+    for mountpoint in mountpoints {
+        let target = c_path(&mountpoint)?;
+        if unsafe { libc::umount(target.as_ptr()) } == 0 {
+            continue;
+        }
+
+        // The init process still holds the old root, so make sure nothing
+        // else will be written to it before detaching. Remounting also flushes
+        // its dirty pages before the image is written.
+        warn!(mountpoint = ?mountpoint, "Filesystem busy; detaching instead");
+        if let Err(error) = mount(
+            None,
+            &mountpoint,
+            None,
+            libc::MS_REMOUNT | libc::MS_RDONLY,
+            None,
+        ) {
+            bail!(
+                "Failed to release {} (it can't be made read-only): {}",
+                mountpoint.display(),
+                error
+            );
+        }
+        check(
+            unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) },
+            &format!("unmount {}", mountpoint.display()),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Check whether the given process has its root, working directory, binary, or
+/// any open file on the old root.
+fn uses_old_root(process: &Path) -> bool {
+    let on_old_root = |path: &Path| {
+        std::fs::read_link(path)
+            .map(|target| target.starts_with(OLD_ROOT))
+            .unwrap_or(false)
+    };
+
+    if ["root", "cwd", "exe"]
+        .iter()
+        .any(|link| on_old_root(&process.join(link)))
+    {
+        return true;
+    }
+
+    if let Ok(fds) = process.join("fd").read_dir() {
+        if fds.flatten().any(|fd| on_old_root(&fd.path())) {
+            return true;
+        }
+    }
+
+    std::fs::read_to_string(process.join("maps"))
+        .map(|maps| maps.contains(OLD_ROOT))
+        .unwrap_or(false)
+}
+
+/// Send all output to the system console since the terminal that started the
+/// write is about to be killed.
+pub fn redirect_to_console() -> Result<()> {
+    let console = std::fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/console")?;
+
+    use std::os::fd::AsRawFd;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        check(
+            unsafe { libc::dup2(console.as_raw_fd(), fd) },
+            "redirect output",
+        )?;
+    }
+
+    // The controlling terminal is going away
+    unsafe {
+        libc::signal(libc::SIGHUP, libc::SIG_IGN);
+        libc::setsid();
+    }
+    Ok(())
+}
+
+/// Flush everything to disk and restart the machine immediately. By now the
+/// old root is either unmounted or read-only, so only the image is flushed.
+pub fn reboot() -> Result<()> {
+    info!("Rebooting");
+    unsafe {
+        libc::sync();
+    }
+    check(unsafe { libc::reboot(libc::RB_AUTOBOOT) }, "reboot")
+}
+
+/// Handle a failure after the pivot. There's no way back to the old system and
+/// the temporary root has no shell, so the error is left on the console for a
+/// while before rebooting.
+pub fn emergency(err: &anyhow::Error) -> ! {
+    error!(error = %err, "Live write failed; the disk may be incomplete");
+    error!(
+        "Rebooting in {} seconds; the machine may need to be reimaged from other media",
+        EMERGENCY_DELAY.as_secs()
+    );
+    std::thread::sleep(EMERGENCY_DELAY);
+
+    if let Err(err) = reboot() {
+        error!(error = %err, "Failed to reboot; the machine must be reset manually");
+    }
+
+    // Exiting would leave the machine without anything running on the console
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Get the available memory in bytes from the content of /proc/meminfo.
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// Get the paths of the shared libraries (including the dynamic loader) from
+/// the output of ldd.
+fn parse_ldd(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .flat_map(|line| {
+            // The loader appears on the left side when it's a symlink, in which
+            // case both paths are needed
+            let (name, path) = line.split_once("=>").unwrap_or((line, ""));
+            [name, path]
+                .into_iter()
+                .filter_map(|field| field.split_whitespace().next())
+        })
+        .filter(|path| path.starts_with('/'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ldd() {
+        assert_eq!(
+            parse_ldd(
+                "	linux-vdso.so.1 (0x00007ffd5b9f3000)
+	libgcc_s.so.1 => /usr/lib/libgcc_s.so.1 (0x00007f0e0b1b0000)
+	libc.so.6 => /usr/lib/libc.so.6 (0x00007f0e0a800000)
+	/lib64/ld-linux-x86-64.so.2 => /usr/lib64/ld-linux-x86-64.so.2 (0x00007f0e0b200000)
+	/lib/ld-musl-x86_64.so.1 (0x00007f0e0b300000)"
+            ),
+            vec![
+                PathBuf::from("/usr/lib/libgcc_s.so.1"),
+                PathBuf::from("/usr/lib/libc.so.6"),
+                PathBuf::from("/lib64/ld-linux-x86-64.so.2"),
+                PathBuf::from("/usr/lib64/ld-linux-x86-64.so.2"),
+                PathBuf::from("/lib/ld-musl-x86_64.so.1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mem_available() {
+        assert_eq!(
+            parse_mem_available("MemTotal:       16318480 kB\nMemAvailable:    8000000 kB\n"),
+            Some(8000000 * 1024)
+        );
+        assert_eq!(parse_mem_available("MemTotal:       16318480 kB\n"), None);
+    }
+}
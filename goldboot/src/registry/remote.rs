@@ -0,0 +1,231 @@
+//! Access to images that live on a remote server. Rather than downloading the
+//! entire image first, clusters are fetched on demand with HTTP range requests.
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+use goldboot_image::ImageHandle;
+use regex::Regex;
+use reqwest::{header, StatusCode};
+use std::io::{Read, Seek, SeekFrom};
+use tracing::{debug, trace};
+
+/// The amount of data to request at once. Clusters are read sequentially, so
+/// reading ahead saves a lot of round trips.
+const READ_AHEAD: u64 = 4 * 1024 * 1024;
+
+/// A seekable reader over a remote file which fetches data with HTTP range
+/// requests.
+pub struct HttpRangeReader {
+    client: reqwest::blocking::Client,
+
+    /// The remote file URL
+    pub url: String,
+
+    /// The total size of the remote file in bytes
+    pub length: u64,
+
+    /// The entity tag that the server gave for the file
+    pub etag: Option<String>,
+
+    /// The current position in the remote file
+    position: u64,
+
+    /// Data that has already been fetched
+    buffer: Vec<u8>,
+
+    /// The offset of the buffer in the remote file
+    buffer_offset: u64,
+}
+
+impl HttpRangeReader {
+    pub fn new(url: &str) -> Result<Self> {
+        let client = reqwest::blocking::Client::new();
+
+        // Request a single byte to check for range support and get the length
+        let rs = client.get(url).header(header::RANGE, "bytes=0-0").send()?;
+
+        if rs.status() != StatusCode::PARTIAL_CONTENT {
+            bail!("Server does not support range requests: {}", rs.status());
+        }
+
+        let length =
+            Self::total_length(&rs).ok_or_else(|| anyhow!("Failed to get content length"))?;
+        let etag = rs
+            .headers()
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim_start_matches("W/").trim_matches('"').to_string());
+        debug!(url, length, etag, "Opened remote file");
+
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            length,
+            etag,
+            position: 0,
+            buffer: Vec::new(),
+            buffer_offset: 0,
+        })
+    }
+
+    /// Parse the total length out of a "Content-Range: bytes 0-0/1234" header.
+    fn total_length(rs: &reqwest::blocking::Response) -> Option<u64> {
+        rs.headers()
+            .get(header::CONTENT_RANGE)?
+            .to_str()
+            .ok()?
+            .rsplit_once('/')?
+            .1
+            .parse()
+            .ok()
+    }
+
+    /// Fetch the range starting at the current position into the buffer.
+    fn fill(&mut self) -> std::io::Result<()> {
+        let end = std::cmp::min(self.position + READ_AHEAD, self.length) - 1;
+        trace!(start = self.position, end, "Requesting range");
+
+        let mut rs = self
+            .client
+            .get(&self.url)
+            .header(header::RANGE, format!("bytes={}-{}", self.position, end))
+            .send()
+            .map_err(std::io::Error::other)?;
+
+        if rs.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(std::io::Error::other(format!(
+                "Range request failed: {}",
+                rs.status()
+            )));
+        }
+
+        self.buffer.clear();
+        rs.read_to_end(&mut self.buffer)?;
+        self.buffer_offset = self.position;
+        Ok(())
+    }
+}
+
+impl Read for HttpRangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let buffer_end = self.buffer_offset + self.buffer.len() as u64;
+        if self.position < self.buffer_offset || self.position >= buffer_end {
+            self.fill()?;
+        }
+
+        let start = (self.position - self.buffer_offset) as usize;
+        let size = std::cmp::min(buf.len(), self.buffer.len() - start);
+        buf[..size].copy_from_slice(&self.buffer[start..start + size]);
+
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for HttpRangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+/// Resolve an image reference into a URL if it refers to a remote image.
+///
+/// References can either be a plain HTTP URL, which is used as-is, or a
+/// registry reference of the form `<registry host>/<image id>`, which is
+/// reached over HTTPS.
+pub fn resolve_url(reference: &str) -> Option<String> {
+    match reference.split_once("://") {
+        Some(("http" | "https", _)) => return Some(reference.to_string()),
+        Some(_) => return None,
+        None => {}
+    }
+
+    match reference.split_once('/') {
+        Some((registry, image_id))
+            if Regex::new("^[A-Fa-f0-9]{12,64}$")
+                .unwrap()
+                .is_match(image_id) =>
+        {
+            Some(format!("https://{registry}/image/download/{image_id}"))
+        }
+        _ => None,
+    }
+}
+
+/// Open a remote image. Only the headers are downloaded; the returned reader
+/// can be used to stream the clusters later. The image ID is empty if the
+/// server doesn't provide it.
+pub fn open(url: &str) -> Result<(ImageHandle, HttpRangeReader)> {
+    let mut reader = HttpRangeReader::new(url)?;
+    let id = image_id(&reader).unwrap_or_default();
+
+    let length = reader.length;
+    let image_handle = ImageHandle::open_from(&mut reader, url, id, length)?;
+    Ok((image_handle, reader))
+}
+
+/// Get the ID of a remote image. Registries give it as the entity tag, and
+/// image files are often named after it like in the local library. Hashing the
+/// image would mean downloading all of it, so otherwise the ID is unknown.
+pub fn image_id(reader: &HttpRangeReader) -> Option<String> {
+    let is_id = |value: &str| Regex::new("^[A-Fa-f0-9]{64}$").unwrap().is_match(value);
+
+    if let Some(etag) = reader.etag.as_deref().filter(|etag| is_id(etag)) {
+        return Some(etag.to_lowercase());
+    }
+
+    let name = reader.url.rsplit('/').next().unwrap_or_default();
+    let stem = name.strip_suffix(".gb").unwrap_or(name);
+    if is_id(stem) {
+        return Some(stem.to_lowercase());
+    }
+
+    debug!(url = reader.url, "Remote image ID is unknown");
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_url() {
+        assert_eq!(
+            resolve_url("https://example.com/test.gb"),
+            Some(String::from("https://example.com/test.gb"))
+        );
+        assert_eq!(
+            resolve_url("registry.example.com/8d9ff3a1b0c2"),
+            Some(String::from(
+                "https://registry.example.com/image/download/8d9ff3a1b0c2"
+            ))
+        );
+        assert_eq!(
+            resolve_url("http://localhost:3000/8d9ff3a1b0c2"),
+            Some(String::from("http://localhost:3000/8d9ff3a1b0c2"))
+        );
+        assert_eq!(resolve_url("ftp://example.com/8d9ff3a1b0c2"), None);
+        assert_eq!(resolve_url("./images/test.gb"), None);
+        assert_eq!(resolve_url("8d9ff3a1b0c2"), None);
+    }
+}
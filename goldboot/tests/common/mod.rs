@@ -0,0 +1,28 @@
+use anyhow::Result;
+use goldboot_image::ImageHandle;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Check that every block of a loaded image was written to the given raw disk.
+pub fn verify_written(image_handle: &ImageHandle, disk: impl AsRef<Path>) -> Result<()> {
+    let mut written = File::open(disk)?;
+
+    let block_size = image_handle.protected_header.as_ref().unwrap().block_size as usize;
+    let mut block = vec![0u8; block_size];
+    for entry in &image_handle.digest_table.as_ref().unwrap().digest_table {
+        written.seek(SeekFrom::Start(entry.block_offset))?;
+        written.read_exact(&mut block)?;
+
+        let digest: [u8; 32] = Sha256::new().chain_update(&block).finalize().into();
+        assert_eq!(
+            digest, entry.digest,
+            "block at {} wasn't written",
+            entry.block_offset
+        );
+    }
+    Ok(())
+}
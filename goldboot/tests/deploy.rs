@@ -0,0 +1,94 @@
+//! Deploys an image to a VM that boots from the network. This needs QEMU and
+//! three inputs:
+//!
+//! - `GOLDBOOT_DEPLOY_KERNEL`: the kernel of the network boot client
+//! - `GOLDBOOT_DEPLOY_INITRD`: an initramfs that runs `goldboot deploy-client`
+//! - `GOLDBOOT_DEPLOY_IMAGE`: an unencrypted image to deploy
+//!
+//! Run it with `cargo test --test deploy -- --ignored`.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use anyhow::{bail, Result};
+use goldboot::{
+    deploy::{server, Assignment, DeployConfig, DeployState, MachineStatus},
+    foundry::{ovmf, qemu::QemuBuilder},
+};
+use goldboot_image::ImageHandle;
+use std::{
+    fs::File,
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+
+/// The MAC address QEMU gives the first network interface.
+const MAC: &str = "52:54:00:12:34:56";
+
+/// The address of the host on QEMU's user network.
+const HOST: &str = "10.0.2.2";
+
+#[test]
+#[ignore = "needs a network boot client and QEMU"]
+fn test_deploy() -> Result<()> {
+    let image = std::env::var("GOLDBOOT_DEPLOY_IMAGE")?;
+    let mut image_handle = ImageHandle::open(&image)?;
+    image_handle.load(None)?;
+
+    let config = DeployConfig {
+        address: String::from(HOST),
+        port: TcpListener::bind("127.0.0.1:0")?.local_addr()?.port(),
+        machines: vec![Assignment {
+            mac: String::from(MAC),
+            image,
+            device: String::from("/dev/vda"),
+        }],
+        client_kernel: Some(std::env::var("GOLDBOOT_DEPLOY_KERNEL")?),
+        client_initrd: Some(std::env::var("GOLDBOOT_DEPLOY_INITRD")?),
+        ..Default::default()
+    };
+    let url = config.url().replace(HOST, "127.0.0.1");
+    let script = format!("{}/boot.ipxe", config.url());
+
+    // The server runs until the test exits
+    std::thread::spawn(move || server::run(config));
+
+    // A blank disk for the client to write
+    let tmp = tempfile::tempdir()?;
+    let disk = tmp.path().join("disk.raw");
+    File::create(&disk)?.set_len(image_handle.primary_header.size)?;
+
+    let arch = image_handle.primary_header.arch;
+    let mut qemu =
+        QemuBuilder::standalone(arch, &ovmf::prepare(arch, tmp.path())?, tmp.path(), "4G")?
+            .disk(&disk, "raw")
+            .netboot(&script)
+            .no_reboot()
+            .start()?;
+
+    // Follow the machine through the server like an operator would
+    let start = Instant::now();
+    let state = loop {
+        if start.elapsed() > Duration::from_secs(3600) {
+            bail!("Timed out waiting for the deployment");
+        }
+        std::thread::sleep(Duration::from_secs(5));
+
+        let Ok(response) = reqwest::blocking::get(format!("{url}/status")) else {
+            continue;
+        };
+        let machines: Vec<MachineStatus> = response.json()?;
+        match machines.into_iter().find(|machine| machine.mac == MAC) {
+            Some(machine) if machine.state == DeployState::Failed => {
+                bail!("Deployment failed: {:?}", machine.message)
+            }
+            Some(machine) if machine.state == DeployState::Done => break machine,
+            _ => {}
+        }
+    };
+    assert_eq!(state.written, state.total);
+
+    qemu.kill()?;
+    common::verify_written(&image_handle, disk)
+}
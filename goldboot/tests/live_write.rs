@@ -0,0 +1,76 @@
+//! Runs a live write inside a VM. This needs QEMU and two inputs:
+//!
+//! - `GOLDBOOT_LIVE_GUEST`: a qcow2 disk of a systemd Linux guest that can run
+//!   the goldboot binary and whose root account can log in on the console with
+//!   the password in `GOLDBOOT_LIVE_PASSWORD`
+//! - `GOLDBOOT_LIVE_IMAGE`: an unencrypted image to write over the guest's disk
+//!
+//! Run it with `cargo test --test live_write -- --ignored`.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use anyhow::{bail, Result};
+use goldboot::foundry::{
+    ovmf,
+    qemu::{self, QemuBuilder},
+    vnc::VncCmd,
+};
+use goldboot_image::ImageHandle;
+
+#[test]
+#[ignore = "needs a guest disk and QEMU"]
+fn test_live_write() -> Result<()> {
+    let guest = std::env::var("GOLDBOOT_LIVE_GUEST")?;
+    let password = std::env::var("GOLDBOOT_LIVE_PASSWORD")?;
+    let mut image_handle = ImageHandle::open(std::env::var("GOLDBOOT_LIVE_IMAGE")?)?;
+    image_handle.load(None)?;
+
+    // Keep the guest disk untouched
+    let tmp = tempfile::tempdir()?;
+    let disk = tmp.path().join("disk.qcow2");
+    qemu::overlay(&guest, &disk)?;
+
+    let arch = image_handle.primary_header.arch;
+    let mut qemu =
+        QemuBuilder::standalone(arch, &ovmf::prepare(arch, tmp.path())?, tmp.path(), "4G")?
+            .disk(&disk, "qcow2")
+            .no_reboot()
+            .prepare_ssh()?
+            .start()?;
+
+    // Log in on the console so the SSH server can be started
+    qemu.vnc.run(vec![
+        vec![VncCmd::Wait(60)],
+        vec![
+            VncCmd::Type(String::from("root")),
+            VncCmd::Enter,
+            VncCmd::Wait(2),
+        ],
+        vec![VncCmd::Type(password), VncCmd::Enter, VncCmd::Wait(2)],
+    ])?;
+
+    let mut ssh = qemu.ssh("root")?;
+    ssh.upload(
+        &std::fs::read(env!("CARGO_BIN_EXE_goldboot"))?,
+        "/root/goldboot",
+    )?;
+    ssh.upload(&std::fs::read(&image_handle.path)?, "/root/image.gb")?;
+    ssh.exec("chmod +x /root/goldboot")?;
+
+    // The connection is dropped when the old root is released, so the command
+    // only returns by itself if the write didn't start
+    if let Ok(code) =
+        ssh.exec("/root/goldboot write /root/image.gb --output /dev/vda --live --confirm")
+    {
+        bail!("Live write exited early with code {}", code);
+    }
+
+    // The VM exits when the guest reboots after the write
+    qemu.wait()?;
+
+    let written = tmp.path().join("disk.raw");
+    qemu::convert(&disk, &written, "raw")?;
+    common::verify_written(&image_handle, written)
+}